@@ -0,0 +1,133 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Where the engine reads the time from. Readings are microseconds since some fixed point
+/// and never go backwards.
+pub trait Clock: Send + Sync {
+    fn now_micros(&self) -> u64;
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+/// The monotonic clock of the OS.
+pub struct SystemClock {
+    origin: Instant
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self { origin: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_micros(&self) -> u64 {
+        self.origin.elapsed().as_micros() as u64
+    }
+}
+
+/// A monotonic clock that is cheaper to read but only advances every few milliseconds; the
+/// same as `SystemClock` outside linux.
+pub struct CoarseClock {
+    #[cfg(not(target_os = "linux"))]
+    inner: SystemClock
+}
+
+impl Default for CoarseClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CoarseClock {
+    #[cfg(target_os = "linux")]
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new() -> Self {
+        Self { inner: SystemClock::new() }
+    }
+}
+
+impl Clock for CoarseClock {
+    #[cfg(target_os = "linux")]
+    fn now_micros(&self) -> u64 {
+        let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+        let ret = unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_COARSE, &mut ts) };
+        debug_assert_eq!(ret, 0);
+        ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn now_micros(&self) -> u64 {
+        self.inner.now_micros()
+    }
+}
+
+/// A clock that only moves when told to, for tests and deterministic simulation.
+#[derive(Default)]
+pub struct MockClock {
+    now: AtomicU64
+}
+
+impl MockClock {
+    pub fn new(now_micros: u64) -> Self {
+        Self { now: AtomicU64::new(now_micros) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Panics if that would turn the clock back, leaving it where it was.
+    pub fn set(&self, now_micros: u64) {
+        let prev = self.now.fetch_max(now_micros, Ordering::Relaxed);
+        assert!(prev <= now_micros, "a clock must not go backwards");
+    }
+}
+
+impl Clock for MockClock {
+    fn now_micros(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+
+    /// Returns at once, with the clock moved forward by `duration`.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::clock::{Clock, CoarseClock, MockClock, SystemClock};
+
+    #[test]
+    fn test_clocks() {
+        let clock = MockClock::new(10);
+        clock.advance(Duration::from_millis(2));
+        assert_eq!(clock.now_micros(), 2010);
+        clock.set(5000);
+        assert_eq!(clock.now_micros(), 5000);
+        assert!(std::panic::catch_unwind(|| clock.set(4000)).is_err());
+        assert_eq!(clock.now_micros(), 5000);
+
+        let clocks: [Box<dyn Clock>; 2] = [Box::new(SystemClock::new()), Box::new(CoarseClock::new())];
+        for clock in clocks.iter() {
+            let start = clock.now_micros();
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(clock.now_micros() >= start + 10_000);
+        }
+    }
+}
@@ -5,7 +5,23 @@ pub enum Error {
     ScTableCorrupt { reason: ErrorStr },
     ScSplitCorrupt { reason: ErrorStr },
     IOError { reason: ErrorStr, file: String },
-    RequiresExplode
+    RequiresExplode,
+    /// A read was shed because compaction is behind, see `ReadShedding`.
+    Busy { compaction_debt: usize },
+    /// A write was rejected because the free disk space is below `Options::reserved_disk_bytes`.
+    NoSpace { free_bytes: u64 },
+    CompactionJobCorrupt { reason: ErrorStr },
+    /// A compaction was aborted by `Options::paranoid_compaction_checks`.
+    CompactionCorrupt { reason: ErrorStr },
+    /// A write was rejected because of `Options::key_size_max` or `value_size_max`.
+    KeyTooLarge { size: usize, max: usize },
+    ValueTooLarge { size: usize, max: usize },
+    /// Options that contradict each other, see `Options::validate`.
+    InvalidOptions { reason: ErrorStr },
+    /// A write would have taken more than its share of `Options::total_memory_budget`.
+    MemoryLimit { used: usize, requested: usize, limit: usize },
+    /// A table file that cannot be ingested as it is.
+    IngestRejected { reason: ErrorStr }
 }
 
 #[derive(Debug, Clone)]
@@ -42,6 +58,42 @@ impl Error {
     pub(crate) fn requires_explode() -> Self {
         Error::RequiresExplode
     }
+
+    pub(crate) fn busy(compaction_debt: usize) -> Self {
+        Error::Busy { compaction_debt }
+    }
+
+    pub(crate) fn no_space(free_bytes: u64) -> Self {
+        Error::NoSpace { free_bytes }
+    }
+
+    pub(crate) fn compaction_job_corrupt(reason: ErrorStr) -> Self {
+        Error::CompactionJobCorrupt { reason }
+    }
+
+    pub(crate) fn compaction_corrupt(reason: ErrorStr) -> Self {
+        Error::CompactionCorrupt { reason }
+    }
+
+    pub(crate) fn key_too_large(size: usize, max: usize) -> Self {
+        Error::KeyTooLarge { size, max }
+    }
+
+    pub(crate) fn value_too_large(size: usize, max: usize) -> Self {
+        Error::ValueTooLarge { size, max }
+    }
+
+    pub(crate) fn invalid_options(reason: ErrorStr) -> Self {
+        Error::InvalidOptions { reason }
+    }
+
+    pub(crate) fn memory_limit(used: usize, requested: usize, limit: usize) -> Self {
+        Error::MemoryLimit { used, requested, limit }
+    }
+
+    pub(crate) fn ingest_rejected(reason: ErrorStr) -> Self {
+        Error::IngestRejected { reason }
+    }
 }
 
 impl Display for Error {
@@ -0,0 +1,60 @@
+//! Named points on the persistence path where crash tests can inject a failure, compiled to
+//! nothing without the `failpoints` feature. Failpoints are armed for the calling thread only,
+//! which is where `BackgroundMode::Inline` runs flushes and compactions, so tests running in
+//! parallel do not see each other's.
+//!
+//! - `publish_temp_written`: a published file is synced under its temporary name
+//! - `publish_renamed`: a published file has its name, but its directory is not yet synced
+//! - `table_written`: a table file is on disk, but not yet part of its level
+//! - `compaction_installed`: compaction outputs replaced their inputs, which are not yet removed
+
+#[cfg(feature = "failpoints")]
+use std::cell::RefCell;
+#[cfg(feature = "failpoints")]
+use std::collections::HashMap;
+
+#[cfg(feature = "failpoints")]
+use crate::error::Error;
+
+#[cfg(feature = "failpoints")]
+macro_rules! fail_point {
+    ($name:expr) => { crate::failpoint::eval($name)? };
+}
+
+#[cfg(not(feature = "failpoints"))]
+macro_rules! fail_point {
+    ($name:expr) => {};
+}
+
+#[cfg(feature = "failpoints")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FailAction {
+    /// The operation fails with an IO error, as if the disk had failed.
+    Error,
+    /// The thread panics, as if the process had crashed right there.
+    Panic
+}
+
+#[cfg(feature = "failpoints")]
+thread_local! {
+    static FAILPOINTS: RefCell<HashMap<String, FailAction>> = RefCell::new(HashMap::new());
+}
+
+#[cfg(feature = "failpoints")]
+pub fn enable(name: &str, action: FailAction) {
+    FAILPOINTS.with(|failpoints| failpoints.borrow_mut().insert(name.to_string(), action));
+}
+
+#[cfg(feature = "failpoints")]
+pub fn disable(name: &str) {
+    FAILPOINTS.with(|failpoints| failpoints.borrow_mut().remove(name));
+}
+
+#[cfg(feature = "failpoints")]
+pub(crate) fn eval(name: &str) -> Result<(), Error> {
+    match FAILPOINTS.with(|failpoints| failpoints.borrow().get(name).copied()) {
+        Some(FailAction::Error) => Err(Error::io_error("injected by failpoint".into(), name.to_string())),
+        Some(FailAction::Panic) => panic!("failpoint {}", name),
+        None => Ok(())
+    }
+}
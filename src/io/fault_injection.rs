@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+
+use crate::io::FileSystem;
+
+/// A `FileSystem` wrapper for crash and IO-error testing. Every fault is armed explicitly and
+/// fires once; writes and appends are counted from 1 starting at construction.
+pub struct FaultInjectionFs {
+    inner: Arc<dyn FileSystem>,
+    state: Mutex<FaultState>
+}
+
+struct FaultState {
+    // file name -> content at its last sync, `None` if it did not exist back then
+    unsynced: HashMap<String, Option<Vec<u8>>>,
+    write_count: u64,
+    sync_count: u64,
+    fail_write: Option<u64>,
+    torn_write: Option<(u64, usize)>,
+    short_reads: bool,
+    free_space: Option<u64>,
+    dir_free_space: HashMap<String, u64>
+}
+
+fn injected_error(what: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::Other, format!("injected fault: {}", what))
+}
+
+impl FaultInjectionFs {
+    pub fn new(inner: Arc<dyn FileSystem>) -> Self {
+        Self {
+            inner,
+            state: Mutex::new(FaultState {
+                unsynced: HashMap::new(),
+                write_count: 0,
+                sync_count: 0,
+                fail_write: None,
+                torn_write: None,
+                short_reads: false,
+                free_space: None,
+                dir_free_space: HashMap::new()
+            })
+        }
+    }
+
+    pub fn write_count(&self) -> u64 {
+        self.state.lock().unwrap().write_count
+    }
+
+    pub fn sync_count(&self) -> u64 {
+        self.state.lock().unwrap().sync_count
+    }
+
+    /// Makes the `n`th write (counting all writes so far) fail without touching the file.
+    pub fn fail_nth_write(&self, n: u64) {
+        self.state.lock().unwrap().fail_write.replace(n);
+    }
+
+    /// Makes the `n`th write persist only its first `keep` bytes and then fail, as if the
+    /// process crashed in the middle of it.
+    pub fn tear_nth_write(&self, n: u64, keep: usize) {
+        self.state.lock().unwrap().torn_write.replace((n, keep));
+    }
+
+    /// While enabled, every read returns only the first half of what was asked for.
+    pub fn set_short_reads(&self, short_reads: bool) {
+        self.state.lock().unwrap().short_reads = short_reads;
+    }
+
+    /// Overrides the free space the wrapped filesystem reports, `None` stops overriding it.
+    pub fn set_free_space(&self, free_space: Option<u64>) {
+        self.state.lock().unwrap().free_space = free_space;
+    }
+
+    /// Like `set_free_space`, for directory `dir_name` only; it takes precedence there.
+    pub fn set_dir_free_space(&self, dir_name: &str, free_space: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        match free_space {
+            Some(free_space) => state.dir_free_space.insert(dir_name.to_string(), free_space),
+            None => state.dir_free_space.remove(dir_name)
+        };
+    }
+
+    /// Reverts every file written since its last sync to its synced content, removing files
+    /// that were never synced. This is what a power loss leaves behind.
+    pub fn drop_unsynced_writes(&self) -> Result<(), std::io::Error> {
+        let unsynced = std::mem::take(&mut self.state.lock().unwrap().unsynced);
+        for (file_name, synced) in unsynced {
+            match synced {
+                Some(data) => {
+                    self.inner.write_file(&file_name, &data)?;
+                    self.inner.sync_file(&file_name)?;
+                },
+                None => match self.inner.remove_file(&file_name) {
+                    // removed again since, by the engine itself
+                    Err(e) if e.kind() == ErrorKind::NotFound => {},
+                    ret => ret?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a write about to happen, failing it if armed so. Returns how many bytes to keep
+    /// if it is to be torn.
+    fn next_write(&self, state: &mut FaultState, file_name: &str) -> Result<Option<usize>, std::io::Error> {
+        state.write_count += 1;
+        let nth = state.write_count;
+        if state.fail_write == Some(nth) {
+            state.fail_write.take();
+            return Err(injected_error("write failed"));
+        }
+        self.remember_synced(state, file_name);
+        match state.torn_write {
+            Some((n, keep)) if n == nth => {
+                state.torn_write.take();
+                Ok(Some(keep))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    fn remember_synced(&self, state: &mut FaultState, file_name: &str) {
+        if !state.unsynced.contains_key(file_name) {
+            let synced = self.inner.read_file(file_name).ok();
+            state.unsynced.insert(file_name.to_string(), synced);
+        }
+    }
+}
+
+impl FileSystem for FaultInjectionFs {
+    fn read_file(&self, file_name: &str) -> Result<Vec<u8>, std::io::Error> {
+        let mut data = self.inner.read_file(file_name)?;
+        if self.state.lock().unwrap().short_reads {
+            data.truncate(data.len() / 2);
+        }
+        Ok(data)
+    }
+
+    fn read_file_range(&self, file_name: &str, offset: u64, len: usize) -> Result<Vec<u8>, std::io::Error> {
+        let mut data = self.inner.read_file_range(file_name, offset, len)?;
+        if self.state.lock().unwrap().short_reads {
+            data.truncate(data.len() / 2);
+        }
+        Ok(data)
+    }
+
+    fn file_size(&self, file_name: &str) -> Result<u64, std::io::Error> {
+        self.inner.file_size(file_name)
+    }
+
+    fn write_file(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(keep) = self.next_write(&mut state, file_name)? {
+            self.inner.write_file(file_name, &data[..keep.min(data.len())])?;
+            return Err(injected_error("torn write"));
+        }
+        self.inner.write_file(file_name, data)
+    }
+
+    fn write_file_at(&self, file_name: &str, offset: u64, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(keep) = self.next_write(&mut state, file_name)? {
+            self.inner.write_file_at(file_name, offset, &data[..keep.min(data.len())])?;
+            return Err(injected_error("torn write"));
+        }
+        self.inner.write_file_at(file_name, offset, data)
+    }
+
+    fn append_file(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(keep) = self.next_write(&mut state, file_name)? {
+            self.inner.append_file(file_name, &data[..keep.min(data.len())])?;
+            return Err(injected_error("torn write"));
+        }
+        self.inner.append_file(file_name, data)
+    }
+
+    fn sync_file(&self, file_name: &str) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock().unwrap();
+        self.inner.sync_file(file_name)?;
+        state.unsynced.remove(file_name);
+        state.sync_count += 1;
+        Ok(())
+    }
+
+    fn sync_data(&self, file_name: &str) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock().unwrap();
+        self.inner.sync_data(file_name)?;
+        state.unsynced.remove(file_name);
+        state.sync_count += 1;
+        Ok(())
+    }
+
+    fn remove_file(&self, file_name: &str) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock().unwrap();
+        self.remember_synced(&mut state, file_name);
+        self.inner.remove_file(file_name)
+    }
+
+    /// Renames count as durable right away, the file keeps the synced content it had.
+    fn rename_file(&self, from: &str, to: &str) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock().unwrap();
+        self.inner.rename_file(from, to)?;
+        state.unsynced.remove(to);
+        if let Some(synced) = state.unsynced.remove(from) {
+            state.unsynced.insert(to.to_string(), synced);
+        }
+        Ok(())
+    }
+
+    fn atomic_sync(&self) -> bool {
+        self.inner.atomic_sync()
+    }
+
+    fn list_dir(&self, dir_name: &str) -> Result<Vec<String>, std::io::Error> {
+        self.inner.list_dir(dir_name)
+    }
+
+    fn sync_dir(&self, dir_name: &str) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock().unwrap();
+        self.inner.sync_dir(dir_name)?;
+        state.sync_count += 1;
+        Ok(())
+    }
+
+    fn preallocate(&self, file_name: &str, len: u64) -> Result<(), std::io::Error> {
+        self.inner.preallocate(file_name, len)
+    }
+
+    fn free_space(&self, dir_name: &str) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state.dir_free_space.get(dir_name).copied()
+            .or(state.free_space)
+            .or_else(|| self.inner.free_space(dir_name))
+    }
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod test {
+    use std::sync::Arc;
+
+    use crate::io::{FileSystem, StdFileSystem, IOManager};
+    use crate::io::fault_injection::FaultInjectionFs;
+
+    fn test_file(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("pr65_fault_injection_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_fail_and_tear_writes() {
+        let fs = FaultInjectionFs::new(Arc::new(StdFileSystem()));
+        let file_name = test_file("tear");
+        fs.fail_nth_write(1);
+        fs.tear_nth_write(2, 3);
+        assert!(fs.write_file(&file_name, b"scott").is_err());
+        assert!(fs.read_file(&file_name).is_err());
+        assert!(fs.write_file(&file_name, b"scott").is_err());
+        assert_eq!(fs.read_file(&file_name).unwrap(), b"sco");
+        fs.write_file(&file_name, b"scott").unwrap();
+        assert_eq!(fs.write_count(), 3);
+        fs.set_short_reads(true);
+        assert_eq!(fs.read_file(&file_name).unwrap(), b"sc");
+        fs.remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn test_drop_unsynced_writes() {
+        let fs = Arc::new(FaultInjectionFs::new(Arc::new(StdFileSystem())));
+        let synced = test_file("synced");
+        let unsynced = test_file("unsynced");
+
+        let io_manager = IOManager::new(1, fs.clone(), 0);
+        io_manager.acquire_quota().write_file(synced.clone(), b"durable").unwrap();
+        fs.write_file(&synced, b"lost").unwrap();
+        fs.write_file(&unsynced, b"lost").unwrap();
+        fs.drop_unsynced_writes().unwrap();
+
+        assert_eq!(io_manager.acquire_quota().read_file(synced.clone()).unwrap(), b"durable");
+        assert!(io_manager.acquire_quota().read_file(unsynced).is_err());
+        fs.remove_file(&synced).unwrap();
+    }
+
+    #[test]
+    fn test_bytes_per_sync() {
+        let fs = Arc::new(FaultInjectionFs::new(Arc::new(StdFileSystem())));
+        let file_name = test_file("bytes_per_sync");
+
+        let io_manager = IOManager::new(1, fs.clone(), 4);
+        io_manager.acquire_quota().write_file(file_name.clone(), b"0123456789").unwrap();
+        assert_eq!(fs.write_count(), 3);
+        assert_eq!(fs.sync_count(), 3);
+        assert_eq!(io_manager.acquire_quota().read_file(file_name.clone()).unwrap(), b"0123456789");
+
+        // a power loss in the middle keeps what was synced before it
+        fs.tear_nth_write(6, 1);
+        assert!(io_manager.acquire_quota().write_file(file_name.clone(), b"abcdefghij").is_err());
+        fs.drop_unsynced_writes().unwrap();
+        assert_eq!(fs.read_file(&file_name).unwrap(), b"abcdefgh");
+
+        // unwrapped, the file is written through a single handle
+        let io_manager = IOManager::new(1, Arc::new(StdFileSystem()), 4);
+        io_manager.acquire_quota().write_file(file_name.clone(), b"0123456789").unwrap();
+        assert_eq!(io_manager.acquire_quota().read_file(file_name.clone()).unwrap(), b"0123456789");
+        fs.remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn test_publish_file() {
+        let fs = Arc::new(FaultInjectionFs::new(Arc::new(StdFileSystem())));
+        let file_name = test_file("publish");
+
+        let io_manager = IOManager::new(1, fs.clone(), 0);
+        io_manager.acquire_quota().publish_file(file_name.clone(), b"durable").unwrap();
+        assert_eq!(fs.sync_count(), 2);
+
+        // a write failing halfway leaves the published file alone, and no temporary file
+        fs.tear_nth_write(2, 3);
+        assert!(io_manager.acquire_quota().publish_file(file_name.clone(), b"lost").is_err());
+        assert!(fs.read_file(&format!("{}.tmp", file_name)).is_err());
+        fs.drop_unsynced_writes().unwrap();
+        assert_eq!(fs.read_file(&file_name).unwrap(), b"durable");
+        fs.remove_file(&file_name).unwrap();
+    }
+}
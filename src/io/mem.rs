@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+
+use crate::io::FileSystem;
+
+/// A `FileSystem` keeping every file in memory, see `Options::in_memory`; `sync_file` is a
+/// no-op.
+pub struct MemFileSystem {
+    files: Mutex<HashMap<String, Vec<u8>>>
+}
+
+impl Default for MemFileSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemFileSystem {
+    pub fn new() -> Self {
+        Self { files: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn file_names(&self) -> Vec<String> {
+        let mut file_names: Vec<String> = self.files.lock().unwrap().keys().cloned().collect();
+        file_names.sort();
+        file_names
+    }
+}
+
+fn not_found(file_name: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::NotFound, format!("{} not found", file_name))
+}
+
+impl FileSystem for MemFileSystem {
+    fn read_file(&self, file_name: &str) -> Result<Vec<u8>, std::io::Error> {
+        self.files.lock().unwrap().get(file_name).cloned().ok_or_else(|| not_found(file_name))
+    }
+
+    fn read_file_range(&self, file_name: &str, offset: u64, len: usize) -> Result<Vec<u8>, std::io::Error> {
+        let files = self.files.lock().unwrap();
+        let data = files.get(file_name).ok_or_else(|| not_found(file_name))?;
+        let offset = offset as usize;
+        if offset + len > data.len() {
+            return Err(std::io::Error::new(ErrorKind::UnexpectedEof, format!("{} is too short", file_name)));
+        }
+        Ok(data[offset..offset + len].to_vec())
+    }
+
+    fn file_size(&self, file_name: &str) -> Result<u64, std::io::Error> {
+        self.files.lock().unwrap().get(file_name).map(|data| data.len() as u64).ok_or_else(|| not_found(file_name))
+    }
+
+    fn write_file(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        self.files.lock().unwrap().insert(file_name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn write_file_at(&self, file_name: &str, offset: u64, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut files = self.files.lock().unwrap();
+        let content = files.get_mut(file_name).ok_or_else(|| not_found(file_name))?;
+        let offset = offset as usize;
+        if offset + data.len() > content.len() {
+            return Err(std::io::Error::new(ErrorKind::UnexpectedEof, format!("{} is too short", file_name)));
+        }
+        content[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn append_file(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        self.files.lock().unwrap().entry(file_name.to_string()).or_default().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn sync_file(&self, file_name: &str) -> Result<(), std::io::Error> {
+        if self.files.lock().unwrap().contains_key(file_name) {
+            Ok(())
+        } else {
+            Err(not_found(file_name))
+        }
+    }
+
+    fn remove_file(&self, file_name: &str) -> Result<(), std::io::Error> {
+        self.files.lock().unwrap().remove(file_name).map(|_| ()).ok_or_else(|| not_found(file_name))
+    }
+
+    fn rename_file(&self, from: &str, to: &str) -> Result<(), std::io::Error> {
+        let mut files = self.files.lock().unwrap();
+        let data = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_string(), data);
+        Ok(())
+    }
+
+    fn list_dir(&self, dir_name: &str) -> Result<Vec<String>, std::io::Error> {
+        let files = self.files.lock().unwrap();
+        Ok(files.keys()
+            .filter_map(|file_name| match file_name.rsplit_once('/') {
+                Some((dir, name)) if dir == dir_name => Some(name.to_string()),
+                None if dir_name == "." => Some(file_name.clone()),
+                _ => None
+            })
+            .collect())
+    }
+}
@@ -1,20 +1,166 @@
+#[cfg(any(test, feature = "testutil"))]
+pub(crate) mod fault_injection;
+pub(crate) mod mem;
+#[cfg(feature = "std-fs")]
+pub(crate) mod std_fs;
+pub(crate) mod trace;
+#[cfg(feature = "object-store")]
+pub(crate) mod object_store;
+
+use std::io::ErrorKind;
+use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
-use std::fs::File;
-use std::io::{Read, Write};
 use std_semaphore::Semaphore;
 
 use crate::error;
+use crate::clock::Clock;
+use crate::io::trace::{IoReason, IoTrace, IoTraceRecord};
+#[cfg(feature = "std-fs")]
+pub use crate::io::std_fs::StdFileSystem;
+
+/// Storage backend used by `IOManager`. Files are written as a whole or appended to;
+/// a written file only counts as durable after `sync_file` returns.
+pub trait FileSystem: Send + Sync {
+    fn read_file(&self, file_name: &str) -> Result<Vec<u8>, std::io::Error>;
+
+    fn read_file_range(&self, file_name: &str, offset: u64, len: usize) -> Result<Vec<u8>, std::io::Error>;
+
+    /// By default the whole file is read, backends that know the size without should say so.
+    fn file_size(&self, file_name: &str) -> Result<u64, std::io::Error> {
+        Ok(self.read_file(file_name)?.len() as u64)
+    }
+
+    fn write_file(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error>;
+
+    /// Overwrites the bytes of `file_name` from `offset` with `data`, which must not reach past
+    /// its end. By default the whole file is read and written again, backends that can write
+    /// in place should.
+    fn write_file_at(&self, file_name: &str, offset: u64, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut content = self.read_file(file_name)?;
+        let offset = offset as usize;
+        if offset + data.len() > content.len() {
+            return Err(std::io::Error::new(ErrorKind::UnexpectedEof, format!("{} is too short", file_name)))
+        }
+        content[offset..offset + data.len()].copy_from_slice(data);
+        self.write_file(file_name, &content)
+    }
+
+    /// Adds `data` to the end of `file_name`, creating it if missing. By default the whole file
+    /// is read and written again, backends that can append should.
+    fn append_file(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut content = match self.read_file(file_name) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e)
+        };
+        content.extend_from_slice(data);
+        self.write_file(file_name, &content)
+    }
+
+    /// Writes `data` to `file_name` in chunks of `bytes_per_sync` bytes, syncing what is
+    /// written so far before every next chunk, so that the final `sync_file` does not flush
+    /// all of it at once. Backends that keep files open should write through one handle.
+    fn write_file_synced(&self, file_name: &str, data: &[u8], bytes_per_sync: usize) -> Result<(), std::io::Error> {
+        let mut chunks = data.chunks(bytes_per_sync);
+        self.write_file(file_name, chunks.next().unwrap_or(&[]))?;
+        let _ = self.preallocate(file_name, data.len() as u64);
+        for chunk in chunks {
+            self.sync_data(file_name)?;
+            self.append_file(file_name, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn sync_file(&self, file_name: &str) -> Result<(), std::io::Error>;
+
+    /// Like `sync_file` but may skip metadata that is not needed to read the data back
+    /// (fdatasync). Used for the intermediate syncs of large writes.
+    fn sync_data(&self, file_name: &str) -> Result<(), std::io::Error> {
+        self.sync_file(file_name)
+    }
+
+    fn remove_file(&self, file_name: &str) -> Result<(), std::io::Error>;
+
+    /// Replaces `to` with `from` in one step, so that `to` is never seen half written.
+    /// Backends that cannot must leave it unsupported, see `atomic_sync`.
+    fn rename_file(&self, _from: &str, _to: &str) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(ErrorKind::Unsupported, "rename is not supported"))
+    }
+
+    /// Whether a written file stays invisible until `sync_file` makes all of it visible at
+    /// once, as object store uploads do. Files are then published under their final name,
+    /// without a rename.
+    fn atomic_sync(&self) -> bool {
+        false
+    }
+
+    /// Names of the files in directory `dir_name`, for removing what a crash left behind.
+    fn list_dir(&self, _dir_name: &str) -> Result<Vec<String>, std::io::Error> {
+        Err(std::io::Error::new(ErrorKind::Unsupported, "listing directories is not supported"))
+    }
+
+    /// Makes the renames and removals in directory `dir_name` durable, like `sync_file` does
+    /// for file contents. Backends without directories have nothing to do.
+    fn sync_dir(&self, _dir_name: &str) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Hints that `file_name` is going to grow to `len` bytes, without changing its size. A
+    /// failure is no reason to give up the write, callers ignore it.
+    fn preallocate(&self, _file_name: &str, _len: u64) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Bytes still available for files in directory `dir_name`, `None` if unknown.
+    fn free_space(&self, _dir_name: &str) -> Option<u64> {
+        None
+    }
+}
 
 pub(crate) struct IOManager {
     open_files: AtomicUsize,
-    sem: Semaphore
+    sem: Semaphore,
+    fs: Arc<dyn FileSystem>,
+    bytes_per_sync: usize,
+    trace: Option<IoTrace>
 }
 
-pub(crate) struct FileQuota<'a>(&'a IOManager);
+pub(crate) struct FileQuota<'a>(&'a IOManager, IoReason);
 
 impl<'a> FileQuota<'a> {
     pub(crate) fn read_file(self, file_name: String) -> Result<Vec<u8>, error::Error> {
-        self.read_file_impl(&file_name).or_else(
+        let FileQuota(io_manager, reason) = self;
+        let start = io_manager.trace.as_ref().map(|trace| trace.now_micros());
+        let ret = self.read_file_impl(&file_name);
+        if let (Some(trace), Some(start), Ok(data)) = (&io_manager.trace, start, &ret) {
+            trace.record(&file_name, 0, data.len(), start, reason);
+        }
+        ret.or_else(
+            |e| {
+                Err(error::Error::io_error(e.to_string().into(),
+                                           file_name))
+            }
+        )
+    }
+
+    pub(crate) fn read_file_range(self, file_name: String, offset: u64, len: usize) -> Result<Vec<u8>, error::Error> {
+        let FileQuota(io_manager, reason) = &self;
+        let start = io_manager.trace.as_ref().map(|trace| trace.now_micros());
+        let ret = io_manager.fs.read_file_range(&file_name, offset, len);
+        if let (Some(trace), Some(start), Ok(_)) = (&io_manager.trace, start, &ret) {
+            trace.record(&file_name, offset, len, start, *reason);
+        }
+        ret.or_else(
+            |e| {
+                Err(error::Error::io_error(e.to_string().into(),
+                                           file_name))
+            }
+        )
+    }
+
+    pub(crate) fn file_size(self, file_name: String) -> Result<u64, error::Error> {
+        let FileQuota(io_manager, _) = &self;
+        io_manager.fs.file_size(&file_name).or_else(
             |e| {
                 Err(error::Error::io_error(e.to_string().into(),
                                            file_name))
@@ -31,42 +177,175 @@ impl<'a> FileQuota<'a> {
         )
     }
 
-    fn read_file_impl(self, file_name: &String) -> Result<Vec<u8>, std::io::Error> {
-        let mut v = Vec::new();
-        File::with_options()
-            .read(true)
-            .write(false)
-            .open(file_name)?
-            .read_to_end(&mut v)?;
-        Ok(v)
+    /// Writes `data` to a temporary file next to `file_name` and only renames it into place
+    /// once it is synced, so that a crash leaves either no file or a complete one. A failed
+    /// publish removes the temporary file; one left by a crash goes at the next open, see
+    /// `IOManager::remove_temp_files`.
+    pub(crate) fn publish_file(self, file_name: String, data: &[u8]) -> Result<(), error::Error> {
+        self.publish_file_impl(&file_name, data).or_else(
+            |e| {
+                Err(error::Error::io_error(e.to_string().into(),
+                                           file_name))
+            }
+        )
+    }
+
+    /// Overwrites a few bytes of `file_name` in place and syncs it.
+    pub(crate) fn write_file_at(self, file_name: String, offset: u64, data: &[u8]) -> Result<(), error::Error> {
+        let FileQuota(io_manager, _) = &self;
+        let fs = &io_manager.fs;
+        fs.write_file_at(&file_name, offset, data).and_then(|_| fs.sync_file(&file_name)).or_else(
+            |e| {
+                Err(error::Error::io_error(e.to_string().into(),
+                                           file_name))
+            }
+        )
+    }
+
+    /// Renames `from` to `to` and makes the rename durable. Fails on backends that cannot
+    /// rename, see `FileSystem::rename_file`.
+    pub(crate) fn move_file(self, from: String, to: String) -> Result<(), error::Error> {
+        let FileQuota(io_manager, _) = &self;
+        let fs = &io_manager.fs;
+        fs.rename_file(&from, &to).and_then(|_| fs.sync_dir(dir_name(&to))).or_else(
+            |e| {
+                Err(error::Error::io_error(e.to_string().into(),
+                                           from))
+            }
+        )
+    }
+
+    pub(crate) fn remove_file(self, file_name: String) -> Result<(), error::Error> {
+        let FileQuota(io_manager, _) = &self;
+        io_manager.fs.remove_file(&file_name).or_else(
+            |e| {
+                Err(error::Error::io_error(e.to_string().into(),
+                                           file_name))
+            }
+        )
     }
 
-    fn write_file_impl(self, file_name: &String, data: &[u8]) -> Result<(), std::io::Error> {
-        File::with_options()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(file_name)?
-            .write_all(data)?;
+    fn read_file_impl(self, file_name: &str) -> Result<Vec<u8>, std::io::Error> {
+        let FileQuota(io_manager, _) = &self;
+        io_manager.fs.read_file(file_name)
+    }
+
+    fn publish_file_impl(self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let FileQuota(io_manager, _) = &self;
+        let fs = &io_manager.fs;
+        if fs.atomic_sync() {
+            return self.write_file_impl(file_name, data)
+        }
+        let temp_file_name = temp_file_name(file_name);
+        let ret = self.write_file_impl(&temp_file_name, data)
+            .and_then(|_| publish_fail_point("publish_temp_written"))
+            .and_then(|_| fs.rename_file(&temp_file_name, file_name));
+        if ret.is_err() {
+            // the error that matters is the one above, the file may not even exist
+            let _ = fs.remove_file(&temp_file_name);
+            return ret
+        }
+        publish_fail_point("publish_renamed")?;
+        fs.sync_dir(dir_name(file_name))
+    }
+
+    fn write_file_impl(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let FileQuota(io_manager, _) = self;
+        let fs = &io_manager.fs;
+        if io_manager.bytes_per_sync == 0 {
+            fs.write_file(file_name, data)?;
+        } else {
+            fs.write_file_synced(file_name, data, io_manager.bytes_per_sync)?;
+        }
+        fs.sync_file(file_name)
+    }
+}
+
+fn publish_fail_point(name: &str) -> Result<(), std::io::Error> {
+    #[cfg_attr(not(feature = "failpoints"), allow(unused_variables))]
+    fn eval(name: &str) -> Result<(), error::Error> {
+        fail_point!(name);
         Ok(())
     }
+    eval(name).map_err(|e| std::io::Error::new(ErrorKind::Other, format!("{:?}", e)))
+}
+
+/// Where `publish_file` writes `file_name` before it is complete.
+fn temp_file_name(file_name: &str) -> String {
+    format!("{}.tmp", file_name)
+}
+
+fn dir_name(file_name: &str) -> &str {
+    match std::path::Path::new(file_name).parent().and_then(|dir| dir.to_str()) {
+        Some(dir_name) if !dir_name.is_empty() => dir_name,
+        _ => "."
+    }
 }
 
 impl<'a> Drop for FileQuota<'a> {
     fn drop(&mut self) {
-        let FileQuota(io_manager) = self;
+        let FileQuota(io_manager, _) = self;
         io_manager.on_quota_released()
     }
 }
 
 impl IOManager {
-    pub fn new(max_open_files: usize) -> Self {
-        Self { open_files: AtomicUsize::new(0), sem: Semaphore::new(max_open_files as isize) }
+    /// `bytes_per_sync == 0` syncs written files only once they are complete.
+    pub fn new(max_open_files: usize, fs: Arc<dyn FileSystem>, bytes_per_sync: usize) -> Self {
+        Self {
+            open_files: AtomicUsize::new(0),
+            sem: Semaphore::new(max_open_files as isize),
+            fs,
+            bytes_per_sync,
+            trace: None
+        }
+    }
+
+    /// Keeps the last `capacity` reads, timed by `clock`.
+    pub fn with_trace(mut self, capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        self.trace = if capacity == 0 { None } else { Some(IoTrace::new(capacity, clock)) };
+        self
+    }
+
+    pub(crate) fn trace_records(&self) -> Vec<IoTraceRecord> {
+        self.trace.as_ref().map_or_else(Vec::new, |trace| trace.records())
+    }
+
+    pub(crate) fn free_space(&self, dir_name: &str) -> Option<u64> {
+        self.fs.free_space(dir_name)
+    }
+
+    /// Removes the temporary files of files named `*suffix` that `publish_file` left in
+    /// `dir_name` when the process crashed, so that only complete files remain. Backends that
+    /// cannot list directories have nothing removed.
+    pub(crate) fn remove_temp_files(&self, dir_name: &str, suffix: &str) -> Result<(), error::Error> {
+        let io_error = |e: std::io::Error, file_name: String| error::Error::io_error(e.to_string().into(), file_name);
+        let file_names = match self.fs.list_dir(dir_name) {
+            Ok(file_names) => file_names,
+            Err(e) if e.kind() == ErrorKind::Unsupported => return Ok(()),
+            Err(e) => return Err(io_error(e, dir_name.to_string()))
+        };
+        let temp_suffix = temp_file_name(suffix);
+        let mut removed = false;
+        for file_name in file_names.iter().filter(|file_name| file_name.ends_with(&temp_suffix)) {
+            let file_path = if dir_name == "." { file_name.clone() } else { format!("{}/{}", dir_name, file_name) };
+            self.fs.remove_file(&file_path).map_err(|e| io_error(e, file_path))?;
+            removed = true;
+        }
+        if removed {
+            self.fs.sync_dir(dir_name).map_err(|e| io_error(e, dir_name.to_string()))?;
+        }
+        Ok(())
     }
 
     pub fn acquire_quota(&self) -> FileQuota {
+        self.acquire_quota_for(IoReason::Other)
+    }
+
+    /// Reads through the quota are traced as done for `reason`.
+    pub fn acquire_quota_for(&self, reason: IoReason) -> FileQuota {
         self.sem.acquire();
-        FileQuota(self)
+        FileQuota(self, reason)
     }
 
     fn on_quota_released(&self) {
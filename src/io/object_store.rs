@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+
+use crate::io::FileSystem;
+
+/// The few object store operations `ObjectStoreFs` needs, for an S3 or GCS client to
+/// implement. Objects are immutable once their multipart upload completes.
+pub trait ObjectStore: Send + Sync {
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, std::io::Error>;
+
+    fn object_size(&self, key: &str) -> Result<u64, std::io::Error>;
+
+    /// Starts an upload and returns its id.
+    fn create_multipart_upload(&self, key: &str) -> Result<String, std::io::Error>;
+
+    /// Parts are numbered from 1.
+    fn upload_part(&self, key: &str, upload_id: &str, part_number: u32, data: &[u8]) -> Result<(), std::io::Error>;
+
+    fn complete_multipart_upload(&self, key: &str, upload_id: &str) -> Result<(), std::io::Error>;
+
+    fn delete_object(&self, key: &str) -> Result<(), std::io::Error>;
+}
+
+pub const OBJECT_STORE_DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+pub const OBJECT_STORE_DEFAULT_PART_SIZE: usize = 8 << 20;
+
+/// A `FileSystem` keeping table files in an object store. Reads are range GETs of aligned
+/// chunks, which are kept in a local cache filesystem. Writes are staged in memory and
+/// uploaded in parts by `sync_file`, which is when they become visible.
+pub struct ObjectStoreFs {
+    store: Arc<dyn ObjectStore>,
+    cache: Arc<dyn FileSystem>,
+    chunk_size: usize,
+    part_size: usize,
+    staged: Mutex<HashMap<String, Vec<u8>>>
+}
+
+impl ObjectStoreFs {
+    pub fn new(store: Arc<dyn ObjectStore>, cache: Arc<dyn FileSystem>) -> Self {
+        Self {
+            store,
+            cache,
+            chunk_size: OBJECT_STORE_DEFAULT_CHUNK_SIZE,
+            part_size: OBJECT_STORE_DEFAULT_PART_SIZE,
+            staged: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0);
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub fn with_part_size(mut self, part_size: usize) -> Self {
+        assert!(part_size > 0);
+        self.part_size = part_size;
+        self
+    }
+
+    fn chunk_name(file_name: &str, chunk: u64) -> String {
+        format!("{}.chunk{}", file_name, chunk)
+    }
+
+    fn read_chunk(&self, file_name: &str, chunk: u64, file_size: u64) -> Result<Vec<u8>, std::io::Error> {
+        let chunk_name = Self::chunk_name(file_name, chunk);
+        if let Ok(data) = self.cache.read_file(&chunk_name) {
+            return Ok(data)
+        }
+        let offset = chunk * self.chunk_size as u64;
+        let len = (file_size - offset).min(self.chunk_size as u64) as usize;
+        let data = self.store.get_range(file_name, offset, len)?;
+        // the cache is only an optimisation, failing to fill it is not an error
+        let _ = self.cache.write_file(&chunk_name, &data);
+        Ok(data)
+    }
+
+    fn drop_cached_chunks(&self, file_name: &str) {
+        let mut chunk = 0;
+        while self.cache.remove_file(&Self::chunk_name(file_name, chunk)).is_ok() {
+            chunk += 1;
+        }
+    }
+
+    fn upload(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let upload_id = self.store.create_multipart_upload(file_name)?;
+        // an empty object still takes one empty part
+        let mut parts: Vec<&[u8]> = data.chunks(self.part_size).collect();
+        if parts.is_empty() {
+            parts.push(&[]);
+        }
+        for (n, part) in parts.into_iter().enumerate() {
+            self.store.upload_part(file_name, &upload_id, n as u32 + 1, part)?;
+        }
+        self.store.complete_multipart_upload(file_name, &upload_id)?;
+        self.drop_cached_chunks(file_name);
+        Ok(())
+    }
+}
+
+impl FileSystem for ObjectStoreFs {
+    fn read_file(&self, file_name: &str) -> Result<Vec<u8>, std::io::Error> {
+        let file_size = self.file_size(file_name)?;
+        self.read_file_range(file_name, 0, file_size as usize)
+    }
+
+    fn read_file_range(&self, file_name: &str, offset: u64, len: usize) -> Result<Vec<u8>, std::io::Error> {
+        if let Some(data) = self.staged.lock().unwrap().get(file_name) {
+            let offset = offset as usize;
+            if offset + len > data.len() {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, format!("{} is too short", file_name)));
+            }
+            return Ok(data[offset..offset + len].to_vec())
+        }
+
+        let file_size = self.store.object_size(file_name)?;
+        if offset + len as u64 > file_size {
+            return Err(std::io::Error::new(ErrorKind::UnexpectedEof, format!("{} is too short", file_name)));
+        }
+        let chunk_size = self.chunk_size as u64;
+        let mut ret = Vec::with_capacity(len);
+        let mut pos = offset;
+        while pos < offset + len as u64 {
+            let chunk = pos / chunk_size;
+            let data = self.read_chunk(file_name, chunk, file_size)?;
+            let begin = (pos - chunk * chunk_size) as usize;
+            let end = (offset + len as u64 - chunk * chunk_size).min(data.len() as u64) as usize;
+            ret.extend_from_slice(&data[begin..end]);
+            pos = chunk * chunk_size + end as u64;
+        }
+        Ok(ret)
+    }
+
+    fn file_size(&self, file_name: &str) -> Result<u64, std::io::Error> {
+        if let Some(data) = self.staged.lock().unwrap().get(file_name) {
+            return Ok(data.len() as u64)
+        }
+        self.store.object_size(file_name)
+    }
+
+    fn write_file(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        self.staged.lock().unwrap().insert(file_name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn append_file(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        self.staged.lock().unwrap().entry(file_name.to_string()).or_default().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn sync_file(&self, file_name: &str) -> Result<(), std::io::Error> {
+        let data = match self.staged.lock().unwrap().remove(file_name) {
+            Some(data) => data,
+            None => return Ok(())
+        };
+        let ret = self.upload(file_name, &data);
+        if ret.is_err() {
+            // keep it staged, so that syncing again retries the upload
+            self.staged.lock().unwrap().entry(file_name.to_string()).or_insert(data);
+        }
+        ret
+    }
+
+    /// An object only appears once its upload completes, there is nothing to rename.
+    fn atomic_sync(&self) -> bool {
+        true
+    }
+
+    /// Parts cannot be uploaded before the whole file is known, intermediate syncs do nothing.
+    fn sync_data(&self, _file_name: &str) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn remove_file(&self, file_name: &str) -> Result<(), std::io::Error> {
+        if self.staged.lock().unwrap().remove(file_name).is_some() {
+            return Ok(())
+        }
+        self.drop_cached_chunks(file_name);
+        self.store.delete_object(file_name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::ErrorKind;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::io::{FileSystem, IOManager};
+    use crate::io::mem::MemFileSystem;
+    use crate::io::object_store::{ObjectStore, ObjectStoreFs};
+
+    #[derive(Default)]
+    struct MemObjectStore {
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+        uploads: Mutex<HashMap<String, Vec<Vec<u8>>>>,
+        gets: AtomicUsize
+    }
+
+    fn not_found(key: &str) -> std::io::Error {
+        std::io::Error::new(ErrorKind::NotFound, key.to_string())
+    }
+
+    impl ObjectStore for MemObjectStore {
+        fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, std::io::Error> {
+            self.gets.fetch_add(1, Ordering::Relaxed);
+            let objects = self.objects.lock().unwrap();
+            let data = objects.get(key).ok_or_else(|| not_found(key))?;
+            Ok(data[offset as usize..offset as usize + len].to_vec())
+        }
+
+        fn object_size(&self, key: &str) -> Result<u64, std::io::Error> {
+            self.objects.lock().unwrap().get(key).map(|data| data.len() as u64).ok_or_else(|| not_found(key))
+        }
+
+        fn create_multipart_upload(&self, key: &str) -> Result<String, std::io::Error> {
+            self.uploads.lock().unwrap().insert(key.to_string(), Vec::new());
+            Ok(key.to_string())
+        }
+
+        fn upload_part(&self, _key: &str, upload_id: &str, part_number: u32, data: &[u8]) -> Result<(), std::io::Error> {
+            let mut uploads = self.uploads.lock().unwrap();
+            let parts = uploads.get_mut(upload_id).ok_or_else(|| not_found(upload_id))?;
+            assert_eq!(parts.len() as u32 + 1, part_number);
+            parts.push(data.to_vec());
+            Ok(())
+        }
+
+        fn complete_multipart_upload(&self, key: &str, upload_id: &str) -> Result<(), std::io::Error> {
+            let parts = self.uploads.lock().unwrap().remove(upload_id).ok_or_else(|| not_found(upload_id))?;
+            self.objects.lock().unwrap().insert(key.to_string(), parts.concat());
+            Ok(())
+        }
+
+        fn delete_object(&self, key: &str) -> Result<(), std::io::Error> {
+            self.objects.lock().unwrap().remove(key).map(|_| ()).ok_or_else(|| not_found(key))
+        }
+    }
+
+    #[test]
+    fn test_object_store_fs() {
+        let store = Arc::new(MemObjectStore::default());
+        let fs = Arc::new(ObjectStoreFs::new(store.clone(), Arc::new(MemFileSystem::new()))
+            .with_chunk_size(4)
+            .with_part_size(3));
+        let data: Vec<u8> = (0..10u8).collect();
+
+        // staged until synced, then uploaded in parts
+        let io_manager = IOManager::new(1, fs.clone(), 4);
+        io_manager.acquire_quota().write_file("table".to_string(), &data).unwrap();
+        assert_eq!(store.objects.lock().unwrap().get("table"), Some(&data));
+
+        assert_eq!(fs.read_file_range("table", 3, 6).unwrap(), &data[3..9]);
+        assert_eq!(store.gets.load(Ordering::Relaxed), 3);
+        // served by the cache from now on
+        assert_eq!(fs.read_file("table").unwrap(), data);
+        assert_eq!(fs.read_file_range("table", 9, 1).unwrap(), &data[9..]);
+        assert_eq!(store.gets.load(Ordering::Relaxed), 3);
+        assert!(fs.read_file_range("table", 9, 2).is_err());
+
+        fs.remove_file("table").unwrap();
+        assert!(fs.read_file("table").is_err());
+        assert!(store.objects.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_publish_file() {
+        let store = Arc::new(MemObjectStore::default());
+        let fs = Arc::new(ObjectStoreFs::new(store.clone(), Arc::new(MemFileSystem::new())));
+
+        // uploaded once, straight under its name
+        let io_manager = IOManager::new(1, fs.clone(), 0);
+        io_manager.acquire_quota().publish_file("table".to_string(), b"table").unwrap();
+        let keys: Vec<String> = store.objects.lock().unwrap().keys().cloned().collect();
+        assert_eq!(keys, vec!["table".to_string()]);
+        assert_eq!(store.gets.load(Ordering::Relaxed), 0);
+    }
+}
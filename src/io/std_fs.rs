@@ -0,0 +1,160 @@
+use std::fs::File;
+use std::io::{Read, Write, Seek, SeekFrom};
+
+use crate::io::FileSystem;
+
+/// Best effort: not every filesystem supports it, and a write that needs the space fails on
+/// its own if it is missing.
+#[cfg(target_os = "linux")]
+fn preallocate_file(file: &File, len: u64) {
+    use std::os::unix::io::AsRawFd;
+
+    if len > 0 {
+        unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len as libc::off_t) };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate_file(_file: &File, _len: u64) {}
+
+/// Free space of the filesystem holding directory `dir_name`.
+#[cfg(target_os = "linux")]
+fn dir_free_space(dir_name: &str) -> Option<u64> {
+    let dir_name = std::ffi::CString::new(dir_name).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::statvfs(dir_name.as_ptr(), &mut stat) };
+    if ret == 0 {
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn dir_free_space(_dir_name: &str) -> Option<u64> {
+    None
+}
+
+/// The filesystem of the OS, through `std::fs`. Not built without the `std-fs` feature, for
+/// targets where it is missing.
+pub struct StdFileSystem();
+
+impl FileSystem for StdFileSystem {
+    fn read_file(&self, file_name: &str) -> Result<Vec<u8>, std::io::Error> {
+        let mut v = Vec::new();
+        File::with_options()
+            .read(true)
+            .write(false)
+            .open(file_name)?
+            .read_to_end(&mut v)?;
+        Ok(v)
+    }
+
+    fn read_file_range(&self, file_name: &str, offset: u64, len: usize) -> Result<Vec<u8>, std::io::Error> {
+        let mut v = vec![0u8; len];
+        let mut file = File::with_options()
+            .read(true)
+            .write(false)
+            .open(file_name)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut v)?;
+        Ok(v)
+    }
+
+    fn file_size(&self, file_name: &str) -> Result<u64, std::io::Error> {
+        Ok(std::fs::metadata(file_name)?.len())
+    }
+
+    fn write_file(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut file = File::with_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_name)?;
+        preallocate_file(&file, data.len() as u64);
+        file.write_all(data)
+    }
+
+    fn write_file_at(&self, file_name: &str, offset: u64, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut file = File::with_options()
+            .write(true)
+            .open(file_name)?;
+        if offset + data.len() as u64 > file.metadata()?.len() {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("{} is too short", file_name)))
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn append_file(&self, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        File::with_options()
+            .append(true)
+            .create(true)
+            .open(file_name)?
+            .write_all(data)
+    }
+
+    fn write_file_synced(&self, file_name: &str, data: &[u8], bytes_per_sync: usize) -> Result<(), std::io::Error> {
+        let mut file = File::with_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_name)?;
+        preallocate_file(&file, data.len() as u64);
+        for (n, chunk) in data.chunks(bytes_per_sync).enumerate() {
+            if n > 0 {
+                file.sync_data()?;
+            }
+            file.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn sync_file(&self, file_name: &str) -> Result<(), std::io::Error> {
+        File::with_options()
+            .write(true)
+            .open(file_name)?
+            .sync_all()
+    }
+
+    fn sync_data(&self, file_name: &str) -> Result<(), std::io::Error> {
+        File::with_options()
+            .write(true)
+            .open(file_name)?
+            .sync_data()
+    }
+
+    fn remove_file(&self, file_name: &str) -> Result<(), std::io::Error> {
+        std::fs::remove_file(file_name)
+    }
+
+    fn rename_file(&self, from: &str, to: &str) -> Result<(), std::io::Error> {
+        std::fs::rename(from, to)
+    }
+
+    fn list_dir(&self, dir_name: &str) -> Result<Vec<String>, std::io::Error> {
+        let mut file_names = Vec::new();
+        for entry in std::fs::read_dir(dir_name)? {
+            if let Ok(file_name) = entry?.file_name().into_string() {
+                file_names.push(file_name);
+            }
+        }
+        Ok(file_names)
+    }
+
+    fn sync_dir(&self, dir_name: &str) -> Result<(), std::io::Error> {
+        File::open(dir_name)?.sync_all()
+    }
+
+    fn preallocate(&self, file_name: &str, len: u64) -> Result<(), std::io::Error> {
+        let file = File::with_options()
+            .write(true)
+            .open(file_name)?;
+        preallocate_file(&file, len);
+        Ok(())
+    }
+
+    fn free_space(&self, dir_name: &str) -> Option<u64> {
+        dir_free_space(dir_name)
+    }
+}
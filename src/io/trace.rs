@@ -0,0 +1,144 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::clock::Clock;
+
+/// What a traced read was for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum IoReason {
+    Get,
+    Compaction,
+    Other
+}
+
+/// A read of a table file, see `Options::io_trace_capacity`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IoTraceRecord {
+    pub file_name: String,
+    pub offset: u64,
+    pub len: usize,
+    pub latency_micros: u64,
+    pub reason: IoReason
+}
+
+/// The last `capacity` reads.
+pub(crate) struct IoTrace {
+    capacity: usize,
+    clock: Arc<dyn Clock>,
+    records: Mutex<VecDeque<IoTraceRecord>>
+}
+
+impl IoTrace {
+    pub(crate) fn new(capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        Self { capacity, clock, records: Mutex::new(VecDeque::with_capacity(capacity)) }
+    }
+
+    pub(crate) fn now_micros(&self) -> u64 {
+        self.clock.now_micros()
+    }
+
+    pub(crate) fn record(&self, file_name: &str, offset: u64, len: usize, start: u64, reason: IoReason) {
+        let record = IoTraceRecord {
+            file_name: file_name.to_string(),
+            offset,
+            len,
+            latency_micros: self.clock.now_micros().saturating_sub(start),
+            reason
+        };
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    pub(crate) fn records(&self) -> Vec<IoTraceRecord> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct IoHeat {
+    pub reads: u64,
+    pub bytes: u64,
+    pub latency_micros: u64
+}
+
+impl IoHeat {
+    fn add(&mut self, record: &IoTraceRecord) {
+        self.reads += 1;
+        self.bytes += record.len as u64;
+        self.latency_micros += record.latency_micros;
+    }
+}
+
+/// Where traced reads went. A read counts towards the bucket its offset falls into.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IoHeatmap {
+    pub bucket_size: u64,
+    /// By file name, one entry per `bucket_size` bytes of the file up to the last one read
+    pub files: BTreeMap<String, Vec<IoHeat>>,
+    /// By the level the table files were written to
+    pub levels: Vec<IoHeat>
+}
+
+impl IoHeatmap {
+    pub fn new(records: &[IoTraceRecord], bucket_size: u64) -> Self {
+        assert!(bucket_size > 0);
+        let mut ret = Self { bucket_size, ..Self::default() };
+        for record in records.iter() {
+            let buckets = ret.files.entry(record.file_name.clone()).or_default();
+            let bucket = (record.offset / bucket_size) as usize;
+            if buckets.len() <= bucket {
+                buckets.resize(bucket + 1, IoHeat::default());
+            }
+            buckets[bucket].add(record);
+
+            if let Some(level) = table_file_level(&record.file_name) {
+                if ret.levels.len() <= level {
+                    ret.levels.resize(level + 1, IoHeat::default());
+                }
+                ret.levels[level].add(record);
+            }
+        }
+        ret
+    }
+}
+
+/// The level in the name of a table file, `<partition>_<level>_<number>.sst`.
+fn table_file_level(file_name: &str) -> Option<usize> {
+    let name = Path::new(file_name).file_name()?.to_str()?;
+    let mut parts = name.strip_suffix(".sst")?.split('_');
+    let _partition = parts.next()?;
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::io::trace::{IoHeat, IoHeatmap, IoReason, IoTrace};
+
+    #[test]
+    fn test_io_heatmap() {
+        let clock = Arc::new(MockClock::new(0));
+        let trace = IoTrace::new(3, clock.clone());
+        for (file_name, offset) in [("0_1_1.sst", 0), ("0_1_1.sst", 5000), ("dir/0_2_3.sst", 100), ("0_1_1.sst", 4100)].iter() {
+            let start = trace.now_micros();
+            clock.advance(Duration::from_micros(10));
+            trace.record(file_name, *offset, 100, start, IoReason::Get);
+        }
+        // the oldest record fell out
+        let records = trace.records();
+        assert_eq!(records.len(), 3);
+
+        let heatmap = IoHeatmap::new(&records, 4096);
+        let heat = IoHeat { reads: 1, bytes: 100, latency_micros: 10 };
+        assert_eq!(heatmap.files["0_1_1.sst"], vec![IoHeat::default(), IoHeat { reads: 2, bytes: 200, latency_micros: 20 }]);
+        assert_eq!(heatmap.files["dir/0_2_3.sst"], vec![heat]);
+        assert_eq!(heatmap.levels, vec![IoHeat::default(), IoHeat { reads: 2, bytes: 200, latency_micros: 20 }, heat]);
+    }
+}
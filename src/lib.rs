@@ -5,15 +5,43 @@
 use std::cmp::Ordering;
 use std::marker::PhantomData;
 use std::collections::VecDeque;
+use std::sync::Arc;
 use std::sync::atomic::AtomicU64;
 
+#[macro_use]
+mod failpoint;
+mod clock;
 mod encode;
 mod error;
 mod table;
 mod partition;
 mod io;
+mod memory;
+mod statistics;
 
 pub use table::tablefmt;
+pub use error::{Error, ErrorStr};
+pub use clock::{Clock, CoarseClock, MockClock, SystemClock};
+pub use statistics::{Statistics, StatisticsSnapshot, Histogram, HistogramSnapshot, HistogramType, SizeHistogram,
+                     SIZE_HISTOGRAM_BUCKETS, Ticker};
+pub use io::{FileSystem, mem::MemFileSystem};
+#[cfg(feature = "std-fs")]
+pub use io::StdFileSystem;
+pub use io::trace::{IoHeat, IoHeatmap, IoReason, IoTraceRecord};
+#[cfg(feature = "testutil")]
+pub use io::fault_injection::FaultInjectionFs;
+#[cfg(feature = "object-store")]
+pub use io::object_store::{ObjectStore, ObjectStoreFs};
+pub use partition::scheduler::{BackgroundJobInfo, BackgroundJobKind, BackgroundMode};
+pub use partition::{EntrySizes, FileChecksum, LevelSpaceUsage, PartitionInfo, PlannedCompaction, ReadShedding, ScanCost, SpaceUsage};
+pub use partition::hot_keys::HotKey;
+pub use partition::remote::{CompactionJob, CompactionOutput, CompactionService};
+#[cfg(feature = "failpoints")]
+pub use failpoint::{enable as enable_failpoint, disable as disable_failpoint, FailAction};
+pub use table::cache::{BlockCache, CacheAdmission, CacheShardStats};
+pub use table::secondary::SecondaryCache;
+pub use table::filter::{FilterKeys, FilterPolicy};
+pub use memory::MemoryUsage;
 
 pub trait Comparator {
     fn compare(lhs: &[u8], rhs: &[u8]) -> Ordering;
@@ -27,15 +55,121 @@ impl Comparator for DefaultComparator {
     }
 }
 
+/// A directory for table files, filled up to about `target_size` bytes before the next one of
+/// `Options::db_paths` takes over.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DbPath {
+    pub path: String,
+    pub target_size: u64
+}
+
+impl DbPath {
+    pub fn new(path: impl ToString, target_size: u64) -> Self {
+        Self { path: path.to_string(), target_size }
+    }
+}
+
 pub struct Options {
     pub db_name: String,
     pub cache_count: usize,
+    pub cache_admission: CacheAdmission,
+    pub cache_shards: usize,
+    pub block_cache: Option<Arc<BlockCache>>,
+    pub row_cache_size: usize,
+    /// 0 disables the bloom filter in front of the memtables
+    pub memtable_filter_bits_per_key: usize,
+    /// Level 0 of a partition gets compacted once it has more tables than this
     pub level0_size: usize,
+    /// Writes of a partition with at least this many tables in level 0 are delayed by a
+    /// millisecond each, and stopped until a compaction catches up at `level0_stop_writes`.
+    /// Both must exceed `level0_size`, and the slowdown must start below the stop; 0 disables
+    /// them
+    pub level0_slowdown_writes: usize,
+    pub level0_stop_writes: usize,
     pub size_factor: usize,
     pub max_open_files: usize,
     pub table_size: usize,
+    /// Compaction outputs of level `n` target `table_size * table_size_multiplier^(n-1)`
+    pub table_size_multiplier: usize,
+    pub block_size: usize,
+    pub filter_policy: FilterPolicy,
+    /// Picks the keys of the filters of the tables written to a level
+    pub filter_keys: Arc<dyn Fn(usize) -> FilterKeys + Send + Sync>,
+    pub filter_bits_per_key: usize,
+    /// Adds a hash index to every block so that point lookups skip the binary search; only
+    /// for comparators that consider keys equal exactly when their bytes are
+    pub block_hash_index: bool,
+    pub read_shedding: ReadShedding,
+    /// How many times reads load a block again after its checksum failed
+    pub corrupt_block_retries: usize,
+    /// Reads go on to older tables instead of failing when a block stays corrupt; they may
+    /// return an older version of a key then
+    pub skip_corrupt_tables: bool,
+    /// Cuts compaction outputs where the partition is likely to be split
+    pub compaction_split_hints: bool,
+    /// Bounds the input of a compaction, in file bytes read from both levels. Level 0 leaves
+    /// its newer tables to follow-up compactions; other levels merge their table with only
+    /// the leading tables of the next level that fit, and rewrite the rest of it in place. A
+    /// single table of each level is always picked, however large; 0 disables the bound
+    pub max_compaction_bytes: u64,
+    /// Compactions check that their inputs are sorted and that their outputs fit into the
+    /// output level, and fail with `Error::CompactionCorrupt` otherwise
+    pub paranoid_compaction_checks: bool,
+    /// Replicas applying the same writes in the same order write byte-identical table files,
+    /// so that comparing checksums detects divergence. Nothing random or time dependent picks
+    /// compaction inputs or cuts outputs; the background jobs must run in a fixed order too,
+    /// as `BackgroundMode::Inline` does
+    pub deterministic_compaction: bool,
+    /// How many of the flushed keys every partition samples to predict where it will be
+    /// split, within the table the data sizes point to; 0 predicts from the table bounds only
+    pub key_sample_size: usize,
+    /// How many of the most frequently read or written keys every partition tracks for
+    /// `ScottDB::hot_keys`; 0 disables the tracking
+    pub hot_key_count: usize,
+    /// Only one in this many reads and writes is counted towards the hot keys
+    pub hot_key_sample_rate: u32,
+    /// Reads and writes of every partition wait rather than go beyond these rates, so that
+    /// one busy partition cannot take all of the IO; 0 does not limit them
+    pub partition_reads_per_sec: u64,
+    pub partition_writes_per_sec: u64,
+    /// Where table files go, by level: a level goes to the first path with room for it and
+    /// all the levels above, the last path takes the rest. The directories must exist. Empty
+    /// keeps the tables in the working directory
+    pub db_paths: Vec<DbPath>,
+    pub bytes_per_sync: usize,
+    /// Writes that would flush a memtable fail with `Error::NoSpace` while the directory of
+    /// level 0 has less free space than this; flushes already started and compactions go on,
+    /// except those into another directory short of it too. 0 disables it
+    pub reserved_disk_bytes: u64,
+    pub compaction_readahead_size: usize,
+    /// How many of the latest table file reads `ScottDB::io_trace` keeps; 0 traces nothing
+    pub io_trace_capacity: usize,
+    /// Runs compactions through this service instead of in the background threads
+    pub compaction_service: Option<Arc<dyn CompactionService>>,
+    /// Writes of larger keys and values fail with `Error::KeyTooLarge` and `ValueTooLarge`
     pub key_size_max: usize,
     pub value_size_max: usize,
+    /// Hard cap on the memory of the database, split by `MemoryUsage::split`: writes that
+    /// would take the memtables past their share, or flush while the table readers are past
+    /// theirs, fail with `Error::MemoryLimit`, and the block cache holds no more blocks than
+    /// fit into its share. Cannot be combined with a shared `block_cache`. 0 disables the cap
+    pub total_memory_budget: usize,
+    /// Where table files are kept, `StdFileSystem` unless built without the `std-fs` feature
+    pub file_system: Arc<dyn FileSystem>,
+    pub background_mode: BackgroundMode,
+    /// Source of the time behind the latency histograms
+    pub clock: Arc<dyn Clock>,
+}
+
+#[cfg(feature = "std-fs")]
+fn default_file_system() -> Arc<dyn FileSystem> {
+    Arc::new(StdFileSystem())
+}
+
+/// Without `std::fs` nothing outlives the database unless `Options::file_system` is set.
+#[cfg(not(feature = "std-fs"))]
+fn default_file_system() -> Arc<dyn FileSystem> {
+    Arc::new(MemFileSystem::new())
 }
 
 impl Options {
@@ -50,23 +184,181 @@ impl Options {
         Self {
             db_name: db_name.to_string(),
             cache_count,
+            cache_admission: CacheAdmission::Lru,
+            cache_shards: 1,
+            block_cache: None,
+            row_cache_size: 0,
+            memtable_filter_bits_per_key: 0,
             level0_size,
+            level0_slowdown_writes: 0,
+            level0_stop_writes: 0,
             size_factor,
             max_open_files,
             table_size,
+            table_size_multiplier: 1,
+            block_size: tablefmt::TABLE_DEFAULT_BLOCK_SIZE,
+            filter_policy: FilterPolicy::Bloom,
+            filter_keys: Arc::new(|_| FilterKeys::WholeKey),
+            filter_bits_per_key: tablefmt::TABLE_DEFAULT_FILTER_BITS_PER_KEY,
+            block_hash_index: false,
+            read_shedding: ReadShedding::Disabled,
+            corrupt_block_retries: 0,
+            skip_corrupt_tables: false,
+            compaction_split_hints: false,
+            max_compaction_bytes: 0,
+            paranoid_compaction_checks: false,
+            deterministic_compaction: false,
+            key_sample_size: 256,
+            hot_key_count: 0,
+            hot_key_sample_rate: 100,
+            partition_reads_per_sec: 0,
+            partition_writes_per_sec: 0,
+            db_paths: Vec::new(),
+            bytes_per_sync: 0,
+            reserved_disk_bytes: 0,
+            compaction_readahead_size: 2 << 20,
+            io_trace_capacity: 0,
+            compaction_service: None,
             key_size_max,
             value_size_max,
+            total_memory_budget: 0,
+            file_system: default_file_system(),
+            background_mode: BackgroundMode::Inline,
+            clock: Arc::new(SystemClock::new()),
+        }
+    }
+
+    /// Keeps every file in memory, nothing outlives the database.
+    pub fn in_memory(mut self) -> Self {
+        self.file_system = Arc::new(MemFileSystem::new());
+        self
+    }
+
+    /// For reads of single keys: `cache_mb` of block cache that scans cannot flush, small
+    /// blocks and whole key filters in front of both the memtables and the tables.
+    pub fn optimized_for_point_lookup(mut self, cache_mb: usize) -> Self {
+        self.block_size = tablefmt::TABLE_DEFAULT_BLOCK_SIZE;
+        self.cache_count = ((cache_mb << 20) / self.block_size).max(1);
+        self.cache_admission = CacheAdmission::TinyLfu;
+        self.block_cache = None;
+        self.filter_policy = FilterPolicy::Bloom;
+        self.filter_keys = Arc::new(|_| FilterKeys::WholeKey);
+        self.filter_bits_per_key = tablefmt::TABLE_DEFAULT_FILTER_BITS_PER_KEY;
+        self.memtable_filter_bits_per_key = tablefmt::TABLE_DEFAULT_FILTER_BITS_PER_KEY;
+        self
+    }
+
+    /// For loading a lot of data before reading it: big memtables and tables so that
+    /// compaction keeps up, no filters, and syncs spread over the writes.
+    pub fn optimized_for_bulk_load(mut self) -> Self {
+        self.table_size = 64 << 20;
+        self.table_size_multiplier = 1;
+        self.level0_size = 8;
+        self.filter_keys = Arc::new(|_| FilterKeys::Disabled);
+        self.memtable_filter_bits_per_key = 0;
+        self.bytes_per_sync = 1 << 20;
+        self.compaction_readahead_size = 8 << 20;
+        self
+    }
+
+    /// Keeps the memory of the database to a few megabytes: small memtables, a 1MB block
+    /// cache, no row cache and no memtable filter.
+    pub fn low_memory(mut self) -> Self {
+        self.table_size = 4 << 20;
+        self.block_size = tablefmt::TABLE_DEFAULT_BLOCK_SIZE;
+        self.cache_count = (1 << 20) / self.block_size;
+        self.cache_shards = 1;
+        self.block_cache = None;
+        self.row_cache_size = 0;
+        self.memtable_filter_bits_per_key = 0;
+        self.max_open_files = self.max_open_files.min(64);
+        self.compaction_readahead_size = 256 << 10;
+        self
+    }
+
+    /// For data that is rarely read: big blocks and tables growing with the level, a wide
+    /// fanout, and the smaller ribbon filters.
+    pub fn optimized_for_archive(mut self) -> Self {
+        self.table_size = 64 << 20;
+        self.table_size_multiplier = 2;
+        self.size_factor = 10;
+        self.block_size = 64 << 10;
+        self.filter_policy = FilterPolicy::Ribbon;
+        self.filter_keys = Arc::new(|_| FilterKeys::WholeKey);
+        self.memtable_filter_bits_per_key = 0;
+        self
+    }
+
+    /// Fails with `Error::InvalidOptions` for settings the database could not run with.
+    pub fn validate(&self) -> Result<(), Error> {
+        // writes waiting for a compaction that level 0 is too small to start would wait forever
+        if self.level0_slowdown_writes != 0 && self.level0_slowdown_writes <= self.level0_size {
+            return Err(Error::invalid_options("level0_slowdown_writes must exceed level0_size".into()))
+        }
+        if self.level0_stop_writes != 0 && self.level0_stop_writes <= self.level0_size {
+            return Err(Error::invalid_options("level0_stop_writes must exceed level0_size".into()))
+        }
+        if self.level0_slowdown_writes != 0 && self.level0_stop_writes != 0
+           && self.level0_slowdown_writes >= self.level0_stop_writes {
+            return Err(Error::invalid_options("level0_slowdown_writes must be below level0_stop_writes".into()))
+        }
+        // a shared cache has the size its owner gave it, whatever share of the budget is left for it
+        if self.total_memory_budget != 0 && self.block_cache.is_some() {
+            return Err(Error::invalid_options("a shared block_cache cannot be held to total_memory_budget".into()))
+        }
+        Ok(())
+    }
+
+    /// Shares of `total_memory_budget`, `None` if there is none.
+    fn memory_budget(&self) -> Option<MemoryUsage> {
+        if self.total_memory_budget == 0 {
+            None
+        } else {
+            Some(MemoryUsage::split(self.total_memory_budget))
         }
     }
 
     fn level_size(&self, level: usize) -> usize {
         self.level0_size * self.size_factor.pow(level as u32)
     }
+
+    /// Directory of the tables of `level`, see `db_paths`.
+    fn db_path(&self, level: usize) -> Option<&str> {
+        let level_bytes: u64 = (0..=level)
+            .map(|n| (self.level_size(n) as u64).saturating_mul(self.target_table_size(n) as u64))
+            .fold(0, u64::saturating_add);
+        let mut path_bytes = 0u64;
+        for db_path in self.db_paths.iter() {
+            path_bytes = path_bytes.saturating_add(db_path.target_size);
+            if level_bytes <= path_bytes {
+                return Some(&db_path.path)
+            }
+        }
+        self.db_paths.last().map(|db_path| db_path.path.as_str())
+    }
+
+    /// Every directory table files may be in, see `db_paths`.
+    fn table_dirs(&self) -> Vec<&str> {
+        if self.db_paths.is_empty() {
+            vec!["."]
+        } else {
+            self.db_paths.iter().map(|db_path| db_path.path.as_str()).collect()
+        }
+    }
+
+    /// Size at which compaction cuts the tables it writes to `level`.
+    fn target_table_size(&self, level: usize) -> usize {
+        let multiplier = self.table_size_multiplier.saturating_pow(level.saturating_sub(1) as u32);
+        self.table_size.saturating_mul(multiplier).min(tablefmt::TABLE_MAX_SIZE)
+    }
 }
 
 use crate::io::IOManager;
 use crate::table::cache::TableCacheManager;
 use crate::partition::ArcPartition;
+use crate::partition::scheduler::BackgroundScheduler;
+use crate::memory::MemoryTracker;
+use crate::table::sctable::TABLE_FILE_SUFFIX;
 
 pub struct ScottDB<'a, Comp: 'static + Comparator> {
     phantom: PhantomData<Comp>,
@@ -76,27 +368,259 @@ pub struct ScottDB<'a, Comp: 'static + Comparator> {
     partitions: VecDeque<ArcPartition<'a, Comp>>,
     cache_manager: TableCacheManager,
     io_manager: IOManager,
+    statistics: Statistics,
+    memory_tracker: Option<Arc<MemoryTracker>>,
+    scheduler: BackgroundScheduler<'a, Comp>,
 }
 
 impl<'a, Comp: 'static + Comparator> ScottDB<'a, Comp> {
+    /// Panics if `options` fail `Options::validate`.
     pub fn new(options: Options) -> Self {
-        let cache_count = options.cache_count;
+        if let Err(e) = options.validate() {
+            panic!("invalid options: {:?}", e)
+        }
+        let memory_budget = options.memory_budget();
+        let cache_count = match &memory_budget {
+            Some(memory_budget) => options.cache_count.min((memory_budget.block_cache / options.block_size).max(1)),
+            None => options.cache_count
+        };
+        let cache_manager = match &options.block_cache {
+            Some(block_cache) => TableCacheManager::shared(block_cache.clone()),
+            None => TableCacheManager::with_shards(cache_count, options.cache_shards, options.cache_admission)
+        };
         let max_open_files = options.max_open_files;
+        let file_system = options.file_system.clone();
+        let bytes_per_sync = options.bytes_per_sync;
+        let background_mode = options.background_mode;
+        let io_manager = IOManager::new(max_open_files, file_system, bytes_per_sync)
+            .with_trace(options.io_trace_capacity, options.clock.clone());
+        for dir_name in options.table_dirs() {
+            // only a crash leaves them, and nothing refers to them; failing to remove them
+            // wastes space but breaks nothing
+            let _ = io_manager.remove_temp_files(dir_name, TABLE_FILE_SUFFIX);
+        }
         Self {
             phantom: PhantomData,
             options,
             seq: AtomicU64::new(0),
             partitions: VecDeque::new(),
-            cache_manager: TableCacheManager::new(cache_count),
-            io_manager: IOManager::new(max_open_files),
+            cache_manager,
+            io_manager,
+            statistics: Statistics::new(),
+            memory_tracker: memory_budget.map(|memory_budget| Arc::new(MemoryTracker::new(memory_budget))),
+            scheduler: BackgroundScheduler::new(background_mode),
+        }
+    }
+
+    pub fn pending_background_jobs(&self) -> usize {
+        self.scheduler.pending_jobs()
+    }
+
+    /// Runs the oldest queued flush or compaction when `Options::background_mode` is
+    /// `BackgroundMode::Manual`; returns `false` once the queue is empty.
+    pub fn step_background_job(&self) -> bool {
+        self.scheduler.step()
+    }
+
+    /// Waits for running flushes and compactions to finish and holds new ones until
+    /// `continue_background_work`, e.g. while copying the table files. Calls nest. Writes that
+    /// need a flush block until background work continues.
+    pub fn pause_background_work(&self) {
+        self.scheduler.pause()
+    }
+
+    pub fn continue_background_work(&self) {
+        self.scheduler.resume()
+    }
+
+    /// Moves the memtable of every partition out of the way of new writes and schedules its
+    /// flush. Everything written up to the returned sequence number is immutable then, in
+    /// tables or in memtables being flushed. Blocks like writes while a partition's previous
+    /// memtable is still being flushed.
+    pub fn freeze_memtables(&self) -> Result<u64, Error> {
+        let mut ret = 0;
+        for partition in self.partitions.iter() {
+            ret = ret.max(partition.freeze_memtable()?);
+        }
+        Ok(ret)
+    }
+
+    /// The running flushes and compactions, then the queued ones.
+    pub fn background_jobs(&self) -> Vec<BackgroundJobInfo> {
+        self.scheduler.jobs()
+    }
+
+    pub fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+
+    /// Per shard, so that skew between the shards of the block cache shows.
+    pub fn block_cache_stats(&self) -> Vec<CacheShardStats> {
+        self.cache_manager.shard_stats()
+    }
+
+    /// Memory charged against `Options::total_memory_budget`, `None` without a budget. The
+    /// block cache is counted in full blocks.
+    pub fn memory_usage(&self) -> Option<MemoryUsage> {
+        let memory_tracker = self.memory_tracker.as_ref()?;
+        let cached_blocks: usize = self.block_cache_stats().iter().map(|stats| stats.occupancy).sum();
+        Some(MemoryUsage { block_cache: cached_blocks * self.options.block_size, ..memory_tracker.usage() })
+    }
+
+    /// Sizes of the keys and values in the tables, from their footers, e.g. to pick a block
+    /// size or filter settings.
+    pub fn entry_sizes(&self) -> Vec<EntrySizes> {
+        self.partitions.iter().map(|partition| partition.entry_sizes()).collect()
+    }
+
+    pub fn partitions(&self) -> Vec<PartitionInfo> {
+        self.partitions.iter().map(|partition| partition.info()).collect()
+    }
+
+    /// Names of the table files reads found a corrupt block in.
+    pub fn corrupt_table_files(&self) -> Vec<String> {
+        self.partitions.iter().flat_map(|partition| partition.corrupt_table_files()).collect()
+    }
+
+    /// What reading the keys from `lower` to `upper`, inclusive, would take, estimated from
+    /// the table indexes without any IO.
+    pub fn estimate_scan_cost(&self, lower: &[u8], upper: &[u8]) -> ScanCost {
+        let mut ret = ScanCost::default();
+        for partition in self.partitions.iter() {
+            ret.add(partition.scan_cost(lower, upper));
         }
+        ret
+    }
+
+    pub fn space_usage(&self) -> Vec<SpaceUsage> {
+        self.partitions.iter().map(|partition| partition.space_usage()).collect()
+    }
+
+    /// The keys hit most often, from every partition, most frequent first. Empty unless
+    /// `Options::hot_key_count` is set.
+    pub fn hot_keys(&self) -> Vec<HotKey> {
+        let mut hot_keys: Vec<HotKey> = self.partitions.iter().flat_map(|partition| partition.hot_keys()).collect();
+        hot_keys.sort_by_key(|hot_key| std::cmp::Reverse(hot_key.count));
+        hot_keys
+    }
+
+    /// Size and checksum of every live table file, so that copies of them can be checked
+    /// without going through the database. Ingested tables are left out until a compaction
+    /// rewrites them.
+    pub fn file_checksums(&self) -> Vec<FileChecksum> {
+        self.partitions.iter().flat_map(|partition| partition.file_checksums()).collect()
+    }
+
+    /// Reads back every table file, failing on the first one that does not match its checksum.
+    pub fn verify_integrity(&self) -> Result<(), Error> {
+        self.partitions.iter().try_for_each(|partition| partition.verify_file_checksums())
+    }
+
+    /// The latest reads of table files, oldest first, see `Options::io_trace_capacity`.
+    pub fn io_trace(&self) -> Vec<IoTraceRecord> {
+        self.io_manager.trace_records()
+    }
+
+    /// Where the reads of `io_trace` went, in buckets of `bucket_size` bytes of every file.
+    pub fn io_heatmap(&self, bucket_size: u64) -> IoHeatmap {
+        IoHeatmap::new(&self.io_trace(), bucket_size)
+    }
+
+    /// The compactions that would run now, without running them.
+    pub fn plan_compactions(&self) -> Vec<PlannedCompaction> {
+        self.partitions.iter().flat_map(|partition| partition.plan_compactions()).collect()
+    }
+
+    /// Key ranges of the corrupt tables, as `(lower, upper)` inclusive bounds.
+    pub fn degraded_ranges(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.partitions.iter().flat_map(|partition| partition.degraded_ranges()).collect()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn prometheus_metrics(&self) -> String {
+        let partitions: Vec<_> = self.partitions.iter().map(|partition| partition.gauges()).collect();
+        statistics::prometheus::render(&self.options.db_name, &self.statistics.snapshot(), &partitions)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use crate::{BlockCache, CacheAdmission, DefaultComparator, FileSystem, MemFileSystem, Options, ScottDB};
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_validate_options() {
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.validate().unwrap();
+        options.level0_stop_writes = 2;
+        assert!(options.validate().is_err());
+        options.level0_stop_writes = 4;
+        options.validate().unwrap();
+        options.level0_slowdown_writes = 4;
+        assert!(options.validate().is_err());
+        options.level0_slowdown_writes = 3;
+        options.validate().unwrap();
+        options.level0_stop_writes = 0;
+        options.level0_slowdown_writes = 1;
+        assert!(options.validate().is_err());
+        options.level0_slowdown_writes = 0;
+
+        options.block_cache = Some(Arc::new(BlockCache::new(4, CacheAdmission::Lru)));
+        options.validate().unwrap();
+        options.total_memory_budget = 1 << 20;
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn test_remove_temp_files() {
+        let fs = Arc::new(MemFileSystem::new());
+        for file_name in ["0_0_1.sst", "0_0_2.sst.tmp", "notes.tmp", "cold/0_1_1.sst.tmp"].iter() {
+            fs.write_file(file_name, b"table").unwrap();
+        }
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.file_system = fs.clone();
+        let _db = ScottDB::<DefaultComparator>::new(options);
+        assert_eq!(fs.file_names(), vec!["0_0_1.sst", "cold/0_1_1.sst.tmp", "notes.tmp"]);
+
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.file_system = fs.clone();
+        options.db_paths = vec![crate::DbPath::new("cold", 0)];
+        let _db = ScottDB::<DefaultComparator>::new(options);
+        assert_eq!(fs.file_names(), vec!["0_0_1.sst", "notes.tmp"]);
+    }
+
+    #[cfg(feature = "failpoints")]
+    #[test]
+    fn test_publish_failpoints() {
+        use crate::failpoint::{self, FailAction};
+        use crate::io::IOManager;
+
+        let fs = Arc::new(MemFileSystem::new());
+        let io_manager = IOManager::new(1, fs.clone(), 0);
+        // a crash before the rename leaves the temporary file, which the next open removes
+        failpoint::enable("publish_temp_written", FailAction::Panic);
+        let crashed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            io_manager.acquire_quota().publish_file("0_0_1.sst".to_string(), b"table")
+        }));
+        failpoint::disable("publish_temp_written");
+        assert!(crashed.is_err());
+        assert_eq!(fs.file_names(), vec!["0_0_1.sst.tmp"]);
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.file_system = fs.clone();
+        let _db = ScottDB::<DefaultComparator>::new(options);
+        assert!(fs.file_names().is_empty());
+
+        // once renamed the file is complete, even if the directory is never synced
+        failpoint::enable("publish_renamed", FailAction::Error);
+        assert!(io_manager.acquire_quota().publish_file("0_0_1.sst".to_string(), b"table").is_err());
+        failpoint::disable("publish_renamed");
+        assert_eq!(fs.file_names(), vec!["0_0_1.sst"]);
+        assert_eq!(fs.read_file("0_0_1.sst").unwrap(), b"table".to_vec());
+    }
 }
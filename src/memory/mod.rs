@@ -0,0 +1,197 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::Error;
+
+/// Bytes by what holds them, either as the shares of `Options::total_memory_budget` or as
+/// what is in use, see `ScottDB::memory_usage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Memtables and immutable memtables waiting for their flush
+    pub memtables: usize,
+    pub block_cache: usize,
+    /// Indexes and filters of the open tables
+    pub table_readers: usize,
+    /// Blocks held outside of the block cache while reads look into them
+    pub pinned_blocks: usize
+}
+
+impl MemoryUsage {
+    /// Half of `total` goes to the memtables, a quarter to the block cache, an eighth to the
+    /// pinned blocks and the rest to the table readers.
+    pub fn split(total: usize) -> Self {
+        let memtables = total / 2;
+        let block_cache = total / 4;
+        let pinned_blocks = total / 8;
+        Self { memtables, block_cache, table_readers: total - memtables - block_cache - pinned_blocks, pinned_blocks }
+    }
+
+    pub fn total(&self) -> usize {
+        self.memtables + self.block_cache + self.table_readers + self.pinned_blocks
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum MemoryKind {
+    Memtables,
+    TableReaders,
+    PinnedBlocks
+}
+
+/// Memory charged by all partitions against the shares of the budget. Partitions charge what
+/// they hold and check before they would grow past a share.
+pub(crate) struct MemoryTracker {
+    limits: MemoryUsage,
+    memtables: AtomicUsize,
+    table_readers: AtomicUsize,
+    pinned_blocks: AtomicUsize
+}
+
+impl MemoryTracker {
+    pub(crate) fn new(limits: MemoryUsage) -> Self {
+        Self {
+            limits,
+            memtables: AtomicUsize::new(0),
+            table_readers: AtomicUsize::new(0),
+            pinned_blocks: AtomicUsize::new(0)
+        }
+    }
+
+    fn used(&self, kind: MemoryKind) -> (&AtomicUsize, usize) {
+        match kind {
+            MemoryKind::Memtables => (&self.memtables, self.limits.memtables),
+            MemoryKind::TableReaders => (&self.table_readers, self.limits.table_readers),
+            MemoryKind::PinnedBlocks => (&self.pinned_blocks, self.limits.pinned_blocks)
+        }
+    }
+
+    /// Fails with `Error::MemoryLimit` if `bytes` more of `kind` would not fit.
+    pub(crate) fn check(&self, kind: MemoryKind, bytes: usize) -> Result<(), Error> {
+        let (used, limit) = self.used(kind);
+        let used = used.load(Ordering::Relaxed);
+        if used.saturating_add(bytes) > limit {
+            Err(Error::memory_limit(used, bytes, limit))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Replaces a charge of `old` bytes of `kind` with one of `new` bytes.
+    pub(crate) fn recharge(&self, kind: MemoryKind, old: usize, new: usize) {
+        let (used, _) = self.used(kind);
+        if new > old {
+            used.fetch_add(new - old, Ordering::Relaxed);
+        } else {
+            used.fetch_sub(old - new, Ordering::Relaxed);
+        }
+    }
+
+    /// Charges `bytes` of `kind` until the returned pin is dropped, or fails with
+    /// `Error::MemoryLimit` if they do not fit.
+    pub(crate) fn pin(self: &Arc<Self>, kind: MemoryKind, bytes: usize) -> Result<MemoryPin, Error> {
+        let (used, limit) = self.used(kind);
+        let before = used.fetch_add(bytes, Ordering::Relaxed);
+        if before.saturating_add(bytes) > limit {
+            used.fetch_sub(bytes, Ordering::Relaxed);
+            return Err(Error::memory_limit(before, bytes, limit))
+        }
+        Ok(MemoryPin { tracker: self.clone(), kind, bytes })
+    }
+
+    pub(crate) fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            memtables: self.memtables.load(Ordering::Relaxed),
+            block_cache: 0,
+            table_readers: self.table_readers.load(Ordering::Relaxed),
+            pinned_blocks: self.pinned_blocks.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// Memory charged by `MemoryTracker::pin`, released when dropped.
+pub(crate) struct MemoryPin {
+    tracker: Arc<MemoryTracker>,
+    kind: MemoryKind,
+    bytes: usize
+}
+
+impl Drop for MemoryPin {
+    fn drop(&mut self) {
+        self.tracker.recharge(self.kind, self.bytes, 0)
+    }
+}
+
+/// What one partition holds, as charged to a `MemoryTracker` last. The charge is released
+/// when the partition goes away.
+pub(crate) struct MemoryCharge {
+    tracker: Arc<MemoryTracker>,
+    charged: MemoryUsage
+}
+
+impl MemoryCharge {
+    pub(crate) fn new(tracker: Arc<MemoryTracker>) -> Self {
+        Self { tracker, charged: MemoryUsage::default() }
+    }
+
+    pub(crate) fn tracker(&self) -> &Arc<MemoryTracker> {
+        &self.tracker
+    }
+
+    /// Replaces the charge with `held`.
+    pub(crate) fn recharge(&mut self, held: MemoryUsage) {
+        self.tracker.recharge(MemoryKind::Memtables, self.charged.memtables, held.memtables);
+        self.tracker.recharge(MemoryKind::TableReaders, self.charged.table_readers, held.table_readers);
+        self.charged = held;
+    }
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.recharge(MemoryUsage::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::error::Error;
+    use crate::memory::{MemoryCharge, MemoryKind, MemoryTracker, MemoryUsage};
+
+    #[test]
+    fn test_split() {
+        let limits = MemoryUsage::split(1001);
+        assert_eq!(limits, MemoryUsage { memtables: 500, block_cache: 250, table_readers: 126, pinned_blocks: 125 });
+        assert_eq!(limits.total(), 1001);
+    }
+
+    #[test]
+    fn test_tracker() {
+        let tracker = MemoryTracker::new(MemoryUsage::split(800));
+        tracker.recharge(MemoryKind::Memtables, 0, 300);
+        tracker.check(MemoryKind::Memtables, 100).unwrap();
+        assert!(matches!(tracker.check(MemoryKind::Memtables, 101),
+                         Err(Error::MemoryLimit { used: 300, requested: 101, limit: 400 })));
+        tracker.check(MemoryKind::TableReaders, 100).unwrap();
+        tracker.recharge(MemoryKind::Memtables, 300, 100);
+        tracker.check(MemoryKind::Memtables, 300).unwrap();
+        assert_eq!(tracker.usage().memtables, 100);
+    }
+
+    #[test]
+    fn test_pin_and_charge() {
+        let tracker = Arc::new(MemoryTracker::new(MemoryUsage::split(800)));
+        let pin = tracker.pin(MemoryKind::PinnedBlocks, 60).unwrap();
+        assert!(matches!(tracker.pin(MemoryKind::PinnedBlocks, 41),
+                         Err(Error::MemoryLimit { used: 60, requested: 41, limit: 100 })));
+        assert_eq!(tracker.usage().pinned_blocks, 60);
+        drop(pin);
+        let _pin = tracker.pin(MemoryKind::PinnedBlocks, 100).unwrap();
+
+        let mut charge = MemoryCharge::new(tracker.clone());
+        charge.recharge(MemoryUsage { memtables: 300, table_readers: 50, ..MemoryUsage::default() });
+        assert_eq!((tracker.usage().memtables, tracker.usage().table_readers), (300, 50));
+        drop(charge);
+        assert_eq!((tracker.usage().memtables, tracker.usage().table_readers), (0, 0));
+    }
+}
@@ -0,0 +1,81 @@
+use rand::{thread_rng, Rng};
+
+/// A key that reads and writes of a partition hit often. `count` is the estimated number of
+/// operations on it, over-estimated by up to `error`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HotKey {
+    pub partition_id: u32,
+    pub key: Vec<u8>,
+    pub count: u64,
+    pub error: u64
+}
+
+/// The `capacity` most frequent of the sampled keys, by the space-saving algorithm: a key
+/// not tracked yet takes the place of the least frequent one and inherits its count.
+pub(crate) struct HotKeySketch {
+    capacity: usize,
+    sample_rate: u32,
+    // (key, count, error), unordered
+    entries: Vec<(Vec<u8>, u64, u64)>
+}
+
+impl HotKeySketch {
+    pub(crate) fn new(capacity: usize, sample_rate: u32) -> Self {
+        Self { capacity, sample_rate, entries: Vec::new() }
+    }
+
+    /// Counts one in `sample_rate` of the calls.
+    pub(crate) fn sample(&mut self, key: &[u8]) {
+        if self.capacity == 0 || self.sample_rate == 0 {
+            return;
+        }
+        if self.sample_rate > 1 && thread_rng().gen_range(0, self.sample_rate) != 0 {
+            return;
+        }
+        if let Some(entry) = self.entries.iter_mut().find(|(k, _, _)| k.as_slice() == key) {
+            entry.1 += 1;
+        } else if self.entries.len() < self.capacity {
+            self.entries.push((key.to_vec(), 1, 0));
+        } else {
+            let min = self.entries.iter_mut().min_by_key(|(_, count, _)| *count).unwrap();
+            *min = (key.to_vec(), min.1 + 1, min.1);
+        }
+    }
+
+    /// Most frequent first, with the counts scaled back up by the sample rate.
+    pub(crate) fn hot_keys(&self, partition_id: u32) -> Vec<HotKey> {
+        let scale = self.sample_rate as u64;
+        let mut ret: Vec<HotKey> = self.entries.iter()
+            .map(|(key, count, error)| HotKey {
+                partition_id,
+                key: key.clone(),
+                count: count * scale,
+                error: error * scale
+            })
+            .collect();
+        ret.sort_by_key(|hot_key| std::cmp::Reverse(hot_key.count));
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::partition::hot_keys::HotKeySketch;
+
+    #[test]
+    fn test_hot_keys() {
+        let mut sketch = HotKeySketch::new(4, 1);
+        for i in 0..1000u32 {
+            sketch.sample(format!("cold{}", i).as_bytes());
+            if i % 2 == 0 {
+                sketch.sample(b"hot");
+            }
+        }
+        let hot_keys = sketch.hot_keys(7);
+        assert_eq!(hot_keys.len(), 4);
+        assert_eq!(hot_keys[0].key, b"hot");
+        assert_eq!(hot_keys[0].partition_id, 7);
+        assert!(hot_keys[0].count - hot_keys[0].error <= 500);
+        assert!(hot_keys[0].count >= 500);
+    }
+}
@@ -1,31 +1,254 @@
-use crate::table::Table;
-use crate::Comparator;
-use crate::table::sctable::ScTable;
+use std::cmp::Ordering;
+use std::sync::Arc;
 
+use crate::table::{Table, TableGet};
+use crate::{Comparator, Options};
+use crate::table::sctable::{ScTable, ScTableFile};
+use crate::table::cache::TableCacheManager;
+use crate::io::IOManager;
+use crate::memory::MemoryTracker;
+use crate::partition::{CorruptTable, FileChecksum, LevelSpaceUsage, LookupKey, ScanCost, UserKey};
+use crate::error::Error;
+use crate::statistics::SizeHistogram;
+
+/// The tables a compaction reads, see `Level::pick_compaction_inputs`.
+pub(crate) struct CompactionInputs<Comp: Comparator> {
+    pub(crate) input_files: Vec<ScTableFile>,
+    /// Tables of the output level the inputs overlap
+    pub(crate) output_files: Vec<ScTableFile>,
+    /// Entries from this key on stay in the input level, rewritten into new tables, since the
+    /// tables of the output level they overlap did not fit into the bound
+    pub(crate) split_at: Option<UserKey<Comp>>
+}
+
+/// Tables of level 0 are kept in flush order and may overlap each other, tables of the other
+/// levels are kept sorted by key range and never overlap.
 pub struct Level<Comp: 'static + Comparator> {
+    level: usize,
     tables: Vec<Box<dyn Table<Comp>>>,
-    file_id: u64
+    file_id: u64,
+    // round-robin position of the next table to compact out of this level
+    compact_pointer: usize
 }
 
 impl<Comp: 'static + Comparator> Level<Comp> {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(level: usize) -> Self {
         Self {
+            level,
             tables: Vec::new(),
-            file_id: 1
+            file_id: 1,
+            compact_pointer: 0
         }
     }
 
     pub(crate) fn add_file(&mut self, table_file: ScTable<Comp>) {
-        self.tables.push(Box::new(table_file));
+        if self.level == 0 {
+            self.tables.push(Box::new(table_file));
+        } else {
+            let idx = self.tables.partition_point(|table| table.lower_bound() < table_file.lower_bound());
+            self.tables.insert(idx, Box::new(table_file));
+        }
+    }
+
+    pub(crate) fn remove_files(&mut self, table_files: &[ScTableFile]) {
+        self.tables.retain(|table| !table_files.contains(&table.table_file()));
     }
 
     pub(crate) fn table_count(&self) -> usize {
         self.tables.len()
     }
 
+    pub(crate) fn reader_memory(&self) -> usize {
+        self.tables.iter().map(|table| table.reader_memory()).sum()
+    }
+
+    pub(crate) fn space_usage(&self) -> LevelSpaceUsage {
+        LevelSpaceUsage {
+            tables: self.tables.len(),
+            entries: self.tables.iter().map(|table| table.entry_count()).sum(),
+            file_bytes: self.tables.iter().map(|table| table.file_size() as u64).sum(),
+            data_bytes: self.tables.iter().map(|table| table.data_size()).sum(),
+            pending_compaction_bytes: 0
+        }
+    }
+
+    pub(crate) fn file_checksums(&self, options: &Options) -> Vec<FileChecksum> {
+        self.tables.iter().filter_map(|table| table.file_checksum().map(|crc32| FileChecksum {
+            file_path: table.table_file().file_path(options),
+            file_size: table.file_size() as u64,
+            crc32
+        })).collect()
+    }
+
+    pub(crate) fn scan_cost(&self, lower: &[u8], upper: &[u8]) -> ScanCost {
+        let mut ret = ScanCost::default();
+        for table in self.tables.iter() {
+            ret.add(table.scan_cost(lower, upper));
+        }
+        ret
+    }
+
+    pub(crate) fn entry_sizes(&self) -> impl Iterator<Item = (SizeHistogram, SizeHistogram)> + '_ {
+        self.tables.iter().map(|table| table.entry_sizes())
+    }
+
+    /// Key range of every table, with the size of its keys and values.
+    pub(crate) fn data_ranges(&self) -> impl Iterator<Item = (&UserKey<Comp>, &UserKey<Comp>, u64)> {
+        self.tables.iter().map(|table| (table.lower_bound(), table.upper_bound(), table.data_size()))
+    }
+
     pub(crate) fn level_next_file_id(&mut self) -> u64 {
         let ret = self.file_id;
         self.file_id += 1;
         ret
     }
+
+    /// Tables found corrupt on the way are added to `corrupt_tables`.
+    pub(crate) fn get(&self,
+                      key: &LookupKey<Comp>,
+                      cache_manager: &TableCacheManager,
+                      io_manager: &IOManager,
+                      options: &Options,
+                      memory_tracker: Option<&Arc<MemoryTracker>>,
+                      corrupt_tables: &mut Vec<CorruptTable<Comp>>) -> Result<TableGet, Error> {
+        if self.level == 0 {
+            // newer tables hold newer versions, stop at the first one that has the key, or
+            // had it deleted
+            for table in self.tables.iter().rev() {
+                if table.cmp_key(key.user_key) != Ordering::Equal
+                   || table.min_seq() > key.seq
+                   || !table.may_contain(key.user_key) {
+                    continue
+                }
+                match Self::table_get(table.as_ref(), key, cache_manager, io_manager, options, memory_tracker,
+                                      corrupt_tables)? {
+                    TableGet::Absent => {},
+                    found_or_deleted => return Ok(found_or_deleted)
+                }
+            }
+            Ok(TableGet::Absent)
+        } else {
+            match self.tables.binary_search_by(|table| table.cmp_key(key.user_key).reverse()) {
+                Ok(idx) if self.tables[idx].min_seq() <= key.seq && self.tables[idx].may_contain(key.user_key) =>
+                    Self::table_get(self.tables[idx].as_ref(), key, cache_manager, io_manager,
+                                    options, memory_tracker, corrupt_tables),
+                _ => Ok(TableGet::Absent)
+            }
+        }
+    }
+
+    /// Reads a corrupt block again up to `Options::corrupt_block_retries` times, then fails or,
+    /// with `Options::skip_corrupt_tables`, reads on as if the table did not have the key.
+    fn table_get(table: &dyn Table<Comp>,
+                 key: &LookupKey<Comp>,
+                 cache_manager: &TableCacheManager,
+                 io_manager: &IOManager,
+                 options: &Options,
+                 memory_tracker: Option<&Arc<MemoryTracker>>,
+                 corrupt_tables: &mut Vec<CorruptTable<Comp>>) -> Result<TableGet, Error> {
+        let mut retries = options.corrupt_block_retries;
+        loop {
+            match table.get(key, cache_manager, io_manager, memory_tracker) {
+                Err(Error::ScTableCorrupt { .. }) if retries > 0 => retries -= 1,
+                Err(e @ Error::ScTableCorrupt { .. }) => {
+                    corrupt_tables.push(CorruptTable {
+                        table_file: table.table_file(),
+                        lower_bound: table.lower_bound().clone(),
+                        upper_bound: table.upper_bound().clone()
+                    });
+                    return if options.skip_corrupt_tables { Ok(TableGet::Absent) } else { Err(e) }
+                },
+                result => return result
+            }
+        }
+    }
+
+    /// Picks the tables to compact into the next level: all of level 0, since its tables
+    /// overlap, or one table chosen round-robin otherwise, with the tables of `output` they
+    /// overlap.
+    pub(crate) fn pick_compaction_inputs(&mut self,
+                                         output: Option<&Level<Comp>>,
+                                         max_bytes: u64) -> CompactionInputs<Comp> {
+        let ret = self.peek_compaction_inputs(output, max_bytes);
+        if self.level != 0 {
+            self.compact_pointer = self.compact_pointer % self.tables.len() + 1;
+        }
+        ret
+    }
+
+    /// What `pick_compaction_inputs` would pick, without moving on to the next table.
+    /// With `max_bytes` set, level 0 gives only its oldest tables, as many as fit together
+    /// with the tables of `output` they overlap. Newer ones staying behind still shadow them.
+    /// Other levels give their one table with only the leading tables of `output` that fit,
+    /// at least one, and split the job where the first one left out begins.
+    pub(crate) fn peek_compaction_inputs(&self,
+                                         output: Option<&Level<Comp>>,
+                                         max_bytes: u64) -> CompactionInputs<Comp> {
+        let picked: Vec<&dyn Table<Comp>> = if self.level == 0 {
+            let mut count = self.tables.len();
+            if max_bytes > 0 {
+                let mut input_bytes = 0;
+                let mut bounds: Option<(&UserKey<Comp>, &UserKey<Comp>)> = None;
+                for (idx, table) in self.tables.iter().enumerate() {
+                    let (lower, upper) = match bounds {
+                        Some((lower, upper)) => (lower.min(table.lower_bound()), upper.max(table.upper_bound())),
+                        None => (table.lower_bound(), table.upper_bound())
+                    };
+                    input_bytes += table.file_size() as u64;
+                    let output_bytes = output.map_or(0, |output| {
+                        output.files_size(&output.overlapping_files(lower, upper))
+                    });
+                    if idx > 0 && input_bytes + output_bytes > max_bytes {
+                        count = idx;
+                        break;
+                    }
+                    bounds = Some((lower, upper));
+                }
+            }
+            self.tables[..count].iter().map(|table| table.as_ref()).collect()
+        } else {
+            vec![self.tables[self.compact_pointer % self.tables.len()].as_ref()]
+        };
+        let lower = picked.iter().map(|table| table.lower_bound()).min().unwrap();
+        let upper = picked.iter().map(|table| table.upper_bound()).max().unwrap();
+        let input_files = picked.iter().map(|table| table.table_file()).collect();
+        let mut overlapping: Vec<&dyn Table<Comp>> = output.map_or_else(Vec::new, |output| {
+            output.tables.iter()
+                .filter(|table| table.lower_bound() <= upper && table.upper_bound() >= lower)
+                .map(|table| table.as_ref())
+                .collect()
+        });
+        let mut split_at = None;
+        if self.level != 0 && max_bytes > 0 {
+            let mut bytes = picked[0].file_size() as u64;
+            for (idx, table) in overlapping.iter().enumerate() {
+                bytes += table.file_size() as u64;
+                if idx > 0 && bytes > max_bytes {
+                    split_at = Some(table.lower_bound().clone());
+                    overlapping.truncate(idx);
+                    break;
+                }
+            }
+        }
+        CompactionInputs {
+            input_files,
+            output_files: overlapping.iter().map(|table| table.table_file()).collect(),
+            split_at
+        }
+    }
+
+    /// Total size of those of `table_files` in this level.
+    pub(crate) fn files_size(&self, table_files: &[ScTableFile]) -> u64 {
+        self.tables.iter()
+            .filter(|table| table_files.contains(&table.table_file()))
+            .map(|table| table.file_size() as u64)
+            .sum()
+    }
+
+    pub(crate) fn overlapping_files(&self, lower: &UserKey<Comp>, upper: &UserKey<Comp>) -> Vec<ScTableFile> {
+        self.tables.iter()
+            .filter(|table| table.lower_bound() <= upper && table.upper_bound() >= lower)
+            .map(|table| table.table_file())
+            .collect()
+    }
 }
@@ -1,57 +1,183 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::ops::Bound;
+
+use crc::crc32;
+use lru::LruCache;
 use std::sync::{Mutex, atomic::AtomicU64, Condvar, Arc};
 use std::marker::PhantomData;
-use std::cmp::Ordering;
-use std::ptr::NonNull;
+use std::cmp::{Ordering, Reverse};
+use std::borrow::Borrow;
 
 use crate::{Comparator, Options, DefaultComparator};
-use crate::table::tablefmt::{TABLE_CATALOG_ITEM_SIZE, TABLE_MIN_SIZE};
+use crate::table::tablefmt::{TABLE_CATALOG_ITEM_SIZE, TABLE_FOOTER_SIZE, TABLE_MIN_SIZE};
 use crate::table::builder::ScTableBuilder;
-use crate::table::cache::TableCacheManager;
+use crate::table::index::{ScTableFooter, ScTableIndex};
+use crate::table::filter::{key_hash, MemTableFilter, TableFilter};
+use crate::table::cache::{CacheQuota, ScTableCache, TableCacheManager};
 use crate::io::IOManager;
 use crate::error::Error;
+use crate::memory::{MemoryCharge, MemoryKind, MemoryTracker, MemoryUsage};
 use crate::partition::level::Level;
-use crate::table::sctable::{ScTable, ScTableFile};
+use crate::table::sctable::{ScTable, ScTableFile, TableEntries};
+use crate::table::{Table, TableGet};
+use crate::statistics::{Statistics, HistogramType, SizeHistogram, Ticker};
+use crate::partition::scheduler::{BackgroundScheduler, BackgroundJob, BackgroundJobKind};
+use crate::partition::remote::{CompactionJob, CompactionService};
+use crate::partition::sample::KeySample;
+use crate::partition::hot_keys::{HotKey, HotKeySketch};
+use crate::partition::throttle::TokenBucket;
+use crate::partition::stream::ValueStream;
 use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use std::io::Read;
 
+pub(crate) mod hot_keys;
 mod level;
+pub(crate) mod remote;
+mod sample;
+pub(crate) mod scheduler;
+pub(crate) mod stream;
+mod throttle;
+
+// how long `Options::level0_slowdown_writes` delays a write
+const LEVEL0_SLOWDOWN_MICROS: u64 = 1000;
 
-pub(crate) enum UserKey<Comp: Comparator> {
-    Owned(Vec<u8>, PhantomData<Comp>),
-    Borrow(NonNull<[u8]>)
+/// What reads do while compaction is behind. The debt is the number of tables beyond the
+/// size targets of their levels.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReadShedding {
+    Disabled,
+    /// Fail reads with `Error::Busy` while the debt is above `max_debt`.
+    Reject { max_debt: usize },
+    /// Skip the tables of level 0 while the debt is above `max_debt`. Such reads may return
+    /// an older version of a key, or miss it.
+    SkipLevel0 { max_debt: usize }
 }
 
-impl<Comp: Comparator> Clone for UserKey<Comp> {
-    fn clone(&self) -> Self {
-        match self {
-            UserKey::Owned(data, _) => UserKey::Owned(data.clone(), PhantomData),
-            UserKey::Borrow(ptr) => UserKey::Borrow(ptr.clone())
+/// Space taken by the tables of a level.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LevelSpaceUsage {
+    pub tables: usize,
+    pub entries: usize,
+    /// Size of the table files
+    pub file_bytes: u64,
+    /// Size of the keys and values in them
+    pub data_bytes: u64,
+    /// Estimated size of the tables beyond the size target of the level
+    pub pending_compaction_bytes: u64
+}
+
+/// Space taken by the tables of a partition, by level.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpaceUsage {
+    pub partition_id: u32,
+    pub levels: Vec<LevelSpaceUsage>
+}
+
+impl SpaceUsage {
+    pub fn file_bytes(&self) -> u64 {
+        self.levels.iter().map(|level| level.file_bytes).sum()
+    }
+
+    /// Estimated size of the live data: the keys and values of the largest level. The
+    /// other levels mostly hold newer versions of keys the largest level has too.
+    pub fn live_bytes(&self) -> u64 {
+        self.levels.iter().map(|level| level.data_bytes).max().unwrap_or(0)
+    }
+
+    /// Size on disk over the size of the live data, 0 while there are no tables.
+    pub fn space_amplification(&self) -> f64 {
+        match self.live_bytes() {
+            0 => 0.0,
+            live_bytes => self.file_bytes() as f64 / live_bytes as f64
         }
     }
 }
 
-impl<Comp: Comparator> UserKey<Comp> {
-    pub(crate) fn new_owned(vec: Vec<u8>) -> Self {
-        UserKey::Owned(vec, PhantomData)
+/// Size and CRC32 of a table file as written, for checking copies of it, see
+/// `ScottDB::file_checksums`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileChecksum {
+    pub file_path: String,
+    pub file_size: u64,
+    pub crc32: u32
+}
+
+/// A compaction the picker would start, see `ScottDB::plan_compactions`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlannedCompaction {
+    pub partition_id: u32,
+    pub input_level: usize,
+    pub output_level: usize,
+    /// Table files picked out of the input level
+    pub input_files: Vec<String>,
+    /// Table files of the output level overlapping them, which get rewritten too
+    pub overlapping_files: Vec<String>,
+    /// Entries from this key on stay in the input level, when not all of the overlapping
+    /// files fit into `Options::max_compaction_bytes`
+    pub split_key: Option<Vec<u8>>,
+    /// Size of all of those files. The compaction reads them all, and writes at most as
+    /// much back out
+    pub estimated_bytes: u64
+}
+
+/// What reading the keys of a range would take, see `ScottDB::estimate_scan_cost`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanCost {
+    /// Blocks of the tables overlapping the range, which a scan reads unless cached
+    pub blocks: u64,
+    pub bytes: u64,
+    /// Estimated entries, all versions of a key included: those of the memtables exactly,
+    /// those of the tables in proportion to the blocks read
+    pub entries: u64
+}
+
+impl ScanCost {
+    pub fn add(&mut self, other: ScanCost) {
+        self.blocks += other.blocks;
+        self.bytes += other.bytes;
+        self.entries += other.entries;
     }
+}
+
+/// Sizes of the keys and values in the tables of a partition, see `ScottDB::entry_sizes`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EntrySizes {
+    pub partition_id: u32,
+    pub keys: SizeHistogram,
+    pub values: SizeHistogram
+}
+
+/// The state of a partition, see `ScottDB::partitions`.
+#[derive(Clone, Debug)]
+pub struct PartitionInfo {
+    pub partition_id: u32,
+    /// Smallest and largest keys written to the partition, `None` before the first write
+    pub lower_bound: Option<Vec<u8>>,
+    pub upper_bound: Option<Vec<u8>>,
+    pub memtable_bytes: usize,
+    pub has_imm: bool,
+    /// Number of tables, by level
+    pub level_tables: Vec<usize>,
+    /// The error background work failed with, which fails the partition's writes since
+    pub background_error: Option<Error>
+}
+
+pub(crate) struct UserKey<Comp: Comparator>(Vec<u8>, PhantomData<Comp>);
 
-    pub(crate) fn new_borrow(slice: &[u8]) -> Self {
-        UserKey::Borrow(unsafe { NonNull::new_unchecked(slice as *const [u8] as _) })
+impl<Comp: Comparator> Clone for UserKey<Comp> {
+    fn clone(&self) -> Self {
+        UserKey(self.0.clone(), PhantomData)
     }
+}
 
-    fn key(&self) -> &[u8] {
-        match self {
-            UserKey::Owned(k, _) => k.as_slice(),
-            UserKey::Borrow(b) => unsafe { b.as_ref() }
-        }
+impl<Comp: Comparator> UserKey<Comp> {
+    pub(crate) fn new_owned(vec: Vec<u8>) -> Self {
+        UserKey(vec, PhantomData)
     }
 
-    fn is_owned(&self) -> bool {
-        if let UserKey::Owned(_, _) = self {
-            true
-        } else {
-            false
-        }
+    pub(crate) fn key(&self) -> &[u8] {
+        self.0.as_slice()
     }
 }
 
@@ -88,17 +214,86 @@ impl<Comp: Comparator> InternalKey<Comp> {
     }
 }
 
-impl<Comp: Comparator> Ord for InternalKey<Comp> {
+/// An internal key borrowing its user key, which is what reads look up.
+pub(crate) struct LookupKey<'a, Comp: Comparator> {
+    seq: u64,
+    pub(crate) user_key: &'a [u8],
+    comp: PhantomData<Comp>
+}
+
+impl<'a, Comp: Comparator> LookupKey<'a, Comp> {
+    pub(crate) fn new(seq: u64, user_key: &'a [u8]) -> Self {
+        Self { seq, user_key, comp: PhantomData }
+    }
+}
+
+/// What internal keys are ordered by. `InternalKey` and `LookupKey` both order as a `dyn KeyRef`,
+/// which lets the memtable be searched with a `LookupKey` without copying its user key.
+pub(crate) trait KeyRef<Comp: Comparator> {
+    fn seq(&self) -> u64;
+
+    fn user_key(&self) -> &[u8];
+}
+
+impl<Comp: Comparator> KeyRef<Comp> for InternalKey<Comp> {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn user_key(&self) -> &[u8] {
+        self.user_key.key()
+    }
+}
+
+impl<'a, Comp: Comparator> KeyRef<Comp> for LookupKey<'a, Comp> {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn user_key(&self) -> &[u8] {
+        self.user_key
+    }
+}
+
+impl<'a, Comp: Comparator + 'a> Borrow<dyn KeyRef<Comp> + 'a> for InternalKey<Comp> {
+    fn borrow(&self) -> &(dyn KeyRef<Comp> + 'a) {
+        self
+    }
+}
+
+/// Ordered by user key, then newest version first, so that seeking to `(key, seq)` lands on the
+/// newest version of `key` visible at `seq`.
+impl<'a, Comp: Comparator> Ord for dyn KeyRef<Comp> + 'a {
     fn cmp(&self, other: &Self) -> Ordering {
-        let ord =  self.seq.cmp(&other.seq);
+        let ord = Comp::compare(self.user_key(), other.user_key());
         if ord == Ordering::Equal {
-            self.user_key.cmp(&other.user_key)
+            other.seq().cmp(&self.seq())
         } else {
             ord
         }
     }
 }
 
+impl<'a, Comp: Comparator> PartialOrd for dyn KeyRef<Comp> + 'a {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a, Comp: Comparator> PartialEq for dyn KeyRef<Comp> + 'a {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'a, Comp: Comparator> Eq for dyn KeyRef<Comp> + 'a {}
+
+impl<Comp: Comparator> Ord for InternalKey<Comp> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self as &dyn KeyRef<Comp>).cmp(other)
+    }
+}
+
 impl<Comp: Comparator> PartialOrd for InternalKey<Comp> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -113,8 +308,36 @@ impl<Comp: Comparator> PartialEq for InternalKey<Comp> {
 
 impl<Comp: Comparator> Eq for InternalKey<Comp> {}
 
+impl<'a, Comp: Comparator> Ord for LookupKey<'a, Comp> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self as &dyn KeyRef<Comp>).cmp(other)
+    }
+}
+
+impl<'a, Comp: Comparator> PartialOrd for LookupKey<'a, Comp> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a, Comp: Comparator> PartialEq for LookupKey<'a, Comp> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'a, Comp: Comparator> Eq for LookupKey<'a, Comp> {}
+
 type MemTable<Comp> = BTreeMap<InternalKey<Comp>, Vec<u8>>;
 
+/// A table a read found a corrupt block in. Its key range is degraded: the table stays out of
+/// reads and compactions once quarantined, so keys there may miss or read older versions.
+pub(crate) struct CorruptTable<Comp: Comparator> {
+    pub(crate) table_file: ScTableFile,
+    pub(crate) lower_bound: UserKey<Comp>,
+    pub(crate) upper_bound: UserKey<Comp>
+}
+
 pub(crate) struct Partition<'a, Comp: 'static + Comparator> {
     data: Mutex<PartitionData<'a, Comp>>,
     condvar: Condvar,
@@ -122,10 +345,18 @@ pub(crate) struct Partition<'a, Comp: 'static + Comparator> {
     under_explode: AtomicBool,
 
     partition_id: u32,
+    // `Options::partition_reads_per_sec` and `partition_writes_per_sec`
+    read_bucket: Option<TokenBucket>,
+    write_bucket: Option<TokenBucket>,
+    // bytes written by the running flush and compaction
+    flush_progress: AtomicU64,
+    compaction_progress: AtomicU64,
 
     seq: &'a AtomicU64,
     cache_manager: &'a TableCacheManager,
     io_manager: &'a IOManager,
+    statistics: &'a Statistics,
+    scheduler: &'a BackgroundScheduler<'a, Comp>,
     options: &'a Options
 }
 
@@ -134,19 +365,53 @@ impl<'a, Comp: 'static + Comparator> Partition<'a, Comp> {
            partition_id: u32,
            seq: &'a AtomicU64,
            cache_manager: &'a TableCacheManager,
-           io_manager: &'a IOManager) -> Self {
+           io_manager: &'a IOManager,
+           statistics: &'a Statistics,
+           scheduler: &'a BackgroundScheduler<'a, Comp>) -> Self {
         Self {
             data: Mutex::new(PartitionData::new(options)),
             condvar: Condvar::new(),
             under_explode: AtomicBool::new(false),
             partition_id,
+            read_bucket: Self::new_bucket(options.partition_reads_per_sec, options),
+            write_bucket: Self::new_bucket(options.partition_writes_per_sec, options),
+            flush_progress: AtomicU64::new(0),
+            compaction_progress: AtomicU64::new(0),
             seq,
             cache_manager,
             io_manager,
+            statistics,
+            scheduler,
             options
         }
     }
 
+    /// Charges the memtables, table readers and pinned blocks of the partition to
+    /// `memory_tracker`, and fails writes and reads that would go past its shares. The charge
+    /// goes away with the partition.
+    pub(crate) fn with_memory_tracker(mut self, memory_tracker: Arc<MemoryTracker>) -> Self {
+        self.data.get_mut().unwrap().memory_charge = Some(MemoryCharge::new(memory_tracker));
+        self
+    }
+
+    fn new_bucket(rate: u64, options: &Options) -> Option<TokenBucket> {
+        if rate == 0 {
+            None
+        } else {
+            Some(TokenBucket::new(rate, options.clock.as_ref()))
+        }
+    }
+
+    /// Waits for a token of `bucket`; done before taking the partition lock, so that a
+    /// throttled partition does not hold up its background jobs.
+    fn throttle(&self, bucket: &Option<TokenBucket>, ticker: Ticker) {
+        if let Some(bucket) = bucket {
+            if bucket.acquire(self.options.clock.as_ref()) {
+                self.statistics.record_tick(ticker);
+            }
+        }
+    }
+
     fn debug_never_eq_sanity_check(&self, other: &Self) -> bool {
         if self as *const Self == other as *const Self {
             return false;
@@ -204,36 +469,210 @@ fn kv_pair_size<Comp>(key: &InternalKey<Comp>, value: &[u8]) -> usize
     key.user_key.key().len() + value.len() + TABLE_CATALOG_ITEM_SIZE
 }
 
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq)]
+fn memtable_entry_size<Comp>() -> usize
+    where Comp: Comparator {
+    std::mem::size_of::<InternalKey<Comp>>() + std::mem::size_of::<Vec<u8>>()
+}
+
+/// What putting the pair into the memtable adds to its `memtable_memory` at most.
+fn kv_pair_memory<Comp>(key: &InternalKey<Comp>, value: &[u8]) -> usize
+    where Comp: Comparator {
+    key.user_key.key().len() + value.len() + (memtable_entry_size::<Comp>() * 3 + 1) / 2
+}
+
+#[derive(Ord, PartialOrd, Eq, PartialEq)]
 pub(crate) struct ArcPartition<'a, Comp: 'static + Comparator>(Arc<Partition<'a, Comp>>);
 
+impl<'a, Comp: 'static + Comparator> Clone for ArcPartition<'a, Comp> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
 impl<'a, Comp: 'static + Comparator> ArcPartition<'a, Comp> {
     pub(crate) fn new(partition: Partition<'a, Comp>) -> Self {
         Self(Arc::new(partition))
     }
 
     pub(crate) fn write(&self, key: InternalKey<Comp>, value: Vec<u8>) -> Result<(), Error> {
+        let start = self.0.options.clock.now_micros();
         let partition = &self.0;
+        if key.user_key.key().len() > partition.options.key_size_max {
+            return Err(Error::key_too_large(key.user_key.key().len(), partition.options.key_size_max))
+        }
+        if value.len() > partition.options.value_size_max {
+            return Err(Error::value_too_large(value.len(), partition.options.value_size_max))
+        }
+        partition.throttle(&partition.write_bucket, Ticker::WriteThrottled);
         let mut data = partition.data.lock().unwrap();
         data.background_error()?;
+        let mut flush = false;
+        let mut slowed_down = false;
+        let mut stopped = false;
         loop {
             if false /* TODO add proper condition here */ {
                 return Err(Error::requires_explode())
             }
+            let level0_tables = data.levels.first().map_or(0, |level| level.table_count());
+            if partition.options.level0_stop_writes > 0 && level0_tables >= partition.options.level0_stop_writes {
+                if !stopped {
+                    stopped = true;
+                    partition.statistics.record_tick(Ticker::WriteStop);
+                    // an inline compaction runs right here, and needs the partition unlocked
+                    drop(data);
+                    partition.scheduler.schedule(BackgroundJob::Compaction(self.clone(), 0));
+                    data = partition.data.lock().unwrap();
+                    data.background_error()?;
+                    continue;
+                }
+                // compactions wake everyone up once installed
+                data = partition.condvar.wait(data).unwrap();
+                data.background_error()?;
+                continue;
+            }
+            if partition.options.level0_slowdown_writes > 0 && level0_tables >= partition.options.level0_slowdown_writes
+               && !slowed_down {
+                slowed_down = true;
+                partition.statistics.record_tick(Ticker::WriteSlowdown);
+                drop(data);
+                partition.options.clock.sleep(Duration::from_micros(LEVEL0_SLOWDOWN_MICROS));
+                data = partition.data.lock().unwrap();
+                data.background_error()?;
+                continue;
+            }
             if data.memtable_size() + kv_pair_size(&key, &value) <= partition.options.table_size {
                 break;
             } else if data.has_imm() {
                 data = partition.condvar.wait(data).unwrap();
             } else {
+                // the memtable is full, keep the disk from filling up with it
+                self.check_disk_space(0)?;
+                // nor the table readers from growing past their share
+                data.check_memory(MemoryKind::TableReaders, 0)?;
                 data.convert_mem_to_imm();
-                // TODO run self.do_compaction() at background
+                flush = true;
                 break;
             }
         }
-        data.memtable_put(key, value);
+        let ret = data.check_memory(MemoryKind::Memtables, kv_pair_memory(&key, &value));
+        if ret.is_ok() {
+            partition.statistics.record_ticks(Ticker::UserBytesWritten, (key.user_key.key().len() + value.len()) as u64);
+            data.hot_keys.sample(key.user_key.key());
+            data.memtable_put(key, value);
+            data.recharge_memory();
+        }
+        drop(data);
+        // the memtable was frozen even if the write did not fit, it still needs its flush
+        if flush {
+            partition.scheduler.schedule(BackgroundJob::Flush(self.clone()));
+        }
+        ret?;
+        partition.statistics.record_since(HistogramType::Put, partition.options.clock.as_ref(), start);
         Ok(())
     }
 
+    /// Turns the memtable into the immutable one and schedules its flush, first waiting for
+    /// the flush of the previous one. Returns the largest sequence number written so far.
+    pub(crate) fn freeze_memtable(&self) -> Result<u64, Error> {
+        let partition = &self.0;
+        let mut data = partition.data.lock().unwrap();
+        data.background_error()?;
+        if data.mem_table.is_empty() {
+            return Ok(data.max_seq)
+        }
+        while data.has_imm() {
+            data = partition.condvar.wait(data).unwrap();
+            data.background_error()?;
+        }
+        data.convert_mem_to_imm();
+        let max_seq = data.max_seq;
+        drop(data);
+        partition.scheduler.schedule(BackgroundJob::Flush(self.clone()));
+        Ok(max_seq)
+    }
+
+    /// Moves the table file at `file_path`, built elsewhere, into level 0 as its newest table
+    /// and returns the sequence number all its entries are read with, one past the newest of
+    /// the partition. Only the footer is rewritten, see `ScTableFooter::stamp_global_seq`, and
+    /// only the footer, filter, index and first block are read. The memtables are flushed
+    /// first, since they are read before any table and an ingested table has to be newer than
+    /// every flushed one.
+    pub(crate) fn ingest(&self, file_path: &str) -> Result<u64, Error> {
+        let partition = &self.0;
+        let io_manager = partition.io_manager;
+        let file_size = io_manager.acquire_quota().file_size(file_path.to_string())? as usize;
+        if file_size < TABLE_FOOTER_SIZE {
+            return Err(Error::sc_table_corrupt("too small to be a table file".into()))
+        }
+        let raw_footer = io_manager.acquire_quota().read_file_range(
+            file_path.to_string(), (file_size - TABLE_FOOTER_SIZE) as u64, TABLE_FOOTER_SIZE)?;
+        let mut footer = ScTableFooter::decode_tail(&raw_footer, file_size)?;
+        footer.check_ingestible()?;
+        let raw_meta = io_manager.acquire_quota().read_file_range(
+            file_path.to_string(), footer.filter_off() as u64, footer.filter_size + footer.index_size)?;
+        let filter = TableFilter::from_footer(&raw_meta[..footer.filter_size], &footer)?;
+        let index = ScTableIndex::from_footer(&raw_meta[footer.filter_size..], &footer)?;
+        let (lower, upper) = table_bounds(&index, file_path, io_manager)?;
+
+        let mut data = partition.data.lock().unwrap();
+        loop {
+            data.background_error()?;
+            if data.has_imm() {
+                data = partition.condvar.wait(data).unwrap();
+            } else if !data.mem_table.is_empty() {
+                data.convert_mem_to_imm();
+                drop(data);
+                partition.scheduler.schedule(BackgroundJob::Flush(self.clone()));
+                data = partition.data.lock().unwrap();
+            } else {
+                break;
+            }
+        }
+        // the lock is held through the stamp and the move, so that no write lands in the
+        // memtable before the table is in place
+        if data.levels.is_empty() {
+            data.levels.push(Level::new(0));
+        }
+        let global_seq = data.max_seq + 1;
+        let (stamp_off, stamp) = footer.stamp_global_seq(global_seq)?;
+        let table_file = ScTableFile::new(partition.partition_id, 0, data.levels[0].level_next_file_id());
+        let table_path = table_file.file_path(partition.options);
+        io_manager.acquire_quota().write_file_at(file_path.to_string(), stamp_off as u64, &stamp)?;
+        io_manager.acquire_quota().move_file(file_path.to_string(), table_path.clone())?;
+        // the checksum would take reading the whole file, the table goes without one until
+        // a compaction rewrites it
+        data.levels[0].add_file(ScTable::new(table_file, table_path, index, filter, &footer, lower, upper));
+        data.max_seq = global_seq;
+        data.row_cache_clear();
+        data.recharge_memory();
+        drop(data);
+        partition.scheduler.schedule(BackgroundJob::Compaction(self.clone(), 0));
+        Ok(global_seq)
+    }
+
+    /// Fails if the directory of the tables of `level` has less than
+    /// `Options::reserved_disk_bytes` left.
+    fn check_disk_space(&self, level: usize) -> Result<(), Error> {
+        let partition = &self.0;
+        match self.short_free_space(level) {
+            Some(free_bytes) => {
+                partition.statistics.record_tick(Ticker::WriteNoSpace);
+                Err(Error::no_space(free_bytes))
+            },
+            None => Ok(())
+        }
+    }
+
+    /// Free bytes of the directory of `level`, if fewer than `Options::reserved_disk_bytes`.
+    fn short_free_space(&self, level: usize) -> Option<u64> {
+        let options = self.0.options;
+        if options.reserved_disk_bytes == 0 {
+            return None
+        }
+        let dir_name = options.db_path(level).unwrap_or(".");
+        self.0.io_manager.free_space(dir_name).filter(|&free_bytes| free_bytes < options.reserved_disk_bytes)
+    }
+
     pub(crate) fn explode(&self) -> (ArcPartition<'a, Comp>, ArcPartition<'a, Comp>) {
         let partition = &self.0;
         let data = partition.data.lock().unwrap();
@@ -243,10 +682,267 @@ impl<'a, Comp: 'static + Comparator> ArcPartition<'a, Comp> {
         unimplemented!()
     }
 
+    pub(crate) fn corrupt_table_files(&self) -> Vec<String> {
+        self.0.data.lock().unwrap().corrupt_tables.iter().map(|table| table.table_file.file_path(self.0.options)).collect()
+    }
+
+    pub(crate) fn scan_cost(&self, lower: &[u8], upper: &[u8]) -> ScanCost {
+        let data = self.0.data.lock().unwrap();
+        let mut ret = ScanCost { entries: data.memtable_entries(lower, upper), ..ScanCost::default() };
+        for level in data.levels.iter() {
+            ret.add(level.scan_cost(lower, upper));
+        }
+        ret
+    }
+
+    pub(crate) fn entry_sizes(&self) -> EntrySizes {
+        let partition = &self.0;
+        let data = partition.data.lock().unwrap();
+        let mut ret = EntrySizes { partition_id: partition.partition_id, ..EntrySizes::default() };
+        for (keys, values) in data.levels.iter().flat_map(|level| level.entry_sizes()) {
+            ret.keys.merge(&keys);
+            ret.values.merge(&values);
+        }
+        ret
+    }
+
+    pub(crate) fn space_usage(&self) -> SpaceUsage {
+        let partition = &self.0;
+        let data = partition.data.lock().unwrap();
+        SpaceUsage {
+            partition_id: partition.partition_id,
+            levels: data.levels.iter().enumerate().map(|(n, level)| {
+                let mut space_usage = level.space_usage();
+                let excess_tables = space_usage.tables.saturating_sub(partition.options.level_size(n));
+                if excess_tables > 0 {
+                    space_usage.pending_compaction_bytes =
+                        space_usage.file_bytes * excess_tables as u64 / space_usage.tables as u64;
+                }
+                space_usage
+            }).collect()
+        }
+    }
+
+    pub(crate) fn hot_keys(&self) -> Vec<HotKey> {
+        self.0.data.lock().unwrap().hot_keys.hot_keys(self.0.partition_id)
+    }
+
+    /// The compactions `schedule_compaction` would run next, one per level beyond its size
+    /// target. They run one at a time, and none while a background error is pending.
+    pub(crate) fn plan_compactions(&self) -> Vec<PlannedCompaction> {
+        let partition = &self.0;
+        let data = partition.data.lock().unwrap();
+        if data.background_error().is_err() {
+            return Vec::new()
+        }
+        let file_paths = |files: &[ScTableFile]| -> Vec<String> {
+            files.iter().map(|f| f.file_path(partition.options)).collect()
+        };
+        let mut ret = Vec::new();
+        for (input_level, level) in data.levels.iter().enumerate() {
+            if level.table_count() <= partition.options.level_size(input_level) {
+                continue;
+            }
+            let output_level = data.levels.get(input_level + 1);
+            let inputs = level.peek_compaction_inputs(output_level, partition.options.max_compaction_bytes);
+            let estimated_bytes = level.files_size(&inputs.input_files)
+                + output_level.map_or(0, |output| output.files_size(&inputs.output_files));
+            ret.push(PlannedCompaction {
+                partition_id: partition.partition_id,
+                input_level,
+                output_level: input_level + 1,
+                input_files: file_paths(&inputs.input_files),
+                overlapping_files: file_paths(&inputs.output_files),
+                split_key: inputs.split_at.map(|split_at| split_at.key().to_vec()),
+                estimated_bytes
+            });
+        }
+        ret
+    }
+
+    pub(crate) fn file_checksums(&self) -> Vec<FileChecksum> {
+        let partition = &self.0;
+        let data = partition.data.lock().unwrap();
+        data.levels.iter().flat_map(|level| level.file_checksums(partition.options)).collect()
+    }
+
+    /// Reads every table file back and compares it with its checksum. Files compacted away
+    /// meanwhile are skipped.
+    pub(crate) fn verify_file_checksums(&self) -> Result<(), Error> {
+        let partition = &self.0;
+        for file_checksum in self.file_checksums() {
+            let raw = match partition.io_manager.acquire_quota().read_file(file_checksum.file_path.clone()) {
+                Ok(raw) => raw,
+                Err(_) if !self.file_checksums().contains(&file_checksum) => continue,
+                Err(e) => return Err(e)
+            };
+            if raw.len() as u64 != file_checksum.file_size || crc32::checksum_ieee(&raw) != file_checksum.crc32 {
+                return Err(Error::sc_table_corrupt(
+                    format!("{} does not match its checksum", file_checksum.file_path).into()))
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn degraded_ranges(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.0.data.lock().unwrap().corrupt_tables.iter()
+            .map(|table| (table.lower_bound.key().to_vec(), table.upper_bound.key().to_vec()))
+            .collect()
+    }
+
+    pub(crate) fn info(&self) -> PartitionInfo {
+        let partition = &self.0;
+        let data = partition.data.lock().unwrap();
+        PartitionInfo {
+            partition_id: partition.partition_id,
+            lower_bound: data.lower_bound.as_ref().map(|key| key.key().to_vec()),
+            upper_bound: data.upper_bound.as_ref().map(|key| key.key().to_vec()),
+            memtable_bytes: data.memtable_memory(),
+            has_imm: data.has_imm(),
+            level_tables: data.levels.iter().map(|level| level.table_count()).collect(),
+            background_error: data.background_error.clone()
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn gauges(&self) -> crate::statistics::prometheus::PartitionGauges {
+        let info = self.info();
+        crate::statistics::prometheus::PartitionGauges {
+            partition_id: info.partition_id,
+            memtable_bytes: info.memtable_bytes,
+            has_imm: info.has_imm,
+            level_tables: info.level_tables
+        }
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let start = self.0.options.clock.now_micros();
+        let partition = &self.0;
+        let lookup_key = LookupKey::new(u64::MAX, key);
+        partition.throttle(&partition.read_bucket, Ticker::ReadThrottled);
+        let mut data = partition.data.lock().unwrap();
+        data.hot_keys.sample(key);
+        let mut skip_level0 = false;
+        match partition.options.read_shedding {
+            ReadShedding::Reject { max_debt } if data.compaction_debt() > max_debt =>
+                return Err(Error::busy(data.compaction_debt())),
+            ReadShedding::SkipLevel0 { max_debt } => skip_level0 = data.compaction_debt() > max_debt,
+            _ => {}
+        }
+        let mut value = data.memtable_get(&lookup_key);
+        if value.is_none() && !skip_level0 {
+            value = data.row_cache_get(key, partition.statistics);
+        }
+        if value.is_none() {
+            let memory_tracker = data.memory_charge.as_ref().map(|memory_charge| memory_charge.tracker());
+            let mut corrupt_tables = Vec::new();
+            let mut result = Ok(TableGet::Absent);
+            // a deletion hides the older versions in the levels below
+            for level in data.levels.iter().skip(skip_level0 as usize) {
+                result = level.get(&lookup_key, partition.cache_manager, partition.io_manager,
+                                   partition.options, memory_tracker, &mut corrupt_tables);
+                if !matches!(result, Ok(TableGet::Absent)) {
+                    break;
+                }
+            }
+            data.record_corrupt_tables(corrupt_tables, partition.statistics);
+            data.recharge_memory();
+            if let TableGet::Found(found) = result? {
+                if !skip_level0 {
+                    data.row_cache_put(key, &found);
+                }
+                value = Some(found);
+            }
+        }
+        drop(data);
+        partition.statistics.record_since(HistogramType::Get, partition.options.clock.as_ref(), start);
+        Ok(value)
+    }
+
+    /// Writes `value` at `seq` as a stream, however large, see `stream`. The key must leave room
+    /// for the chunk keys within `Options::key_size_max`. Chunks of the value the key had
+    /// before are emptied once the new one is in place. Returns the size of the value.
+    pub(crate) fn put_stream(&self, seq: u64, key: &[u8], value: &mut dyn Read) -> Result<u64, Error> {
+        let chunk_size = self.0.options.value_size_max;
+        if chunk_size < stream::STREAM_HEAD_SIZE {
+            return Err(Error::value_too_large(stream::STREAM_HEAD_SIZE, chunk_size))
+        }
+        let old_head = self.get(key)?.and_then(|old| stream::decode_head(&old));
+        let mut buf = vec![0u8; chunk_size];
+        let mut chunk_count = 0;
+        let mut size = 0;
+        loop {
+            let filled = stream::read_chunk(value, &mut buf).map_err(|e| {
+                Error::io_error(e.to_string().into(), String::from_utf8_lossy(key).into_owned())
+            })?;
+            if filled == 0 {
+                break;
+            }
+            let chunk_key = UserKey::new_owned(stream::chunk_key(key, seq, chunk_count));
+            self.write(InternalKey::new(seq, chunk_key), buf[..filled].to_vec())?;
+            chunk_count += 1;
+            size += filled as u64;
+        }
+        self.write(InternalKey::new(seq, UserKey::new_owned(key.to_vec())), stream::encode_head(seq, chunk_count))?;
+        if let Some((old_seq, old_chunk_count)) = old_head.filter(|&(old_seq, _)| old_seq != seq) {
+            for chunk in 0..old_chunk_count {
+                let chunk_key = UserKey::new_owned(stream::chunk_key(key, old_seq, chunk));
+                self.write(InternalKey::new(seq, chunk_key), Vec::new())?;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Reads the value of `key` as a stream, also one written in a single record.
+    pub(crate) fn get_stream(&self, key: &[u8]) -> Result<Option<ValueStream<'a, Comp>>, Error> {
+        Ok(self.get(key)?.map(|value| match stream::decode_head(&value) {
+            Some((seq, chunk_count)) => ValueStream::chunked(self.clone(), key, seq, chunk_count),
+            None => ValueStream::whole(self.clone(), value)
+        }))
+    }
+
+    fn write_table(&self,
+                   level: usize,
+                   buffer: &[u8],
+                   lower: UserKey<Comp>,
+                   upper: UserKey<Comp>) -> Result<ScTable<Comp>, Error> {
+        let partition = &self.0;
+        let file_number = partition.data.lock().unwrap().levels[level].level_next_file_id();
+        let table_file = ScTableFile::new(partition.partition_id, level as u32, file_number);
+        let index = ScTableIndex::from_table(buffer)?;
+        let filter = TableFilter::from_table(buffer)?;
+        let footer = ScTableFooter::decode(buffer)?;
+        let file_path = table_file.file_path(partition.options);
+        partition.io_manager.acquire_quota().publish_file(file_path.clone(), buffer)?;
+        // only flushes write level 0
+        let ticker = if level == 0 { Ticker::FlushBytesWritten } else { Ticker::CompactionBytesWritten };
+        partition.statistics.record_ticks(ticker, buffer.len() as u64);
+        let progress = if level == 0 { &partition.flush_progress } else { &partition.compaction_progress };
+        progress.fetch_add(buffer.len() as u64, std::sync::atomic::Ordering::Relaxed);
+        fail_point!("table_written");
+        Ok(ScTable::new(table_file, file_path, index, filter, &footer, lower, upper)
+            .with_file_checksum(crc32::checksum_ieee(buffer)))
+    }
+
+    fn job_progress_counter(&self, kind: BackgroundJobKind) -> &AtomicU64 {
+        match kind {
+            BackgroundJobKind::Flush => &self.0.flush_progress,
+            BackgroundJobKind::Compaction(_) => &self.0.compaction_progress
+        }
+    }
+
+    pub(crate) fn job_progress(&self, kind: BackgroundJobKind) -> u64 {
+        self.job_progress_counter(kind).load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn reset_job_progress(&self, kind: BackgroundJobKind) {
+        self.job_progress_counter(kind).store(0, std::sync::atomic::Ordering::Relaxed)
+    }
+
     fn compact_memtable(&self) {
+        let start = self.0.options.clock.now_micros();
         let partition = &self.0;
-        let buffer;
-        let file_number;
+        let mut builder = new_table_builder(partition.options, 0);
         let imm_bounds;
         {
             let mut data = partition.data.lock().unwrap();
@@ -254,56 +950,345 @@ impl<'a, Comp: 'static + Comparator> ArcPartition<'a, Comp> {
                 return;
             }
             imm_bounds = data.imm_bounds();
-            let mut builder = ScTableBuilder::new();
+            let data = &mut *data;
             for (k, v) in data.imm_table.as_ref().unwrap().iter() {
                 builder.add_kv(k.seq, k.user_key.key(), &v);
+                data.key_sample.add(&k.user_key);
             }
-            buffer = builder.build();
             if data.levels.len() == 0 {
-                data.levels.push(Level::new());
+                data.levels.push(Level::new(0));
             }
-            file_number = data.levels[0].level_next_file_id();
-        }
-        let table_file = ScTableFile::new(partition.partition_id, 0, file_number);
-        if let Err(e) = partition.io_manager.acquire_quota().write_file(table_file.file_name(), &buffer) {
-            partition.data.lock().unwrap().record_background_error(e);
-            partition.condvar.notify_one();
-            return;
         }
         let (imm_lower, imm_upper) = imm_bounds;
-        let table = ScTable::new(table_file, imm_lower, imm_upper);
+        let table = match self.write_table(0, &builder.build(), imm_lower, imm_upper) {
+            Ok(table) => table,
+            Err(e) => {
+                partition.data.lock().unwrap().record_background_error(e);
+                partition.condvar.notify_all();
+                return;
+            }
+        };
         {
             let mut data = partition.data.lock().unwrap();
             data.levels[0].add_file(table);
             // TODO flush metadata onto disk
-            let _ = data.imm_table.take();
+            if let Some(imm_table) = data.imm_table.take() {
+                data.row_cache_invalidate(&imm_table);
+            }
+            data.imm_filter.take();
+            data.imm_table_memory = 0;
+            data.recharge_memory();
         }
-        partition.condvar.notify_one();
-        self.schedule_compaction(0);
+        partition.statistics.record_since(HistogramType::Flush, partition.options.clock.as_ref(), start);
+        partition.condvar.notify_all();
+        partition.scheduler.schedule(BackgroundJob::Compaction(self.clone(), 0));
     }
 
     fn schedule_compaction(&self, input_level: usize) {
+        let start = self.0.options.clock.now_micros();
         let output_level = input_level + 1;
         let partition = &self.0;
+        let input_files;
+        let output_files;
+        let split_at;
+        let mut split_keys: Vec<UserKey<Comp>>;
         {
-            let data = partition.data.lock().unwrap();
-            if data.background_error().is_err() {
+            let mut data = partition.data.lock().unwrap();
+            if data.background_error().is_err() || data.compacting {
+                return;
+            }
+            if data.levels.len() <= input_level
+               || data.levels[input_level].table_count() <= partition.options.level_size(input_level) {
                 return;
             }
-            if data.levels[input_level].table_count() <= partition.options.level_size(input_level) {
+            // moving tables to another directory frees nothing there, wait for room instead
+            if partition.options.db_path(output_level) != partition.options.db_path(input_level)
+               && self.short_free_space(output_level).is_some() {
                 return;
             }
-            // TODO
-            // 1. pick files to be compacted in input_level
-            // 2. find overlapping files in output_level
-            // 3. temporary unlock
-            // 4. perform compaction
-            // 5. re-lock
-            // 6. update metadata
-            // 7. schedule another compaction
-            // 8. un-lock
-            unimplemented!()
+            if data.levels.len() == output_level {
+                data.levels.push(Level::new(output_level));
+            }
+            let (inputs, outputs) = data.levels.split_at_mut(output_level);
+            let picked = inputs[input_level].pick_compaction_inputs(Some(&outputs[0]),
+                                                                    partition.options.max_compaction_bytes);
+            input_files = picked.input_files;
+            output_files = picked.output_files;
+            split_at = picked.split_at;
+            split_keys = if partition.options.compaction_split_hints {
+                data.predicted_split_key().into_iter().collect()
+            } else {
+                Vec::new()
+            };
+            split_keys.extend(split_at.iter().cloned());
+            split_keys.sort();
+            split_keys.dedup();
+            // two compactions could pick the same files, so only one runs at a time
+            data.compacting = true;
         }
+
+        let table_files: Vec<ScTableFile> = input_files.iter().chain(output_files.iter()).copied().collect();
+        let result = match &partition.options.compaction_service {
+            Some(service) => self.remote_compaction(service.as_ref(), &table_files, output_level, &split_keys,
+                                                    split_at.as_ref()),
+            None => self.merge_tables(&table_files, output_level, &split_keys, split_at.as_ref())
+        };
+        let split_level = |table: &ScTable<Comp>| Self::split_level(output_level, split_at.as_ref(), table.lower_bound());
+        {
+            let mut data = partition.data.lock().unwrap();
+            data.compacting = false;
+            let result = result.and_then(|tables| {
+                if partition.options.paranoid_compaction_checks {
+                    let (moved, left): (Vec<&ScTable<Comp>>, Vec<&ScTable<Comp>>) =
+                        tables.iter().partition(|table| split_level(table) == output_level);
+                    Self::check_compaction_outputs(&data.levels[output_level], &output_files, &moved)?;
+                    Self::check_compaction_outputs(&data.levels[input_level], &input_files, &left)?;
+                }
+                Ok(tables)
+            });
+            match result {
+                Ok(tables) => {
+                    data.levels[input_level].remove_files(&input_files);
+                    data.levels[output_level].remove_files(&output_files);
+                    for table in tables {
+                        data.levels[split_level(&table)].add_file(table);
+                    }
+                    data.recharge_memory();
+                    // TODO flush metadata onto disk
+                },
+                Err(e) => {
+                    if matches!(e, Error::CompactionCorrupt { .. }) {
+                        partition.statistics.record_tick(Ticker::CompactionCorrupt);
+                    }
+                    data.record_background_error(e);
+                    partition.condvar.notify_all();
+                    return;
+                }
+            }
+            partition.condvar.notify_all();
+            if let Err(e) = Self::compaction_installed() {
+                data.record_background_error(e);
+                return;
+            }
+        }
+        for table_file in table_files.iter() {
+            // readers hold the partition lock, so nobody can still be reading these;
+            // a file failing to go away only wastes space
+            let _ = partition.io_manager.acquire_quota().remove_file(table_file.file_path(partition.options));
+        }
+        partition.statistics.record_since(HistogramType::Compaction, partition.options.clock.as_ref(), start);
+
+        // flushes finished during the compaction skipped their check of level 0
+        if input_level != 0 {
+            partition.scheduler.schedule(BackgroundJob::Compaction(self.clone(), 0));
+        }
+        partition.scheduler.schedule(BackgroundJob::Compaction(self.clone(), input_level));
+        partition.scheduler.schedule(BackgroundJob::Compaction(self.clone(), output_level));
+    }
+
+    /// The new tables of `level` must be sorted and overlap neither each other nor the tables
+    /// staying in the level, all but `replaced_files`.
+    fn check_compaction_outputs(level: &Level<Comp>,
+                                replaced_files: &[ScTableFile],
+                                tables: &[&ScTable<Comp>]) -> Result<(), Error> {
+        for table in tables.iter() {
+            let siblings = level.overlapping_files(table.lower_bound(), table.upper_bound());
+            if siblings.iter().any(|sibling| !replaced_files.contains(sibling)) {
+                return Err(Error::compaction_corrupt("output overlaps a table of the output level".into()))
+            }
+        }
+        if tables.windows(2).any(|pair| pair[0].upper_bound() >= pair[1].lower_bound()) {
+            return Err(Error::compaction_corrupt("outputs overlap each other".into()))
+        }
+        Ok(())
+    }
+
+    fn compaction_installed() -> Result<(), Error> {
+        fail_point!("compaction_installed");
+        Ok(())
+    }
+
+    /// Tables from `split_at` on, one of `split_keys`, are written to the level above
+    /// `output_level`.
+    fn merge_tables(&self,
+                    table_files: &[ScTableFile],
+                    output_level: usize,
+                    split_keys: &[UserKey<Comp>],
+                    split_at: Option<&UserKey<Comp>>) -> Result<Vec<ScTable<Comp>>, Error> {
+        let partition = &self.0;
+        let file_paths: Vec<String> = table_files.iter().map(|f| f.file_path(partition.options)).collect();
+        merge_table_files(partition.options, partition.io_manager, &file_paths, output_level, split_keys,
+                          |builder, lower, upper| {
+                              let level = Self::split_level(output_level, split_at, &lower);
+                              self.write_table(level, &builder.build(), lower, upper)
+                          })
+    }
+
+    /// The level a compaction into `output_level` writes the table starting at `lower` to.
+    fn split_level(output_level: usize, split_at: Option<&UserKey<Comp>>, lower: &UserKey<Comp>) -> usize {
+        match split_at {
+            Some(split_at) if lower >= split_at => output_level - 1,
+            _ => output_level
+        }
+    }
+
+    /// Has `service` merge `table_files`, then writes the tables it sent back locally.
+    fn remote_compaction(&self,
+                         service: &dyn CompactionService,
+                         table_files: &[ScTableFile],
+                         output_level: usize,
+                         split_keys: &[UserKey<Comp>],
+                         split_at: Option<&UserKey<Comp>>) -> Result<Vec<ScTable<Comp>>, Error> {
+        let partition = &self.0;
+        let job = CompactionJob {
+            partition_id: partition.partition_id,
+            output_level,
+            input_files: table_files.iter().map(|f| f.file_path(partition.options)).collect(),
+            split_keys: split_keys.iter().map(|k| k.key().to_vec()).collect()
+        };
+        service.compact(&job)?.into_iter()
+            .map(|output| {
+                let lower = UserKey::new_owned(output.lower);
+                let level = Self::split_level(output_level, split_at, &lower);
+                self.write_table(level, &output.table, lower, UserKey::new_owned(output.upper))
+            })
+            .collect()
+    }
+}
+
+fn new_table_builder(options: &Options, level: usize) -> ScTableBuilder {
+    ScTableBuilder::new(options.block_size,
+                        options.filter_policy,
+                        (options.filter_keys)(level),
+                        options.filter_bits_per_key)
+        .with_block_hash_index(options.block_hash_index)
+}
+
+/// Merges the table files at `file_paths` into new tables of `output_level`, keeping only the
+/// newest version of every key, and hands every full table to `write_table`. A deletion is
+/// kept as the newest version, since the levels below may still hold the key. No output
+/// table spans any of the sorted `split_keys`, so that splitting the partition there later
+/// needs no table shared by both halves. The inputs are read block by block as the merge
+/// goes, never held in memory as a whole.
+pub(crate) fn merge_table_files<Comp, T, F>(options: &Options,
+                                            io_manager: &IOManager,
+                                            file_paths: &[String],
+                                            output_level: usize,
+                                            split_keys: &[UserKey<Comp>],
+                                            mut write_table: F) -> Result<Vec<T>, Error>
+    where Comp: 'static + Comparator,
+          F: FnMut(&ScTableBuilder, UserKey<Comp>, UserKey<Comp>) -> Result<T, Error> {
+    let mut inputs = file_paths.iter()
+        .map(|file_path| MergeInput::open(file_path, options, io_manager))
+        .collect::<Result<Vec<_>, Error>>()?;
+    // the next entry of every input, the smallest first: newer versions of a key come before
+    // older ones, and equal entries in the order of the inputs
+    let mut heap = BinaryHeap::new();
+    for (n, input) in inputs.iter_mut().enumerate() {
+        input.push_next::<Comp>(n, &mut heap)?;
+    }
+
+    let mut tables = Vec::new();
+    let mut builder = new_table_builder(options, output_level);
+    let mut bounds: Option<(UserKey<Comp>, UserKey<Comp>)> = None;
+    let mut last: Option<InternalKey<Comp>> = None;
+    let mut split_keys = split_keys.iter().peekable();
+    while let Some(Reverse((k, n, v))) = heap.pop() {
+        inputs[n].push_next(n, &mut heap)?;
+        if options.paranoid_compaction_checks && last.as_ref() == Some(&k) {
+            return Err(Error::compaction_corrupt(
+                format!("{} repeats a version of another input", file_paths[n]).into()))
+        }
+        // older versions come right after the newest one
+        if matches!(&last, Some(last) if last.user_key == k.user_key) {
+            last.replace(k);
+            continue;
+        }
+        let mut cross_split = false;
+        while matches!(split_keys.peek(), Some(&split_key) if split_key <= &k.user_key) {
+            split_keys.next();
+            cross_split = true;
+        }
+        if cross_split {
+            if let Some((lower, upper)) = bounds.take() {
+                let full = std::mem::replace(&mut builder, new_table_builder(options, output_level));
+                tables.push(write_table(&full, lower, upper)?);
+            }
+        }
+        builder.add_entry(k.seq, k.user_key.key(), v.as_deref());
+        let lower = bounds.take().map_or_else(|| k.user_key.clone(), |(lower, _)| lower);
+        bounds.replace((lower, k.user_key.clone()));
+        if builder.size() >= options.target_table_size(output_level) {
+            let (lower, upper) = bounds.take().unwrap();
+            let full = std::mem::replace(&mut builder, new_table_builder(options, output_level));
+            tables.push(write_table(&full, lower, upper)?);
+        }
+        last.replace(k);
+    }
+    if let Some((lower, upper)) = bounds {
+        tables.push(write_table(&builder, lower, upper)?);
+    }
+    Ok(tables)
+}
+
+/// Smallest and largest user key of the table file at `file_path`, reading only its first block.
+fn table_bounds<Comp: Comparator>(index: &ScTableIndex,
+                                  file_path: &str,
+                                  io_manager: &IOManager) -> Result<(UserKey<Comp>, UserKey<Comp>), Error> {
+    if index.block_count() == 0 {
+        return Err(Error::sc_table_corrupt("table has no entries".into()))
+    }
+    let first = index.nth_block(0);
+    let raw_block = io_manager.acquire_quota().read_file_range(
+        file_path.to_string(), first.block_off as u64, first.block_size as usize)?;
+    let first_block = ScTableCache::from_raw(&raw_block, CacheQuota::transient())?;
+    if first_block.catalog_size() == 0 {
+        return Err(Error::sc_table_corrupt("table has an empty block".into()))
+    }
+    let (_, lower, _) = first_block.nth_item(0);
+    let upper = &index.nth_block(index.block_count() - 1).last_key;
+    Ok((UserKey::new_owned(lower.to_vec()), UserKey::new_owned(upper.clone())))
+}
+
+type RowCache = LruCache<u64, (Vec<u8>, Vec<u8>)>;
+
+type MergeHeap<Comp> = BinaryHeap<Reverse<(InternalKey<Comp>, usize, Option<Vec<u8>>)>>;
+
+/// One input table of `merge_table_files`, read as the merge goes.
+struct MergeInput<'a> {
+    file_path: &'a str,
+    entries: TableEntries<'a>,
+    paranoid_checks: bool,
+    last: Option<(u64, Vec<u8>)>
+}
+
+impl<'a> MergeInput<'a> {
+    fn open(file_path: &'a str, options: &Options, io_manager: &'a IOManager) -> Result<Self, Error> {
+        Ok(Self {
+            file_path,
+            entries: TableEntries::open(file_path.to_string(), options.compaction_readahead_size, io_manager)?,
+            paranoid_checks: options.paranoid_compaction_checks,
+            last: None
+        })
+    }
+
+    /// Moves the next entry of the input, the `n`th one, to `heap`.
+    fn push_next<Comp: Comparator>(&mut self, n: usize, heap: &mut MergeHeap<Comp>) -> Result<(), Error> {
+        let (seq, key, value) = match self.entries.next().transpose()? {
+            Some(entry) => entry,
+            None => return Ok(())
+        };
+        if self.paranoid_checks {
+            // newer versions of a key come first, and every version has a sequence number
+            // of its own
+            if matches!(&self.last, Some((last_seq, last_key))
+                        if Comp::compare(last_key, &key).then(seq.cmp(last_seq)) != Ordering::Less) {
+                return Err(Error::compaction_corrupt(format!("{} has entries out of order", self.file_path).into()))
+            }
+            self.last.replace((seq, key.clone()));
+        }
+        heap.push(Reverse((InternalKey::new(seq, UserKey::new_owned(key)), n, value)));
+        Ok(())
     }
 }
 
@@ -312,12 +1297,29 @@ pub(crate) struct PartitionData<'a, Comp: 'static + Comparator> {
     mem_table_data_size: usize,
 
     imm_table: Option<MemTable<Comp>>,
+    // `memtable_memory` of the imm when it was frozen
+    imm_table_memory: usize,
+    // user keys of the memtable and the imm, if `Options::memtable_filter_bits_per_key` is set
+    mem_filter: Option<MemTableFilter>,
+    imm_filter: Option<MemTableFilter>,
     levels: Vec<Level<Comp>>,
+    compacting: bool,
+    key_sample: KeySample<Comp>,
+    hot_keys: HotKeySketch,
+    // values found in the levels with their user keys, by `key_hash` of the user key so a
+    // lookup needs no owned key; a colliding key only ever replaces the entry
+    row_cache: Option<RowCache>,
 
     lower_bound: Option<UserKey<Comp>>,
     upper_bound: Option<UserKey<Comp>>,
+    // largest sequence number written
+    max_seq: u64,
 
     background_error: Option<Error>,
+    // tables reads found a corrupt block in, for repair out of band
+    corrupt_tables: Vec<CorruptTable<Comp>>,
+    // `Options::total_memory_budget`
+    memory_charge: Option<MemoryCharge>,
 
     options: &'a Options
 }
@@ -328,10 +1330,21 @@ impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
             mem_table: MemTable::new(),
             mem_table_data_size: 0,
             imm_table: None,
+            imm_table_memory: 0,
+            mem_filter: Self::new_mem_filter(options),
+            imm_filter: None,
             levels: Vec::new(),
+            compacting: false,
+            // the sample is random, replicas would predict different split keys
+            key_sample: KeySample::new(if options.deterministic_compaction { 0 } else { options.key_sample_size }),
+            hot_keys: HotKeySketch::new(options.hot_key_count, options.hot_key_sample_rate),
+            row_cache: if options.row_cache_size == 0 { None } else { Some(LruCache::new(options.row_cache_size)) },
             lower_bound: None,
             upper_bound: None,
             background_error: None,
+            max_seq: 0,
+            corrupt_tables: Vec::new(),
+            memory_charge: None,
             options
         }
     }
@@ -348,6 +1361,15 @@ impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
         self.background_error.replace(error);
     }
 
+    fn new_mem_filter(options: &Options) -> Option<MemTableFilter> {
+        if options.memtable_filter_bits_per_key == 0 {
+            None
+        } else {
+            // every entry takes at least a catalog item out of `table_size`
+            Some(MemTableFilter::new(options.table_size / TABLE_CATALOG_ITEM_SIZE, options.memtable_filter_bits_per_key))
+        }
+    }
+
     fn has_imm(&self) -> bool {
         self.imm_table.is_some()
     }
@@ -361,6 +1383,7 @@ impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
 
     fn memtable_put(&mut self, key: InternalKey<Comp>, value: Vec<u8>) {
         debug_assert!(self.memtable_size() + kv_pair_size(&key, &value) <= self.options.table_size);
+        self.max_seq = self.max_seq.max(key.seq);
         if self.lower_bound.is_none() && self.upper_bound.is_none() {
             self.set_lower_bound(key.user_key.clone());
             self.set_upper_bound(key.user_key.clone());
@@ -369,29 +1392,199 @@ impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
         } else if &key.user_key > self.upper_bound.as_ref().unwrap() {
             self.set_upper_bound(key.user_key.clone());
         }
-        self.mem_table.insert(key, value);
+        if let Some(mem_filter) = self.mem_filter.as_mut() {
+            mem_filter.insert(key.user_key.key());
+        }
+        self.remove_older_versions(&key);
+        self.mem_table_data_size += key.user_key.key().len() + value.len();
+        let replaced_size = key.user_key.key().len();
+        if let Some(replaced) = self.mem_table.insert(key, value) {
+            // the same version written again, only the new value is kept
+            self.mem_table_data_size -= replaced_size + replaced.len();
+        }
+    }
+
+    /// Drops the versions of `key.user_key` older than `key` from the memtable. Reads are never
+    /// older than the newest version, there are no snapshots, so nothing can see them any more;
+    /// compaction drops them the same way.
+    fn remove_older_versions(&mut self, key: &InternalKey<Comp>) {
+        let older: Vec<u64> = self.mem_table
+            .range::<dyn KeyRef<Comp>, _>((Bound::Excluded(key as &dyn KeyRef<Comp>), Bound::Unbounded))
+            .take_while(|(k, _)| k.user_key == key.user_key)
+            .map(|(k, _)| k.seq)
+            .collect();
+        for seq in older {
+            let older_key = LookupKey::<Comp>::new(seq, key.user_key.key());
+            if let Some(value) = self.mem_table.remove(&older_key as &dyn KeyRef<Comp>) {
+                self.mem_table_data_size -= key.user_key.key().len() + value.len();
+            }
+        }
+    }
+
+    /// Entries of the memtables with user keys from `lower` to `upper`, inclusive.
+    fn memtable_entries(&self, lower: &[u8], upper: &[u8]) -> u64 {
+        if Comp::compare(lower, upper) == Ordering::Greater {
+            return 0
+        }
+        let first = LookupKey::<Comp>::new(u64::MAX, lower);
+        let last = LookupKey::<Comp>::new(0, upper);
+        let count = |table: &MemTable<Comp>| {
+            table.range::<dyn KeyRef<Comp>, _>((Bound::Included(&first as &dyn KeyRef<Comp>),
+                                                 Bound::Included(&last as &dyn KeyRef<Comp>)))
+                 .count() as u64
+        };
+        count(&self.mem_table) + self.imm_table.as_ref().map_or(0, count)
+    }
+
+    fn memtable_get(&self, key: &LookupKey<Comp>) -> Option<Vec<u8>> {
+        let lookup = |table: &MemTable<Comp>, filter: &Option<MemTableFilter>| {
+            if let Some(filter) = filter {
+                if !filter.may_contain(key.user_key) {
+                    return None
+                }
+            }
+            table.range::<dyn KeyRef<Comp>, _>((Bound::Included(key as &dyn KeyRef<Comp>), Bound::Unbounded))
+                 .next()
+                 .filter(|(k, _)| Comp::compare(k.user_key.key(), key.user_key) == Ordering::Equal)
+                 .map(|(_, v)| v.clone())
+        };
+        lookup(&self.mem_table, &self.mem_filter)
+            .or_else(|| self.imm_table.as_ref().and_then(|imm_table| lookup(imm_table, &self.imm_filter)))
+    }
+
+    /// With `Options::skip_corrupt_tables` the tables are also quarantined: taken out of their
+    /// level, leaving the file in place for repair. The row cache is cleared either way, values
+    /// read from a table that turned out corrupt are not to be served again.
+    fn record_corrupt_tables(&mut self, corrupt_tables: Vec<CorruptTable<Comp>>, statistics: &Statistics) {
+        if !corrupt_tables.is_empty() {
+            self.row_cache_clear();
+        }
+        for corrupt_table in corrupt_tables {
+            statistics.record_tick(Ticker::CorruptTableRead);
+            if self.options.skip_corrupt_tables {
+                for level in self.levels.iter_mut() {
+                    level.remove_files(&[corrupt_table.table_file]);
+                }
+            }
+            if self.corrupt_tables.iter().all(|table| table.table_file != corrupt_table.table_file) {
+                self.corrupt_tables.push(corrupt_table);
+            }
+        }
+    }
+
+    fn row_cache_get(&mut self, key: &[u8], statistics: &Statistics) -> Option<Vec<u8>> {
+        let row_cache = self.row_cache.as_mut()?;
+        let ret = row_cache.get(&key_hash(key))
+                           .filter(|(cached_key, _)| cached_key.as_slice() == key)
+                           .map(|(_, value)| value.clone());
+        statistics.record_tick(if ret.is_some() { Ticker::RowCacheHit } else { Ticker::RowCacheMiss });
+        ret
+    }
+
+    fn row_cache_put(&mut self, key: &[u8], value: &[u8]) {
+        if let Some(row_cache) = self.row_cache.as_mut() {
+            row_cache.put(key_hash(key), (key.to_vec(), value.to_vec()));
+        }
+    }
+
+    /// A flushed memtable holds a newer version of every key in it than the levels did, so
+    /// whatever was cached for those keys is stale now.
+    fn row_cache_clear(&mut self) {
+        if let Some(row_cache) = self.row_cache.as_mut() {
+            row_cache.clear();
+        }
+    }
+
+    fn row_cache_invalidate(&mut self, flushed: &MemTable<Comp>) {
+        if let Some(row_cache) = self.row_cache.as_mut() {
+            for k in flushed.keys() {
+                row_cache.pop(&key_hash(k.user_key.key()));
+            }
+        }
     }
 
     fn convert_mem_to_imm(&mut self) {
+        self.imm_table_memory = self.memtable_memory();
         let new_imm = std::mem::replace(&mut self.mem_table, MemTable::new());
         self.imm_table.replace(new_imm);
+        self.mem_table_data_size = 0;
+        self.imm_filter = std::mem::replace(&mut self.mem_filter, Self::new_mem_filter(self.options));
+    }
+
+    /// Where the partition will most likely be split: in the table holding the middle byte of
+    /// the data, with the tables of all levels ordered by their lower bounds and weighted by
+    /// the size of their keys and values. Within that table it is the median of the flushed
+    /// keys sampled there, or else its lower bound. Before there are two tables the sample
+    /// alone predicts it.
+    fn predicted_split_key(&self) -> Option<UserKey<Comp>> {
+        let mut tables: Vec<(&UserKey<Comp>, &UserKey<Comp>, u64)> =
+            self.levels.iter().flat_map(|level| level.data_ranges()).collect();
+        if tables.len() < 2 {
+            return self.key_sample.median()
+        }
+        tables.sort_by(|(lower, _, _), (other_lower, _, _)| lower.cmp(other_lower));
+        let half = tables.iter().map(|(_, _, data_size)| data_size).sum::<u64>() / 2;
+        let mut below = 0;
+        let (lower, upper, _) = tables.iter()
+            .find(|(_, _, data_size)| {
+                below += data_size;
+                below > half
+            })
+            .unwrap_or(&tables[tables.len() - 1]);
+        Some(self.key_sample.median_between(lower, upper).unwrap_or_else(|| (*lower).clone()))
+    }
+
+    fn compaction_debt(&self) -> usize {
+        self.levels.iter()
+            .enumerate()
+            .map(|(n, level)| level.table_count().saturating_sub(self.options.level_size(n)))
+            .sum()
     }
 
+    /// Size of the table the memtable would flush to.
     fn memtable_size(&self) -> usize {
         self.mem_table_data_size + self.mem_table.len() * TABLE_CATALOG_ITEM_SIZE + TABLE_MIN_SIZE
     }
 
+    /// Memory held by the memtable: its keys and values plus the entries themselves, in B-tree
+    /// nodes assumed to be two thirds full.
+    fn memtable_memory(&self) -> usize {
+        self.mem_table_data_size + self.mem_table.len() * memtable_entry_size::<Comp>() * 3 / 2
+    }
+
+    /// Memory of the memtables and table readers, for `Options::total_memory_budget`.
+    fn memory_held(&self) -> MemoryUsage {
+        MemoryUsage {
+            memtables: self.memtable_memory() + self.imm_table_memory,
+            table_readers: self.levels.iter().map(|level| level.reader_memory()).sum(),
+            ..MemoryUsage::default()
+        }
+    }
+
+    /// Brings what is charged to the memory tracker in line with what is held now.
+    fn recharge_memory(&mut self) {
+        let held = self.memory_held();
+        if let Some(memory_charge) = self.memory_charge.as_mut() {
+            memory_charge.recharge(held);
+        }
+    }
+
+    fn check_memory(&self, kind: MemoryKind, bytes: usize) -> Result<(), Error> {
+        match &self.memory_charge {
+            Some(memory_charge) => memory_charge.tracker().check(kind, bytes),
+            None => Ok(())
+        }
+    }
+
     fn bounds(&self) -> (Option<&UserKey<Comp>>, Option<&UserKey<Comp>>) {
         (self.lower_bound.as_ref(), self.upper_bound.as_ref())
     }
 
     fn set_lower_bound(&mut self, lower_bound: UserKey<Comp>) {
-        debug_assert!(lower_bound.is_owned());
         self.lower_bound.replace(lower_bound);
     }
 
     fn set_upper_bound(&mut self, upper_bound: UserKey<Comp>) {
-        debug_assert!(upper_bound.is_owned());
         self.upper_bound.replace(upper_bound);
     }
 
@@ -399,3 +1592,910 @@ impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
         self.lower_bound.is_some() == self.upper_bound.is_some()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU64;
+    use std::io::Read;
+
+    use crc::crc32;
+
+    use crate::{DbPath, Options, DefaultComparator};
+    use crate::io::{FileSystem, IOManager};
+    use crate::io::mem::MemFileSystem;
+    use crate::io::fault_injection::FaultInjectionFs;
+    use crate::statistics::{Statistics, Ticker};
+    use crate::table::Table;
+    use crate::partition::level::Level;
+    use crate::table::cache::TableCacheManager;
+    use crate::table::sctable::ScTableFile;
+    use crate::table::index::ScTableIndex;
+    use crate::table::filter::key_hash;
+    use crate::clock::{Clock, MockClock};
+    use crate::error::Error;
+    use crate::memory::{MemoryKind, MemoryTracker, MemoryUsage};
+    use crate::partition::{Partition, ArcPartition, InternalKey, UserKey, ReadShedding, ScanCost, merge_table_files, new_table_builder};
+    use crate::partition::scheduler::{BackgroundJob, BackgroundScheduler, BackgroundMode};
+    use crate::partition::stream;
+    use crate::table::tablefmt::{TABLE_CATALOG_ITEM_SIZE, TABLE_FOOTER_SIZE};
+
+    /// What a partition under test borrows, with `options` reading and writing through `fs`.
+    struct Fixture<'a> {
+        options: Options,
+        seq: AtomicU64,
+        cache_manager: TableCacheManager,
+        io_manager: IOManager,
+        statistics: Statistics,
+        scheduler: BackgroundScheduler<'a, DefaultComparator>
+    }
+
+    impl<'a> Fixture<'a> {
+        fn new(mut options: Options, fs: Arc<dyn FileSystem>, mode: BackgroundMode) -> Self {
+            options.file_system = fs.clone();
+            Self {
+                options,
+                seq: AtomicU64::new(0),
+                cache_manager: TableCacheManager::new(4),
+                io_manager: IOManager::new(4, fs, 0),
+                statistics: Statistics::new(),
+                scheduler: BackgroundScheduler::new(mode)
+            }
+        }
+
+        fn new_partition(&'a self, partition_id: u32) -> Partition<'a, DefaultComparator> {
+            Partition::new(&self.options, partition_id, &self.seq, &self.cache_manager,
+                           &self.io_manager, &self.statistics, &self.scheduler)
+        }
+
+        fn partition(&'a self) -> ArcPartition<'a, DefaultComparator> {
+            ArcPartition::new(self.new_partition(0))
+        }
+    }
+
+    #[test]
+    fn test_get_after_compaction() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.block_size = 128;
+        let fixture = Fixture::new(options, fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+
+        for i in 0..600u64 {
+            let key = format!("key{:02}", i % 100).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), format!("value{}", i).into_bytes())
+                     .unwrap();
+        }
+
+        {
+            let data = partition.0.data.lock().unwrap();
+            assert!(data.levels.len() > 1);
+            assert!(data.levels[0].table_count() <= fixture.options.level0_size);
+        }
+        for i in 500..600u64 {
+            let key = format!("key{:02}", i % 100).into_bytes();
+            assert_eq!(partition.get(&key).unwrap(), Some(format!("value{}", i).into_bytes()));
+        }
+        assert_eq!(partition.get(b"key").unwrap(), None);
+        assert_eq!(partition.get(b"key100").unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_paths() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        // room for level 0 only
+        options.db_paths = vec![DbPath::new("hot", 1000), DbPath::new("cold", 1 << 30)];
+        let fixture = Fixture::new(options, fs.clone(), BackgroundMode::Inline);
+        let partition = fixture.partition();
+        for i in 0..300u64 {
+            let key = format!("key{:02}", i % 100).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), format!("value{}", i).into_bytes())
+                     .unwrap();
+        }
+
+        let file_names = fs.file_names();
+        assert!(file_names.iter().any(|file_name| file_name.starts_with("cold/")));
+        for file_name in file_names {
+            let expected = if file_name.contains("/0_0_") { "hot/" } else { "cold/" };
+            assert!(file_name.starts_with(expected), "{}", file_name);
+        }
+        for i in 200..300u64 {
+            let key = format!("key{:02}", i % 100).into_bytes();
+            assert_eq!(partition.get(&key).unwrap(), Some(format!("value{}", i).into_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_db_paths_no_space() {
+        let mem_fs = Arc::new(MemFileSystem::new());
+        let fs = Arc::new(FaultInjectionFs::new(mem_fs.clone()));
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.db_paths = vec![DbPath::new("hot", 1000), DbPath::new("cold", 1 << 30)];
+        options.reserved_disk_bytes = 1000;
+        let fixture = Fixture::new(options, fs.clone(), BackgroundMode::Inline);
+        let partition = fixture.partition();
+        // only the directory of level 0 has room, flushes go on but nothing moves to level 1
+        fs.set_free_space(Some(999));
+        fs.set_dir_free_space("hot", Some(1000));
+        for i in 0..100u64 {
+            partition.write(InternalKey::new(i, UserKey::new_owned(format!("key{:02}", i).into_bytes())),
+                            b"value".to_vec()).unwrap();
+        }
+        assert!(partition.space_usage().levels[0].tables > fixture.options.level0_size);
+        assert!(mem_fs.file_names().iter().all(|file_name| file_name.starts_with("hot/")));
+
+        fs.set_dir_free_space("cold", Some(1000));
+        partition.schedule_compaction(0);
+        assert!(partition.space_usage().levels[0].tables <= fixture.options.level0_size);
+        assert!(mem_fs.file_names().iter().any(|file_name| file_name.starts_with("cold/")));
+        assert_eq!(fixture.statistics.ticker(Ticker::WriteNoSpace), 0);
+
+        // a full level 0 directory still stops flushes
+        fs.set_dir_free_space("hot", Some(999));
+        let e = (100..200u64).map(|i| {
+            partition.write(InternalKey::new(i, UserKey::new_owned(format!("key{:03}", i).into_bytes())),
+                            b"value".to_vec())
+        }).find_map(Result::err);
+        assert!(matches!(e, Some(Error::NoSpace { free_bytes: 999 })));
+    }
+
+    #[test]
+    fn test_row_cache() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.row_cache_size = 16;
+        let fixture = Fixture::new(options, fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+        let mut next_seq = 0;
+        let mut write = |key: &[u8], value: &[u8]| {
+            next_seq += 1;
+            partition.write(InternalKey::new(next_seq, UserKey::new_owned(key.to_vec())), value.to_vec()).unwrap();
+        };
+        let flush = |write: &mut dyn FnMut(&[u8], &[u8])| {
+            for i in 0..20u8 {
+                write(&[b'z', i], b"");
+            }
+        };
+
+        write(b"hot", b"1");
+        flush(&mut write);
+        assert_eq!(partition.get(b"hot").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(partition.get(b"hot").unwrap(), Some(b"1".to_vec()));
+        let statistics = &fixture.statistics;
+        assert_eq!((statistics.ticker(Ticker::RowCacheMiss), statistics.ticker(Ticker::RowCacheHit)), (1, 1));
+
+        // the newer version is served from the memtable, then from the levels once flushed
+        write(b"hot", b"2");
+        assert_eq!(partition.get(b"hot").unwrap(), Some(b"2".to_vec()));
+        flush(&mut write);
+        assert_eq!(partition.get(b"hot").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(statistics.ticker(Ticker::RowCacheMiss), 2);
+    }
+
+    /// Reads `key` once level 0 piled up behind a stalled compaction.
+    fn read_with_debt(read_shedding: ReadShedding, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.read_shedding = read_shedding;
+        let fixture = Fixture::new(options, fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.0.data.lock().unwrap().compacting = true;
+        for i in 0..100u64 {
+            let key = format!("key{:02}", i).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), b"value".to_vec()).unwrap();
+        }
+        assert!(partition.0.data.lock().unwrap().compaction_debt() > 1);
+        partition.get(key)
+    }
+
+    #[test]
+    fn test_read_shedding() {
+        assert_eq!(read_with_debt(ReadShedding::Disabled, b"key00").unwrap(), Some(b"value".to_vec()));
+        assert!(matches!(read_with_debt(ReadShedding::Reject { max_debt: 1 }, b"key00"), Err(Error::Busy { .. })));
+        // the memtable is still read
+        let skip_level0 = ReadShedding::SkipLevel0 { max_debt: 1 };
+        assert_eq!(read_with_debt(skip_level0, b"key00").unwrap(), None);
+        assert_eq!(read_with_debt(skip_level0, b"key99").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_split_keys() {
+        let fs = Arc::new(MemFileSystem::new());
+        let fixture = Fixture::new(Options::new("test", 4, 2, 2, 4, 400, 16, 16), fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.0.data.lock().unwrap().compacting = true;
+        for i in 0..100u64 {
+            let key = format!("key{:02}", i).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), b"value".to_vec()).unwrap();
+        }
+
+        let files = {
+            let mut data = partition.0.data.lock().unwrap();
+            data.levels.push(Level::new(1));
+            data.levels[0].pick_compaction_inputs(None, 0).input_files
+        };
+        let split_key = UserKey::new_owned(b"key05".to_vec());
+        let tables = partition.merge_tables(&files, 1, std::slice::from_ref(&split_key), None).unwrap();
+        assert!(tables.iter().all(|table| table.upper_bound() < &split_key || table.lower_bound() >= &split_key));
+        assert!(tables.iter().any(|table| table.lower_bound() == &split_key));
+    }
+
+    #[test]
+    fn test_predicted_split_key() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.key_sample_size = 0;
+        let fixture = Fixture::new(options, fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.0.data.lock().unwrap().compacting = true;
+        // most of the keys are at the start, most of the data at the end
+        for i in 0..100u64 {
+            let key = format!("key{:02}", i).into_bytes();
+            let value = if i < 80 { Vec::new() } else { vec![b'v'; 16] };
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), value).unwrap();
+        }
+
+        let split_key = partition.0.data.lock().unwrap().predicted_split_key().unwrap();
+        assert!(split_key > UserKey::new_owned(b"key70".to_vec()), "{:?}", String::from_utf8_lossy(split_key.key()));
+    }
+
+    #[test]
+    fn test_table_size_multiplier() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.table_size_multiplier = 4;
+        let fixture = Fixture::new(options, fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.0.data.lock().unwrap().compacting = true;
+        for i in 0..100u64 {
+            let key = format!("key{:02}", i).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), b"value".to_vec()).unwrap();
+        }
+
+        let files = {
+            let mut data = partition.0.data.lock().unwrap();
+            data.levels.push(Level::new(1));
+            data.levels.push(Level::new(2));
+            data.levels[0].pick_compaction_inputs(None, 0).input_files
+        };
+        let level1 = partition.merge_tables(&files, 1, &[], None).unwrap();
+        let level2 = partition.merge_tables(&files, 2, &[], None).unwrap();
+        assert!(level2.len() * 3 < level1.len(), "{} vs {}", level2.len(), level1.len());
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 4000, 16, 100);
+        options.block_size = 200;
+        let fixture = Fixture::new(options, fs, BackgroundMode::Inline);
+        let memory_tracker = Arc::new(MemoryTracker::new(MemoryUsage::split(2000)));
+        let partition = ArcPartition::new(fixture.new_partition(0).with_memory_tracker(memory_tracker.clone()));
+        let mut written = 0;
+        let error = loop {
+            let key = format!("key{:02}", written).into_bytes();
+            match partition.write(InternalKey::new(written + 1, UserKey::new_owned(key)), vec![0u8; 100]) {
+                Ok(()) => written += 1,
+                Err(e) => break e
+            }
+        };
+        assert!(matches!(error, Error::MemoryLimit { limit: 1000, .. }), "{:?}", error);
+        assert!(written > 0);
+        let memtable_memory = partition.0.data.lock().unwrap().memtable_memory();
+        assert_eq!(memory_tracker.usage().memtables, memtable_memory);
+        assert!(memtable_memory <= 1000);
+        assert_eq!(partition.get(format!("key{:02}", written).as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_memory_budget_pinned_blocks() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 4000, 16, 100);
+        options.block_size = 200;
+        let fixture = Fixture::new(options, fs, BackgroundMode::Inline);
+        let memory_tracker = Arc::new(MemoryTracker::new(MemoryUsage::split(2000)));
+        let partition = ArcPartition::new(fixture.new_partition(0).with_memory_tracker(memory_tracker.clone()));
+        partition.write(InternalKey::new(1, UserKey::new_owned(b"key".to_vec())), b"value".to_vec()).unwrap();
+        partition.freeze_memtable().unwrap();
+
+        // another read holds most of the share of the pinned blocks; the block read is charged
+        // its own size, not a whole `block_size`
+        let pin = memory_tracker.pin(MemoryKind::PinnedBlocks, 240).unwrap();
+        let error = partition.get(b"key").unwrap_err();
+        assert!(matches!(error, Error::MemoryLimit { used: 240, requested, limit: 250 } if requested < 200),
+                "{:?}", error);
+        drop(pin);
+        let pin = memory_tracker.pin(MemoryKind::PinnedBlocks, 100).unwrap();
+        assert_eq!(partition.get(b"key").unwrap(), Some(b"value".to_vec()));
+        drop(pin);
+        assert_eq!(memory_tracker.usage().pinned_blocks, 0);
+
+        // the partition gives back all it was charged when it goes away
+        assert!(memory_tracker.usage().table_readers > 0);
+        drop(partition);
+        assert_eq!(memory_tracker.usage(), MemoryUsage::default());
+    }
+
+    #[test]
+    fn test_memtable_size() {
+        let fs = Arc::new(MemFileSystem::new());
+        let fixture = Fixture::new(Options::new("test", 4, 2, 2, 4, 400, 16, 100), fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+        let empty_size = partition.0.data.lock().unwrap().memtable_size();
+        partition.write(InternalKey::new(1, UserKey::new_owned(b"key".to_vec())), b"value".to_vec()).unwrap();
+        partition.write(InternalKey::new(2, UserKey::new_owned(b"key".to_vec())), b"value2".to_vec()).unwrap();
+        // rewriting a version replaces its value, a newer one drops the older versions
+        partition.write(InternalKey::new(2, UserKey::new_owned(b"key".to_vec())), b"v2".to_vec()).unwrap();
+        {
+            let data = partition.0.data.lock().unwrap();
+            assert_eq!(data.mem_table.len(), 1);
+            assert_eq!(data.memtable_size(), empty_size + 3 + 2 + TABLE_CATALOG_ITEM_SIZE);
+            assert!(data.memtable_memory() > 3 + 2);
+        }
+        assert_eq!(partition.get(b"key").unwrap(), Some(b"v2".to_vec()));
+
+        // values count towards the flush threshold
+        for i in 0..10u64 {
+            partition.write(InternalKey::new(10 + i, UserKey::new_owned(format!("key{}", i).into_bytes())),
+                            vec![0u8; 100]).unwrap();
+        }
+        let data = partition.0.data.lock().unwrap();
+        assert!(data.memtable_size() <= fixture.options.table_size);
+        assert!(data.levels[0].table_count() > 0);
+        drop(data);
+
+        let e = partition.write(InternalKey::new(20, UserKey::new_owned(vec![0u8; 17])), Vec::new()).unwrap_err();
+        assert!(matches!(e, Error::KeyTooLarge { size: 17, max: 16 }));
+        let e = partition.write(InternalKey::new(20, UserKey::new_owned(b"key".to_vec())), vec![0u8; 101]).unwrap_err();
+        assert!(matches!(e, Error::ValueTooLarge { size: 101, max: 100 }));
+    }
+
+    #[test]
+    fn test_space_usage() {
+        let fs = Arc::new(MemFileSystem::new());
+        let fixture = Fixture::new(Options::new("test", 4, 2, 2, 4, 400, 16, 16), fs.clone(), BackgroundMode::Inline);
+        let partition = fixture.partition();
+        assert_eq!(partition.space_usage().space_amplification(), 0.0);
+        partition.0.data.lock().unwrap().compacting = true;
+        for i in 0..100u64 {
+            let key = format!("key{:02}", i).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), b"value".to_vec()).unwrap();
+        }
+
+        let space_usage = partition.space_usage();
+        let level0 = space_usage.levels[0];
+        assert_eq!(level0.tables, fs.file_names().len());
+        assert_eq!(level0.data_bytes, level0.entries as u64 * 10);
+        let file_bytes: usize = fs.file_names().iter().map(|file_name| fs.read_file(file_name).unwrap().len()).sum();
+        assert_eq!(space_usage.file_bytes(), file_bytes as u64);
+        assert_eq!(space_usage.live_bytes(), level0.data_bytes);
+        assert!(space_usage.space_amplification() > 1.0);
+        let excess_tables = level0.tables as u64 - fixture.options.level0_size as u64;
+        assert_eq!(level0.pending_compaction_bytes, level0.file_bytes * excess_tables / level0.tables as u64);
+
+        let snapshot = fixture.statistics.snapshot();
+        assert_eq!((snapshot.user_bytes_written, snapshot.flush_bytes_written), (1000, file_bytes as u64));
+        assert_eq!(snapshot.compaction_bytes_written, 0);
+        assert!(snapshot.write_amplification() > 0.0);
+    }
+
+    #[test]
+    fn test_partition_info() {
+        let fs = Arc::new(MemFileSystem::new());
+        let fixture = Fixture::new(Options::new("test", 4, 2, 2, 4, 400, 16, 16), fs.clone(), BackgroundMode::Inline);
+        let partition = ArcPartition::new(fixture.new_partition(3));
+        let info = partition.info();
+        assert_eq!((info.partition_id, info.lower_bound, info.upper_bound), (3, None, None));
+        partition.0.data.lock().unwrap().compacting = true;
+        for i in (0..100u64).rev() {
+            let key = format!("key{:02}", i).into_bytes();
+            partition.write(InternalKey::new(100 - i, UserKey::new_owned(key)), b"value".to_vec()).unwrap();
+        }
+
+        let info = partition.info();
+        assert_eq!(info.lower_bound, Some(b"key00".to_vec()));
+        assert_eq!(info.upper_bound, Some(b"key99".to_vec()));
+        assert_eq!(info.level_tables, vec![fs.file_names().len()]);
+        assert!(info.memtable_bytes > 0);
+        assert!(!info.has_imm);
+        assert!(info.background_error.is_none());
+    }
+
+    #[test]
+    fn test_scan_cost() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.block_size = 64;
+        let fixture = Fixture::new(options, fs.clone(), BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.0.data.lock().unwrap().compacting = true;
+        for i in 0..100u64 {
+            let key = format!("key{:02}", i).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), b"value".to_vec()).unwrap();
+        }
+
+        let all = partition.scan_cost(b"key", b"key99");
+        assert_eq!(all.entries, 100);
+        let blocks: usize = fs.file_names().iter()
+            .map(|file_name| ScTableIndex::from_table(&fs.read_file(file_name).unwrap()).unwrap().block_count())
+            .sum();
+        assert_eq!(all.blocks, blocks as u64);
+        assert!(all.bytes > 0 && all.bytes < partition.space_usage().file_bytes());
+
+        // the first table only, block by block
+        let some = partition.scan_cost(b"key00", b"key02");
+        assert!(some.blocks >= 1 && some.blocks < all.blocks);
+        assert!(some.entries >= 3 && some.entries < all.entries);
+        assert_eq!(partition.scan_cost(b"key99x", b"kez"), ScanCost::default());
+        assert_eq!(partition.scan_cost(b"key50", b"key40"), ScanCost::default());
+    }
+
+    #[test]
+    fn test_freeze_memtable() {
+        let fs = Arc::new(MemFileSystem::new());
+        let fixture = Fixture::new(Options::new("test", 4, 2, 2, 4, 400, 16, 16), fs.clone(), BackgroundMode::Manual);
+        let partition = fixture.partition();
+        assert_eq!(partition.freeze_memtable().unwrap(), 0);
+        assert_eq!(fixture.scheduler.pending_jobs(), 0);
+        partition.write(InternalKey::new(5, UserKey::new_owned(b"key1".to_vec())), b"value".to_vec()).unwrap();
+        partition.write(InternalKey::new(3, UserKey::new_owned(b"key2".to_vec())), b"value".to_vec()).unwrap();
+
+        assert_eq!(partition.freeze_memtable().unwrap(), 5);
+        assert!(partition.0.data.lock().unwrap().has_imm());
+        // nothing new to freeze
+        assert_eq!(partition.freeze_memtable().unwrap(), 5);
+        assert_eq!(fixture.scheduler.pending_jobs(), 1);
+        assert!(fixture.scheduler.step());
+        assert_eq!(fs.file_names().len(), 1);
+        assert_eq!(partition.get(b"key2").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_stream() {
+        let fs = Arc::new(MemFileSystem::new());
+        let fixture = Fixture::new(Options::new("test", 4, 2, 2, 4, 400, 16, 16), fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+        let read = |key: &[u8]| -> Option<Vec<u8>> {
+            let mut value = Vec::new();
+            partition.get_stream(key).unwrap()?.read_to_end(&mut value).unwrap();
+            Some(value)
+        };
+        let value: Vec<u8> = (0..100u8).collect();
+        assert_eq!(partition.put_stream(1, b"big", &mut value.as_slice()).unwrap(), 100);
+        assert_eq!(read(b"big"), Some(value));
+        assert_eq!(partition.get(&stream::chunk_key(b"big", 1, 6)).unwrap(), Some((96..100u8).collect()));
+
+        // a new value empties the chunks of the old one
+        let value = vec![7u8; 20];
+        assert_eq!(partition.put_stream(2, b"big", &mut value.as_slice()).unwrap(), 20);
+        assert_eq!(read(b"big"), Some(value));
+        assert_eq!(partition.get(&stream::chunk_key(b"big", 1, 6)).unwrap(), Some(Vec::new()));
+
+        partition.write(InternalKey::new(3, UserKey::new_owned(b"small".to_vec())), b"value".to_vec()).unwrap();
+        assert_eq!(read(b"small"), Some(b"value".to_vec()));
+        assert_eq!(read(b"none"), None);
+        // no room left for the chunk keys
+        let e = partition.put_stream(4, b"too long", &mut [0u8; 10].as_ref()).unwrap_err();
+        assert!(matches!(e, Error::KeyTooLarge { size: 21, max: 16 }));
+    }
+
+    #[test]
+    fn test_deletion() {
+        let fs = Arc::new(MemFileSystem::new());
+        let fixture = Fixture::new(Options::new("test", 4, 1, 2, 4, 400, 16, 16), fs, BackgroundMode::Manual);
+        let partition = fixture.partition();
+        partition.write(InternalKey::new(1, UserKey::new_owned(b"key1".to_vec())), b"value".to_vec()).unwrap();
+        partition.write(InternalKey::new(2, UserKey::new_owned(b"key2".to_vec())), b"value".to_vec()).unwrap();
+        partition.freeze_memtable().unwrap();
+        assert!(fixture.scheduler.step());
+
+        // a newer table of level 0 deleting `key1`
+        let mut builder = new_table_builder(&fixture.options, 0);
+        builder.add_deletion(3, b"key1");
+        let key1 = UserKey::new_owned(b"key1".to_vec());
+        let table = partition.write_table(0, &builder.build(), key1.clone(), key1).unwrap();
+        partition.0.data.lock().unwrap().levels[0].add_file(table);
+        assert_eq!(partition.get(b"key1").unwrap(), None);
+        assert_eq!(partition.get(b"key2").unwrap(), Some(b"value".to_vec()));
+
+        // compaction keeps the deletion
+        fixture.scheduler.schedule(BackgroundJob::Compaction(partition.clone(), 0));
+        while fixture.scheduler.step() {}
+        assert_eq!(partition.0.data.lock().unwrap().levels[0].table_count(), 0);
+        assert_eq!(partition.get(b"key1").unwrap(), None);
+        assert_eq!(partition.get(b"key2").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_ingest() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.row_cache_size = 16;
+        let fixture = Fixture::new(options, fs.clone(), BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.write(InternalKey::new(5, UserKey::new_owned(b"key1".to_vec())), b"old".to_vec()).unwrap();
+        partition.write(InternalKey::new(6, UserKey::new_owned(b"key3".to_vec())), b"old".to_vec()).unwrap();
+        assert_eq!(partition.get(b"key1").unwrap(), Some(b"old".to_vec()));
+
+        // several versions of a key cannot share one seq, the file stays where it is
+        let mut builder = new_table_builder(&fixture.options, 0);
+        builder.add_kv(2, b"key1", b"new");
+        builder.add_kv(1, b"key1", b"old");
+        fs.write_file("external", &builder.build()).unwrap();
+        assert!(matches!(partition.ingest("external"), Err(Error::IngestRejected { .. })));
+        assert_eq!(fs.file_names(), vec!["external".to_string()]);
+
+        // built elsewhere, with sequence numbers of its own older than the memtable's
+        let mut builder = new_table_builder(&fixture.options, 0);
+        builder.add_kv(1, b"key1", b"new");
+        builder.add_kv(1, b"key2", b"new");
+        let raw = builder.build();
+        fs.write_file("external", &raw).unwrap();
+        assert_eq!(partition.ingest("external").unwrap(), 7);
+        assert!(!partition.0.data.lock().unwrap().has_imm());
+        // moved into the partition, with only the footer rewritten
+        assert!(fs.read_file("external").is_err());
+        // the flushed table has a checksum, the ingested one goes without
+        let file_checksums = partition.file_checksums();
+        let ingested: Vec<String> = fs.file_names().into_iter()
+            .filter(|file_name| file_checksums.iter().all(|file_checksum| &file_checksum.file_path != file_name))
+            .collect();
+        assert_eq!((file_checksums.len(), ingested.len()), (1, 1));
+        let ingested = fs.read_file(&ingested[0]).unwrap();
+        assert_eq!(&ingested[..raw.len() - TABLE_FOOTER_SIZE], &raw[..raw.len() - TABLE_FOOTER_SIZE]);
+        assert_eq!(partition.get(b"key1").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(partition.get(b"key2").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(partition.get(b"key3").unwrap(), Some(b"old".to_vec()));
+
+        // newer writes still win, also once compacted together
+        partition.write(InternalKey::new(8, UserKey::new_owned(b"key2".to_vec())), b"newer".to_vec()).unwrap();
+        partition.freeze_memtable().unwrap();
+        assert!(partition.space_usage().levels.len() > 1);
+        assert_eq!(partition.get(b"key1").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(partition.get(b"key2").unwrap(), Some(b"newer".to_vec()));
+        assert_eq!(partition.get(b"key3").unwrap(), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn test_paranoid_compaction_checks() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.paranoid_compaction_checks = true;
+        let io_manager = IOManager::new(4, fs.clone(), 0);
+        let write = |file_name: &str, entries: &[(u64, &[u8])]| {
+            let mut builder = new_table_builder(&options, 1);
+            for (seq, key) in entries {
+                builder.add_kv(*seq, key, b"value");
+            }
+            fs.write_file(file_name, &builder.build()).unwrap();
+            file_name.to_string()
+        };
+        let sorted = write("sorted", &[(3, b"a"), (2, b"a"), (1, b"b")]);
+        let unsorted = write("unsorted", &[(1, b"b"), (2, b"a")]);
+        let regressed = write("regressed", &[(2, b"a"), (3, b"a")]);
+        let repeated = write("repeated", &[(2, b"a")]);
+
+        let merge = |options: &Options, file_paths: &[String]| {
+            merge_table_files::<DefaultComparator, _, _>(options, &io_manager, file_paths, 1, &[], |_, _, _| Ok(()))
+        };
+        assert!(merge(&options, std::slice::from_ref(&sorted)).is_ok());
+        for file_paths in [vec![unsorted.clone()], vec![regressed], vec![sorted, repeated]].iter() {
+            assert!(matches!(merge(&options, file_paths), Err(Error::CompactionCorrupt { .. })));
+        }
+        options.paranoid_compaction_checks = false;
+        assert!(merge(&options, std::slice::from_ref(&unsorted)).is_ok());
+    }
+
+    #[test]
+    fn test_deterministic_compaction() {
+        let replica = || {
+            let fs = Arc::new(MemFileSystem::new());
+            let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+            options.compaction_split_hints = true;
+            options.deterministic_compaction = true;
+            let fixture = Fixture::new(options, fs.clone(), BackgroundMode::Inline);
+            let partition = fixture.partition();
+            for i in 0..600u64 {
+                let key = format!("key{:02}", i * 7 % 100).into_bytes();
+                partition.write(InternalKey::new(i, UserKey::new_owned(key)), format!("value{}", i).into_bytes())
+                         .unwrap();
+            }
+            fs.file_names().into_iter().map(|file_name| {
+                let content = fs.read_file(&file_name).unwrap();
+                (file_name, content)
+            }).collect::<Vec<_>>()
+        };
+        let files = replica();
+        assert!(files.iter().any(|(file_name, _)| !file_name.starts_with("0_0_")));
+        assert_eq!(replica(), files);
+    }
+
+    #[test]
+    fn test_file_checksums() {
+        let fs = Arc::new(MemFileSystem::new());
+        let fixture = Fixture::new(Options::new("test", 4, 2, 2, 4, 400, 16, 16), fs.clone(), BackgroundMode::Inline);
+        let partition = fixture.partition();
+        for i in 0..300u64 {
+            let key = format!("key{:02}", i % 100).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), b"value".to_vec()).unwrap();
+        }
+
+        let file_checksums = partition.file_checksums();
+        assert_eq!(file_checksums.len(), fs.file_names().len());
+        for file_checksum in file_checksums.iter() {
+            let raw = fs.read_file(&file_checksum.file_path).unwrap();
+            assert_eq!((raw.len() as u64, crc32::checksum_ieee(&raw)), (file_checksum.file_size, file_checksum.crc32));
+        }
+        assert!(partition.verify_file_checksums().is_ok());
+
+        let mut raw = fs.read_file(&file_checksums[0].file_path).unwrap();
+        raw[0] ^= 1;
+        fs.write_file(&file_checksums[0].file_path, &raw).unwrap();
+        assert!(matches!(partition.verify_file_checksums(), Err(Error::ScTableCorrupt { .. })));
+    }
+
+    #[test]
+    fn test_plan_compactions() {
+        let fs = Arc::new(MemFileSystem::new());
+        let fixture = Fixture::new(Options::new("test", 4, 2, 2, 4, 400, 16, 16), fs.clone(), BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.0.data.lock().unwrap().compacting = true;
+        for i in 0..100u64 {
+            let key = format!("key{:02}", i).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), b"value".to_vec()).unwrap();
+        }
+
+        let plans = partition.plan_compactions();
+        assert_eq!(plans.len(), 1);
+        assert_eq!((plans[0].input_level, plans[0].output_level), (0, 1));
+        let mut input_files = plans[0].input_files.clone();
+        input_files.sort();
+        assert_eq!(input_files, fs.file_names());
+        assert!(plans[0].overlapping_files.is_empty());
+        assert_eq!(plans[0].estimated_bytes, partition.space_usage().file_bytes());
+        // planning picks nothing, the compaction then does what was planned
+        assert_eq!(partition.plan_compactions(), plans);
+        partition.0.data.lock().unwrap().compacting = false;
+        partition.schedule_compaction(0);
+        assert!(fs.file_names().iter().all(|file_name| !input_files.contains(file_name)));
+        assert!(partition.plan_compactions().is_empty());
+    }
+
+    #[test]
+    fn test_level0_slowdown() {
+        let fs = Arc::new(MemFileSystem::new());
+        let clock = Arc::new(MockClock::new(0));
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.level0_slowdown_writes = 3;
+        options.clock = clock.clone();
+        let fixture = Fixture::new(options, fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.0.data.lock().unwrap().compacting = true;
+        let mut i = 0u64;
+        while partition.0.data.lock().unwrap().levels.first().map_or(0, |level| level.table_count()) < 3 {
+            partition.write(InternalKey::new(i, UserKey::new_owned(i.to_be_bytes().to_vec())), b"value".to_vec()).unwrap();
+            i += 1;
+        }
+        assert_eq!(fixture.statistics.snapshot().writes_slowed_down, 0);
+        assert_eq!(clock.now_micros(), 0);
+        partition.write(InternalKey::new(i, UserKey::new_owned(i.to_be_bytes().to_vec())), b"value".to_vec()).unwrap();
+        assert_eq!(fixture.statistics.snapshot().writes_slowed_down, 1);
+        assert_eq!(clock.now_micros(), 1000);
+
+        // the compaction brings level 0 back down
+        partition.0.data.lock().unwrap().compacting = false;
+        partition.schedule_compaction(0);
+        partition.write(InternalKey::new(i + 1, UserKey::new_owned(b"key".to_vec())), b"value".to_vec()).unwrap();
+        assert_eq!(fixture.statistics.snapshot().writes_slowed_down, 1);
+    }
+
+    #[test]
+    fn test_level0_stop() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.level0_stop_writes = 3;
+        let fixture = Fixture::new(options, fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.0.data.lock().unwrap().compacting = true;
+        let mut i = 0u64;
+        while partition.0.data.lock().unwrap().levels.first().map_or(0, |level| level.table_count()) < 3 {
+            partition.write(InternalKey::new(i, UserKey::new_owned(i.to_be_bytes().to_vec())), b"value".to_vec()).unwrap();
+            i += 1;
+        }
+
+        assert_eq!(fixture.statistics.snapshot().writes_stopped, 0);
+
+        // the stopped write runs the compaction it waits for, which brings level 0 back down
+        partition.0.data.lock().unwrap().compacting = false;
+        partition.write(InternalKey::new(i, UserKey::new_owned(b"key".to_vec())), b"value".to_vec()).unwrap();
+        assert_eq!(fixture.statistics.snapshot().writes_stopped, 1);
+        assert!(partition.0.data.lock().unwrap().levels[0].table_count() < 3);
+        assert_eq!(partition.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_max_compaction_bytes() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.max_compaction_bytes = 1;
+        let fixture = Fixture::new(options, fs, BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.0.data.lock().unwrap().compacting = true;
+        for i in 0..100u64 {
+            let key = format!("key{:02}", i % 25).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), format!("value{}", i).into_bytes()).unwrap();
+        }
+        let level0_tables = partition.0.data.lock().unwrap().levels[0].table_count();
+        assert!(level0_tables > 3);
+
+        let plans = partition.plan_compactions();
+        assert_eq!(plans[0].input_files, vec![ScTableFile::new(0, 0, 1).file_path(&fixture.options)]);
+        // one table per compaction, until level 0 is back within its size
+        partition.0.data.lock().unwrap().compacting = false;
+        partition.schedule_compaction(0);
+        assert_eq!(partition.0.data.lock().unwrap().levels[0].table_count(), 2);
+        for i in 0..25u64 {
+            let key = format!("key{:02}", i).into_bytes();
+            assert_eq!(partition.get(&key).unwrap(), Some(format!("value{}", i + 75).into_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_max_compaction_bytes_split() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 0, 2, 4, 400, 16, 16);
+        options.max_compaction_bytes = 1;
+        options.paranoid_compaction_checks = true;
+        let fixture = Fixture::new(options, fs, BackgroundMode::Manual);
+        let partition = fixture.partition();
+        let table = |level: usize, seq: u64, keys: &[&[u8]], value: &[u8]| {
+            let mut builder = new_table_builder(&fixture.options, level);
+            for key in keys {
+                builder.add_kv(seq, key, value);
+            }
+            let (lower, upper) = (UserKey::new_owned(keys[0].to_vec()), UserKey::new_owned(keys[keys.len() - 1].to_vec()));
+            partition.write_table(level, &builder.build(), lower, upper).unwrap()
+        };
+        for level in 0..3 {
+            partition.0.data.lock().unwrap().levels.push(Level::new(level));
+        }
+        let level2 = vec![table(2, 1, &[b"a", b"b"], b"old"), table(2, 1, &[b"c", b"d"], b"old"),
+                          table(2, 1, &[b"e", b"f"], b"old")];
+        let level1 = table(1, 2, &[b"a", b"c", b"e"], b"new");
+        {
+            let mut data = partition.0.data.lock().unwrap();
+            level2.into_iter().for_each(|table| data.levels[2].add_file(table));
+            data.levels[1].add_file(level1);
+        }
+
+        // the table of level 1 overlaps all of level 2, only the first one fits
+        let plans = partition.plan_compactions();
+        let plan = plans.iter().find(|plan| plan.input_level == 1).unwrap();
+        assert_eq!(plan.overlapping_files, vec![ScTableFile::new(0, 2, 1).file_path(&fixture.options)]);
+        assert_eq!(plan.split_key, Some(b"c".to_vec()));
+        partition.schedule_compaction(1);
+        assert_eq!(partition.0.data.lock().unwrap().levels[1].table_count(), 1);
+        assert_eq!(partition.0.data.lock().unwrap().levels[2].table_count(), 3);
+        let check = || {
+            for (key, value) in [(b"a", b"new"), (b"b", b"old"), (b"c", b"new"), (b"d", b"old"), (b"e", b"new"),
+                                 (b"f", b"old")].iter() {
+                assert_eq!(partition.get(*key).unwrap(), Some(value.to_vec()));
+            }
+        };
+        check();
+
+        // the rest of the table follows, one table of level 2 at a time
+        partition.schedule_compaction(1);
+        partition.schedule_compaction(1);
+        assert_eq!(partition.0.data.lock().unwrap().levels[1].table_count(), 0);
+        assert_eq!(partition.0.data.lock().unwrap().levels[2].table_count(), 3);
+        check();
+    }
+
+    #[test]
+    fn test_reserved_disk_bytes() {
+        let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFileSystem::new())));
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.reserved_disk_bytes = 1000;
+        let fixture = Fixture::new(options, fs.clone(), BackgroundMode::Inline);
+        let partition = fixture.partition();
+        fs.set_free_space(Some(999));
+        let mut i = 0;
+        // the memtable still takes writes, flushing it does not
+        let e = loop {
+            match partition.write(InternalKey::new(i, UserKey::new_owned(format!("key{:02}", i).into_bytes())),
+                                  b"value".to_vec()) {
+                Ok(()) => i += 1,
+                Err(e) => break e
+            }
+        };
+        assert!(matches!(e, Error::NoSpace { free_bytes: 999 }));
+        assert!(i > 0);
+        assert_eq!(fs.write_count(), 0);
+        assert_eq!(fixture.statistics.ticker(Ticker::WriteNoSpace), 1);
+
+        fs.set_free_space(Some(1000));
+        partition.write(InternalKey::new(i, UserKey::new_owned(format!("key{:02}", i).into_bytes())),
+                        b"value".to_vec()).unwrap();
+        assert_eq!(partition.get(format!("key{:02}", i).as_bytes()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(partition.space_usage().levels[0].tables, 1);
+    }
+
+    type ReadResult = Result<Option<Vec<u8>>, Error>;
+
+    /// Reads a key whose newest table is corrupt twice. Returns the degraded upper bounds too.
+    fn read_corrupt(skip_corrupt_tables: bool) -> ([ReadResult; 2], Vec<Vec<u8>>, u64) {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.corrupt_block_retries = 2;
+        options.skip_corrupt_tables = skip_corrupt_tables;
+        options.row_cache_size = 16;
+        let fixture = Fixture::new(options, fs.clone(), BackgroundMode::Inline);
+        let partition = fixture.partition();
+        partition.0.data.lock().unwrap().compacting = true;
+        let mut next_seq = 0;
+        // each version of `key` goes into a table of its own
+        for (tables, value) in [(1, b"old"), (2, b"new")].iter() {
+            next_seq += 1;
+            partition.write(InternalKey::new(next_seq, UserKey::new_owned(b"key".to_vec())), value.to_vec()).unwrap();
+            while partition.0.data.lock().unwrap().levels.first().map_or(0, |level| level.table_count()) < *tables {
+                next_seq += 1;
+                let filler = format!("filler{}", next_seq).into_bytes();
+                partition.write(InternalKey::new(next_seq, UserKey::new_owned(filler)), b"value".to_vec()).unwrap();
+            }
+        }
+
+        // cached from a table left intact, dropped all the same once a corrupt table shows up
+        assert_eq!(partition.get(b"filler2").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(partition.0.data.lock().unwrap().row_cache.as_ref().unwrap().len(), 1);
+
+        // the newest table, flipping a byte of its first block
+        let file_name = fs.file_names().pop().unwrap();
+        let mut raw = fs.read_file(&file_name).unwrap();
+        raw[20] ^= 0xff;
+        fs.write_file(&file_name, &raw).unwrap();
+
+        let results = [partition.get(b"key"), partition.get(b"key")];
+        assert_eq!(partition.corrupt_table_files(), vec![file_name]);
+        assert!(!partition.0.data.lock().unwrap().row_cache.as_ref().unwrap().contains(&key_hash(b"filler2")));
+        let upper_bounds = partition.degraded_ranges().into_iter().map(|(_, upper)| upper).collect();
+        (results, upper_bounds, fixture.statistics.ticker(Ticker::CorruptTableRead))
+    }
+
+    #[test]
+    fn test_corrupt_block() {
+        let degraded = vec![b"key".to_vec()];
+        let ([first, second], degraded_ranges, corrupt_reads) = read_corrupt(false);
+        assert!(matches!(first, Err(Error::ScTableCorrupt { .. })));
+        assert!(matches!(second, Err(Error::ScTableCorrupt { .. })));
+        assert_eq!((degraded_ranges, corrupt_reads), (degraded.clone(), 2));
+
+        // quarantined after the first read, the second one does not touch the table
+        let ([first, second], degraded_ranges, corrupt_reads) = read_corrupt(true);
+        assert_eq!(first.unwrap(), Some(b"old".to_vec()));
+        assert_eq!(second.unwrap(), Some(b"old".to_vec()));
+        assert_eq!((degraded_ranges, corrupt_reads), (degraded, 1));
+    }
+
+    #[cfg(feature = "failpoints")]
+    #[test]
+    fn test_failpoints() {
+        use crate::failpoint::{self, FailAction};
+
+        let fs = Arc::new(MemFileSystem::new());
+        let fixture = Fixture::new(Options::new("test", 4, 2, 2, 4, 400, 16, 16), fs.clone(), BackgroundMode::Inline);
+        let partition = fixture.partition();
+
+        // the first flush writes its table but never adds it to level 0
+        failpoint::enable("table_written", FailAction::Error);
+        let mut i = 0;
+        while partition.write(InternalKey::new(i, UserKey::new_owned(format!("key{:02}", i).into_bytes())),
+                              b"value".to_vec()).is_ok() {
+            i += 1;
+        }
+        failpoint::disable("table_written");
+        assert_eq!(fs.file_names(), vec!["0_0_1.sst".to_string()]);
+        assert!(partition.0.data.lock().unwrap().levels[0].table_count() == 0);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{Comparator, Options};
+use crate::encode::{decode_fixed32, encode_fixed32_ret};
+use crate::error::Error;
+use crate::io::IOManager;
+use crate::partition::{UserKey, merge_table_files};
+
+/// Runs compactions somewhere else, e.g. on a worker machine sharing the table files. The
+/// engine sends a `CompactionJob` and writes the tables it gets back into the output level
+/// itself, installing them the same way as a local compaction does.
+pub trait CompactionService: Send + Sync {
+    fn compact(&self, job: &CompactionJob) -> Result<Vec<CompactionOutput>, Error>;
+}
+
+/// A compaction merging `input_files` into tables of `output_level`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionJob {
+    pub partition_id: u32,
+    pub output_level: usize,
+    pub input_files: Vec<String>,
+    /// Keys no output table may span
+    pub split_keys: Vec<Vec<u8>>
+}
+
+/// A table file written by a `CompactionService`, holding the keys `lower..=upper`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionOutput {
+    pub table: Vec<u8>,
+    pub lower: Vec<u8>,
+    pub upper: Vec<u8>
+}
+
+fn encode_bytes(dest: &mut Vec<u8>, bytes: &[u8]) {
+    dest.extend_from_slice(&encode_fixed32_ret(bytes.len() as u32));
+    dest.extend_from_slice(bytes);
+}
+
+fn decode_fixed32_at(raw: &[u8], pos: &mut usize) -> Result<u32, Error> {
+    if raw.len() < *pos + 4 {
+        return Err(Error::compaction_job_corrupt("truncated".into()))
+    }
+    let ret = decode_fixed32(&raw[*pos..*pos + 4]);
+    *pos += 4;
+    Ok(ret)
+}
+
+fn decode_bytes_at(raw: &[u8], pos: &mut usize) -> Result<Vec<u8>, Error> {
+    let len = decode_fixed32_at(raw, pos)? as usize;
+    if raw.len() < *pos + len {
+        return Err(Error::compaction_job_corrupt("truncated".into()))
+    }
+    let ret = raw[*pos..*pos + len].to_vec();
+    *pos += len;
+    Ok(ret)
+}
+
+impl CompactionJob {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&encode_fixed32_ret(self.partition_id));
+        ret.extend_from_slice(&encode_fixed32_ret(self.output_level as u32));
+        ret.extend_from_slice(&encode_fixed32_ret(self.input_files.len() as u32));
+        for input_file in self.input_files.iter() {
+            encode_bytes(&mut ret, input_file.as_bytes());
+        }
+        ret.extend_from_slice(&encode_fixed32_ret(self.split_keys.len() as u32));
+        for split_key in self.split_keys.iter() {
+            encode_bytes(&mut ret, split_key);
+        }
+        ret
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<Self, Error> {
+        let mut pos = 0;
+        let partition_id = decode_fixed32_at(raw, &mut pos)?;
+        let output_level = decode_fixed32_at(raw, &mut pos)? as usize;
+        let input_count = decode_fixed32_at(raw, &mut pos)?;
+        let mut input_files = Vec::new();
+        for _ in 0..input_count {
+            let input_file = String::from_utf8(decode_bytes_at(raw, &mut pos)?)
+                .map_err(|_| Error::compaction_job_corrupt("input file name is not utf-8".into()))?;
+            input_files.push(input_file);
+        }
+        let split_count = decode_fixed32_at(raw, &mut pos)?;
+        let mut split_keys = Vec::new();
+        for _ in 0..split_count {
+            split_keys.push(decode_bytes_at(raw, &mut pos)?);
+        }
+        if pos != raw.len() {
+            return Err(Error::compaction_job_corrupt("trailing bytes".into()))
+        }
+        Ok(Self { partition_id, output_level, input_files, split_keys })
+    }
+
+    /// Executes the job on the worker side. `options` has to match those of the database for
+    /// the tables to come out the same as from a local compaction; the input files are read
+    /// through `options.file_system`.
+    pub fn run<Comp: 'static + Comparator>(&self, options: &Options) -> Result<Vec<CompactionOutput>, Error> {
+        let io_manager = IOManager::new(options.max_open_files, options.file_system.clone(), 0);
+        let split_keys: Vec<UserKey<Comp>> = self.split_keys.iter()
+            .map(|key| UserKey::new_owned(key.clone()))
+            .collect();
+        merge_table_files(options, &io_manager, &self.input_files, self.output_level, &split_keys,
+                          |builder, lower, upper| Ok(CompactionOutput {
+                              table: builder.build(),
+                              lower: lower.key().to_vec(),
+                              upper: upper.key().to_vec()
+                          }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    use crate::{DefaultComparator, Options};
+    use crate::error::Error;
+    use crate::io::IOManager;
+    use crate::io::mem::MemFileSystem;
+    use crate::partition::{ArcPartition, Partition, InternalKey, UserKey};
+    use crate::partition::remote::{CompactionJob, CompactionOutput, CompactionService};
+    use crate::partition::scheduler::{BackgroundMode, BackgroundScheduler};
+    use crate::statistics::Statistics;
+    use crate::table::cache::TableCacheManager;
+
+    /// Sends the job over the "wire" and runs it with the same options.
+    struct LoopbackService {
+        options: Options,
+        jobs: AtomicUsize
+    }
+
+    impl CompactionService for LoopbackService {
+        fn compact(&self, job: &CompactionJob) -> Result<Vec<CompactionOutput>, Error> {
+            self.jobs.fetch_add(1, Ordering::Relaxed);
+            let job = CompactionJob::decode(&job.encode())?;
+            job.run::<DefaultComparator>(&self.options)
+        }
+    }
+
+    #[test]
+    fn test_remote_compaction() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut worker_options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        worker_options.block_size = 128;
+        worker_options.file_system = fs.clone();
+        let service = Arc::new(LoopbackService { options: worker_options, jobs: AtomicUsize::new(0) });
+        let mut options = Options::new("test", 4, 2, 2, 4, 400, 16, 16);
+        options.block_size = 128;
+        options.file_system = fs.clone();
+        options.compaction_service = Some(service.clone());
+        let seq = AtomicU64::new(0);
+        let io_manager = IOManager::new(4, fs, 0);
+        let cache_manager = TableCacheManager::new(4);
+        let statistics = Statistics::new();
+        let scheduler = BackgroundScheduler::new(BackgroundMode::Inline);
+        let partition = ArcPartition::new(Partition::<DefaultComparator>::new(
+            &options, 0, &seq, &cache_manager, &io_manager, &statistics, &scheduler));
+
+        for i in 0..600u64 {
+            let key = format!("key{:02}", i % 100).into_bytes();
+            partition.write(InternalKey::new(i, UserKey::new_owned(key)), format!("value{}", i).into_bytes())
+                     .unwrap();
+        }
+
+        assert!(service.jobs.load(Ordering::Relaxed) > 0);
+        assert!(partition.0.data.lock().unwrap().levels.len() > 1);
+        for i in 500..600u64 {
+            let key = format!("key{:02}", i % 100).into_bytes();
+            assert_eq!(partition.get(&key).unwrap(), Some(format!("value{}", i).into_bytes()));
+        }
+        assert!(CompactionJob::decode(&[0; 7]).is_err());
+    }
+}
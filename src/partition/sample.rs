@@ -0,0 +1,82 @@
+use rand::{thread_rng, Rng};
+
+use crate::Comparator;
+use crate::partition::UserKey;
+
+/// A uniform sample of up to `capacity` of the keys flushed from a partition, by reservoir
+/// sampling. Keys rewritten by several flushes count once per flush.
+pub(crate) struct KeySample<Comp: 'static + Comparator> {
+    capacity: usize,
+    seen: u64,
+    keys: Vec<UserKey<Comp>>
+}
+
+impl<Comp: 'static + Comparator> KeySample<Comp> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self { capacity, seen: 0, keys: Vec::with_capacity(capacity) }
+    }
+
+    pub(crate) fn add(&mut self, key: &UserKey<Comp>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.seen += 1;
+        if self.keys.len() < self.capacity {
+            self.keys.push(key.clone());
+            return;
+        }
+        let slot = thread_rng().gen_range(0, self.seen);
+        if slot < self.capacity as u64 {
+            self.keys[slot as usize] = key.clone();
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// The key with about half of the sampled keys on either side.
+    pub(crate) fn median(&self) -> Option<UserKey<Comp>> {
+        if self.keys.len() < 2 {
+            return None
+        }
+        let mut keys: Vec<&UserKey<Comp>> = self.keys.iter().collect();
+        keys.sort();
+        Some(keys[keys.len() / 2].clone())
+    }
+
+    /// The key with about half of the sampled keys from `lower` to `upper` on either side.
+    pub(crate) fn median_between(&self, lower: &UserKey<Comp>, upper: &UserKey<Comp>) -> Option<UserKey<Comp>> {
+        let mut keys: Vec<&UserKey<Comp>> = self.keys.iter().filter(|key| *key >= lower && *key <= upper).collect();
+        if keys.is_empty() {
+            return None
+        }
+        keys.sort();
+        Some(keys[keys.len() / 2].clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::DefaultComparator;
+    use crate::partition::UserKey;
+    use crate::partition::sample::KeySample;
+
+    #[test]
+    fn test_key_sample() {
+        let mut sample = KeySample::<DefaultComparator>::new(256);
+        // most keys sit at the start of the key space
+        for i in 0..10000u32 {
+            let key = if i % 10 == 0 { 9000 + i / 10 } else { i / 10 };
+            sample.add(&UserKey::new_owned(format!("key{:05}", key).into_bytes()));
+        }
+        assert_eq!(sample.len(), 256);
+        let median = sample.median().unwrap();
+        assert!(median > UserKey::new_owned(b"key00300".to_vec()));
+        assert!(median < UserKey::new_owned(b"key00700".to_vec()));
+        let median = sample.median_between(&UserKey::new_owned(b"key09000".to_vec()),
+                                           &UserKey::new_owned(b"key09999".to_vec())).unwrap();
+        assert!(median > UserKey::new_owned(b"key09100".to_vec()));
+        assert!(median < UserKey::new_owned(b"key09900".to_vec()));
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::Comparator;
+use crate::partition::ArcPartition;
+
+/// How flushes and compactions are executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackgroundMode {
+    /// Run each job on the thread that scheduled it, as soon as it has released the partition.
+    Inline,
+    /// Queue jobs until the caller runs them with `ScottDB::step_background_job`. Note that a
+    /// writer waiting for a pending flush blocks until another thread steps the queue.
+    Manual
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackgroundJobKind {
+    Flush,
+    /// Compaction of the given level into the next one
+    Compaction(usize)
+}
+
+/// A flush or compaction, see `ScottDB::background_jobs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackgroundJobInfo {
+    pub partition_id: u32,
+    pub kind: BackgroundJobKind,
+    /// Clock reading the job started running at, `None` while it is queued
+    pub start_micros: Option<u64>,
+    /// Bytes of tables the job has written so far
+    pub progress_bytes: u64
+}
+
+pub(crate) enum BackgroundJob<'a, Comp: 'static + Comparator> {
+    Flush(ArcPartition<'a, Comp>),
+    Compaction(ArcPartition<'a, Comp>, usize)
+}
+
+impl<'a, Comp: 'static + Comparator> BackgroundJob<'a, Comp> {
+    fn run(self) {
+        match self {
+            BackgroundJob::Flush(partition) => partition.compact_memtable(),
+            BackgroundJob::Compaction(partition, level) => partition.schedule_compaction(level)
+        }
+    }
+
+    fn partition(&self) -> &ArcPartition<'a, Comp> {
+        match self {
+            BackgroundJob::Flush(partition) | BackgroundJob::Compaction(partition, _) => partition
+        }
+    }
+
+    fn info(&self, start_micros: Option<u64>) -> BackgroundJobInfo {
+        let partition = self.partition();
+        let kind = match self {
+            BackgroundJob::Flush(_) => BackgroundJobKind::Flush,
+            BackgroundJob::Compaction(_, level) => BackgroundJobKind::Compaction(*level)
+        };
+        BackgroundJobInfo {
+            partition_id: partition.0.partition_id,
+            kind,
+            start_micros,
+            progress_bytes: if start_micros.is_some() { partition.job_progress(kind) } else { 0 }
+        }
+    }
+}
+
+/// A running job, with its partition and kind kept so that it can still be listed.
+struct RunningJob<'a, Comp: 'static + Comparator> {
+    id: u64,
+    job: BackgroundJob<'a, Comp>,
+    start_micros: u64
+}
+
+pub(crate) struct BackgroundScheduler<'a, Comp: 'static + Comparator> {
+    mode: BackgroundMode,
+    queue: Mutex<VecDeque<BackgroundJob<'a, Comp>>>,
+    running: Mutex<Vec<RunningJob<'a, Comp>>>,
+    // notified whenever `running` drains
+    idle: Condvar,
+    next_id: AtomicU64,
+    // nested pauses, only changed with `queue` locked
+    paused: AtomicUsize
+}
+
+impl<'a, Comp: 'static + Comparator> BackgroundScheduler<'a, Comp> {
+    pub(crate) fn new(mode: BackgroundMode) -> Self {
+        Self {
+            mode,
+            queue: Mutex::new(VecDeque::new()),
+            running: Mutex::new(Vec::new()),
+            idle: Condvar::new(),
+            next_id: AtomicU64::new(0),
+            paused: AtomicUsize::new(0)
+        }
+    }
+
+    pub(crate) fn schedule(&self, job: BackgroundJob<'a, Comp>) {
+        let mut queue = self.queue.lock().unwrap();
+        if self.mode == BackgroundMode::Manual || self.paused.load(Ordering::Relaxed) > 0 {
+            queue.push_back(job);
+            return;
+        }
+        drop(queue);
+        self.run(job);
+    }
+
+    /// Holds new jobs in the queue, then waits for the running ones to finish.
+    pub(crate) fn pause(&self) {
+        {
+            let _queue = self.queue.lock().unwrap();
+            self.paused.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut running = self.running.lock().unwrap();
+        while !running.is_empty() {
+            running = self.idle.wait(running).unwrap();
+        }
+    }
+
+    /// Undoes one `pause`. Once none are left, in `BackgroundMode::Inline` the jobs held
+    /// meanwhile run on this thread.
+    pub(crate) fn resume(&self) {
+        {
+            let _queue = self.queue.lock().unwrap();
+            let paused = self.paused.load(Ordering::Relaxed);
+            if paused == 0 {
+                return;
+            }
+            self.paused.store(paused - 1, Ordering::Relaxed);
+        }
+        if self.mode == BackgroundMode::Inline {
+            while self.step() {}
+        }
+    }
+
+    fn run(&self, job: BackgroundJob<'a, Comp>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let partition = job.partition().clone();
+        let start_micros = partition.0.options.clock.now_micros();
+        let listed = match &job {
+            BackgroundJob::Flush(_) => BackgroundJob::Flush(partition),
+            BackgroundJob::Compaction(_, level) => BackgroundJob::Compaction(partition, *level)
+        };
+        let kind = listed.info(None).kind;
+        listed.partition().reset_job_progress(kind);
+        self.running.lock().unwrap().push(RunningJob { id, job: listed, start_micros });
+        job.run();
+        let mut running = self.running.lock().unwrap();
+        running.retain(|running| running.id != id);
+        if running.is_empty() {
+            self.idle.notify_all();
+        }
+    }
+
+    /// The running jobs, then the queued ones in the order they will run.
+    pub(crate) fn jobs(&self) -> Vec<BackgroundJobInfo> {
+        let mut ret: Vec<BackgroundJobInfo> = self.running.lock().unwrap().iter()
+            .map(|running| running.job.info(Some(running.start_micros)))
+            .collect();
+        ret.extend(self.queue.lock().unwrap().iter().map(|job| job.info(None)));
+        ret
+    }
+
+    pub(crate) fn pending_jobs(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Runs the oldest queued job, returns `false` if there was none or background work is
+    /// paused.
+    pub(crate) fn step(&self) -> bool {
+        // the queue lock must not be held while running: the job may schedule follow-ups
+        let job = {
+            let mut queue = self.queue.lock().unwrap();
+            if self.paused.load(Ordering::Relaxed) > 0 { None } else { queue.pop_front() }
+        };
+        if let Some(job) = job {
+            self.run(job);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU64;
+
+    use crate::{Options, DefaultComparator};
+    use crate::io::IOManager;
+    use crate::io::mem::MemFileSystem;
+    use crate::statistics::Statistics;
+    use crate::table::cache::TableCacheManager;
+    use crate::partition::{Partition, ArcPartition, InternalKey, UserKey};
+    use crate::partition::scheduler::{BackgroundScheduler, BackgroundMode, BackgroundJobInfo, BackgroundJobKind};
+
+    #[test]
+    fn test_manual_stepping() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 4, 10, 4, 100, 16, 16);
+        options.file_system = fs.clone();
+        let seq = AtomicU64::new(0);
+        let cache_manager = TableCacheManager::new(4);
+        let io_manager = IOManager::new(4, fs.clone(), 0);
+        let statistics = Statistics::new();
+        let scheduler = BackgroundScheduler::new(BackgroundMode::Manual);
+        let partition = ArcPartition::new(
+            Partition::<DefaultComparator>::new(&options, 7, &seq, &cache_manager,
+                                                &io_manager, &statistics, &scheduler));
+
+        for i in 0..4u8 {
+            partition.write(InternalKey::new(i as u64, UserKey::new_owned(vec![i])), vec![i]).unwrap();
+        }
+        assert_eq!(scheduler.pending_jobs(), 1);
+        assert_eq!(scheduler.jobs(), vec![BackgroundJobInfo {
+            partition_id: 7,
+            kind: BackgroundJobKind::Flush,
+            start_micros: None,
+            progress_bytes: 0
+        }]);
+        assert!(partition.0.data.lock().unwrap().has_imm());
+        assert!(fs.file_names().is_empty());
+
+        // the flush, then the level 0 compaction check it schedules
+        assert!(scheduler.step());
+        assert_eq!(fs.file_names(), vec!["7_0_1.sst".to_string()]);
+        assert!(!partition.0.data.lock().unwrap().has_imm());
+        assert_eq!(scheduler.pending_jobs(), 1);
+        assert_eq!(scheduler.jobs()[0].kind, BackgroundJobKind::Compaction(0));
+        assert!(scheduler.step());
+        assert!(!scheduler.step());
+        assert!(scheduler.jobs().is_empty());
+    }
+
+    #[test]
+    fn test_pause() {
+        let fs = Arc::new(MemFileSystem::new());
+        let mut options = Options::new("test", 4, 4, 10, 4, 100, 16, 16);
+        options.file_system = fs.clone();
+        let seq = AtomicU64::new(0);
+        let cache_manager = TableCacheManager::new(4);
+        let io_manager = IOManager::new(4, fs.clone(), 0);
+        let statistics = Statistics::new();
+        let scheduler = BackgroundScheduler::new(BackgroundMode::Inline);
+        let partition = ArcPartition::new(
+            Partition::<DefaultComparator>::new(&options, 7, &seq, &cache_manager,
+                                                &io_manager, &statistics, &scheduler));
+
+        scheduler.pause();
+        scheduler.pause();
+        for i in 0..4u8 {
+            partition.write(InternalKey::new(i as u64, UserKey::new_owned(vec![i])), vec![i]).unwrap();
+        }
+        assert_eq!(scheduler.pending_jobs(), 1);
+        assert!(!scheduler.step());
+        assert!(fs.file_names().is_empty());
+
+        // the pauses nest
+        scheduler.resume();
+        assert_eq!(scheduler.pending_jobs(), 1);
+        scheduler.resume();
+        assert_eq!(scheduler.pending_jobs(), 0);
+        assert_eq!(fs.file_names(), vec!["7_0_1.sst".to_string()]);
+    }
+}
@@ -0,0 +1,105 @@
+//! Values larger than `Options::value_size_max`, written and read as streams. The value is cut
+//! into chunks of at most `value_size_max` bytes, each one a record of its own under a chunk
+//! key: the user key, a zero byte, the seq of the write and the chunk number. A head record
+//! under the user key itself says how many chunks there are, and is written last, so that
+//! readers never find a head without its chunks.
+//! ```raw
+//! +-HEAD---------------------------------------+
+//! | 4byte STREAM_HEAD_MAGIC | 4byte chunk count |
+//! | 8byte seq of the chunk keys                |
+//! +--------------------------------------------+
+//! ```
+
+use std::io::{ErrorKind, Read};
+
+use crate::Comparator;
+use crate::encode::{decode_fixed32, decode_fixed64, encode_fixed32_ret, encode_fixed64_ret};
+use crate::partition::ArcPartition;
+
+pub(crate) const STREAM_HEAD_MAGIC: &[u8] = b"SCST";
+pub(crate) const STREAM_HEAD_SIZE: usize = 16;
+/// What a chunk key adds to the user key.
+pub(crate) const STREAM_CHUNK_KEY_SUFFIX_SIZE: usize = 13;
+
+pub(crate) fn encode_head(seq: u64, chunk_count: u32) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(STREAM_HEAD_SIZE);
+    ret.extend_from_slice(STREAM_HEAD_MAGIC);
+    ret.extend_from_slice(&encode_fixed32_ret(chunk_count));
+    ret.extend_from_slice(&encode_fixed64_ret(seq));
+    ret
+}
+
+/// The seq and chunk count of a head, `None` for any other value.
+pub(crate) fn decode_head(value: &[u8]) -> Option<(u64, u32)> {
+    if value.len() != STREAM_HEAD_SIZE || &value[..4] != STREAM_HEAD_MAGIC {
+        return None
+    }
+    Some((decode_fixed64(&value[8..16]), decode_fixed32(&value[4..8])))
+}
+
+pub(crate) fn chunk_key(key: &[u8], seq: u64, chunk: u32) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(key.len() + STREAM_CHUNK_KEY_SUFFIX_SIZE);
+    ret.extend_from_slice(key);
+    ret.push(0);
+    ret.extend_from_slice(&encode_fixed64_ret(seq));
+    ret.extend_from_slice(&encode_fixed32_ret(chunk));
+    ret
+}
+
+/// Fills `buf` from `reader` as far as it goes, returns how much it filled.
+pub(crate) fn read_chunk(reader: &mut dyn Read, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(filled)
+}
+
+/// A value read chunk by chunk, see `ArcPartition::get_stream`. Only one chunk is held at a
+/// time. Chunks emptied by a later write of the key fail the read.
+pub(crate) struct ValueStream<'a, Comp: 'static + Comparator> {
+    partition: ArcPartition<'a, Comp>,
+    key: Vec<u8>,
+    seq: u64,
+    chunk_count: u32,
+    next_chunk: u32,
+    chunk: Vec<u8>,
+    chunk_off: usize
+}
+
+impl<'a, Comp: 'static + Comparator> ValueStream<'a, Comp> {
+    pub(crate) fn chunked(partition: ArcPartition<'a, Comp>, key: &[u8], seq: u64, chunk_count: u32) -> Self {
+        Self { partition, key: key.to_vec(), seq, chunk_count, next_chunk: 0, chunk: Vec::new(), chunk_off: 0 }
+    }
+
+    /// A value written in one record.
+    pub(crate) fn whole(partition: ArcPartition<'a, Comp>, value: Vec<u8>) -> Self {
+        Self { partition, key: Vec::new(), seq: 0, chunk_count: 0, next_chunk: 0, chunk: value, chunk_off: 0 }
+    }
+}
+
+impl<'a, Comp: 'static + Comparator> Read for ValueStream<'a, Comp> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        while self.chunk_off == self.chunk.len() {
+            if self.next_chunk == self.chunk_count {
+                return Ok(0)
+            }
+            self.chunk = match self.partition.get(&chunk_key(&self.key, self.seq, self.next_chunk)) {
+                Ok(Some(chunk)) if !chunk.is_empty() => chunk,
+                Ok(_) => return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "value overwritten while read")),
+                Err(e) => return Err(std::io::Error::new(ErrorKind::Other, format!("{:?}", e)))
+            };
+            self.chunk_off = 0;
+            self.next_chunk += 1;
+        }
+        let n = buf.len().min(self.chunk.len() - self.chunk_off);
+        buf[..n].copy_from_slice(&self.chunk[self.chunk_off..self.chunk_off + n]);
+        self.chunk_off += n;
+        Ok(n)
+    }
+}
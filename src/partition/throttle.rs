@@ -0,0 +1,62 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::clock::Clock;
+
+const MICROS_PER_SEC: i64 = 1_000_000;
+
+/// Lets through `rate` operations per second, in bursts of up to a second's worth. Tokens are
+/// counted in millionths, so that a microsecond refills `rate` of them.
+pub(crate) struct TokenBucket {
+    rate: i64,
+    // (tokens, clock reading they were counted at)
+    state: Mutex<(i64, u64)>
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u64, clock: &dyn Clock) -> Self {
+        let rate = rate.min(i64::MAX as u64 / MICROS_PER_SEC as u64) as i64;
+        Self { rate, state: Mutex::new((rate * MICROS_PER_SEC, clock.now_micros())) }
+    }
+
+    /// Takes a token, first sleeping until there is one if the bucket is empty. Returns
+    /// whether it had to.
+    pub(crate) fn acquire(&self, clock: &dyn Clock) -> bool {
+        let wait_micros = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = *state;
+            let now = clock.now_micros().max(last);
+            let refill = ((now - last).min(i64::MAX as u64) as i64).saturating_mul(self.rate);
+            // callers going into debt here queue up behind each other
+            let tokens = tokens.saturating_add(refill).min(self.rate * MICROS_PER_SEC) - MICROS_PER_SEC;
+            *state = (tokens, now);
+            if tokens >= 0 { 0 } else { (-tokens + self.rate - 1) / self.rate }
+        };
+        if wait_micros == 0 {
+            return false
+        }
+        clock.sleep(Duration::from_micros(wait_micros as u64));
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clock::{Clock, MockClock};
+    use crate::partition::throttle::TokenBucket;
+
+    #[test]
+    fn test_token_bucket() {
+        let clock = MockClock::new(0);
+        let bucket = TokenBucket::new(100, &clock);
+        // the first second's worth goes through at once
+        assert!((0..100).all(|_| !bucket.acquire(&clock)));
+        assert_eq!(clock.now_micros(), 0);
+        assert!(bucket.acquire(&clock));
+        assert_eq!(clock.now_micros(), 10_000);
+        for _ in 0..99 {
+            bucket.acquire(&clock);
+        }
+        assert_eq!(clock.now_micros(), 1_000_000);
+    }
+}
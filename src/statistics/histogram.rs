@@ -0,0 +1,245 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::encode::{encode_fixed32_ret, decode_fixed32};
+
+/// Log-linear buckets: values below `SUB_BUCKET_COUNT` get one bucket each, every following
+/// power of two is split into `SUB_BUCKET_COUNT` equal buckets, so the relative error of a
+/// reported value stays under 1 / SUB_BUCKET_COUNT.
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKET_COUNT: usize = 1 << SUB_BUCKET_BITS;
+const BUCKET_COUNT: usize = SUB_BUCKET_COUNT + (64 - SUB_BUCKET_BITS as usize) * SUB_BUCKET_COUNT;
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKET_COUNT as u64 {
+        return value as usize;
+    }
+    let exp = 63 - value.leading_zeros();
+    let sub = (value >> (exp - SUB_BUCKET_BITS)) as usize - SUB_BUCKET_COUNT;
+    SUB_BUCKET_COUNT + (exp - SUB_BUCKET_BITS) as usize * SUB_BUCKET_COUNT + sub
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKET_COUNT {
+        return index as u64;
+    }
+    let shift = ((index - SUB_BUCKET_COUNT) / SUB_BUCKET_COUNT) as u32;
+    let sub = ((index - SUB_BUCKET_COUNT) % SUB_BUCKET_COUNT) as u64;
+    let lower = (SUB_BUCKET_COUNT as u64 + sub) << shift;
+    lower + ((1u64 << shift) - 1)
+}
+
+/// Lock-free latency histogram, recorded in microseconds.
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        let mut buckets = Vec::with_capacity(BUCKET_COUNT);
+        buckets.resize_with(BUCKET_COUNT, || AtomicU64::new(0));
+        Self {
+            buckets,
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0)
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        self.buckets[bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.min.fetch_min(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn record_duration(&self, duration: Duration) {
+        self.record(duration.as_micros() as u64)
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let buckets: Vec<u64> = self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        // concurrent recorders may have bumped a bucket but not yet `count`, so derive the total
+        // from the buckets themselves to keep percentiles consistent
+        let count: u64 = buckets.iter().sum();
+        let min = self.min.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+        let percentile = |p: f64| -> u64 {
+            if count == 0 {
+                return 0;
+            }
+            let rank = ((p * count as f64).ceil() as u64).max(1);
+            let mut seen = 0u64;
+            for (index, bucket_count) in buckets.iter().enumerate() {
+                seen += bucket_count;
+                if seen >= rank {
+                    return bucket_upper_bound(index).min(max).max(min);
+                }
+            }
+            max
+        };
+        HistogramSnapshot {
+            count,
+            sum: self.sum.load(Ordering::Relaxed),
+            min: if count == 0 { 0 } else { min },
+            max,
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            p999: percentile(0.999)
+        }
+    }
+}
+
+/// Point-in-time view of a `Histogram`; all values are in microseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub p999: u64
+}
+
+impl HistogramSnapshot {
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+}
+
+pub const SIZE_HISTOGRAM_BUCKETS: usize = 24;
+
+/// Key or value sizes by power of two, small enough to go into every table footer: bucket
+/// `n` counts the sizes of `n` bits, up to `2^n - 1`, and the last one the larger sizes too.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    pub buckets: [u64; SIZE_HISTOGRAM_BUCKETS]
+}
+
+impl SizeHistogram {
+    pub const ENCODED_SIZE: usize = SIZE_HISTOGRAM_BUCKETS * 4;
+
+    pub(crate) fn add(&mut self, size: usize) {
+        let bits = 64 - (size as u64).leading_zeros() as usize;
+        self.buckets[bits.min(SIZE_HISTOGRAM_BUCKETS - 1)] += 1;
+    }
+
+    pub fn merge(&mut self, other: &SizeHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The largest size of the bucket the `p` quantile falls into, 0 if there are no
+    /// sizes. Sizes of the last bucket are reported as its smallest one.
+    pub fn percentile(&self, p: f64) -> u64 {
+        let count = self.count();
+        if count == 0 {
+            return 0;
+        }
+        let rank = ((p * count as f64).ceil() as u64).max(1);
+        let mut seen = 0u64;
+        for (index, bucket_count) in self.buckets.iter().enumerate() {
+            seen += bucket_count;
+            if seen >= rank && index < SIZE_HISTOGRAM_BUCKETS - 1 {
+                return (1u64 << index) - 1;
+            }
+        }
+        1u64 << (SIZE_HISTOGRAM_BUCKETS - 2)
+    }
+
+    pub(crate) fn serialize(&self, dest: &mut Vec<u8>) {
+        for count in self.buckets.iter() {
+            dest.extend_from_slice(&encode_fixed32_ret(*count as u32));
+        }
+    }
+
+    pub(crate) fn decode(raw: &[u8]) -> Self {
+        debug_assert_eq!(raw.len(), Self::ENCODED_SIZE);
+        let mut ret = Self::default();
+        for (bucket, raw) in ret.buckets.iter_mut().zip(raw.chunks(4)) {
+            *bucket = decode_fixed32(raw) as u64;
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{thread_rng, Rng};
+    use crate::statistics::histogram::{Histogram, SizeHistogram, SIZE_HISTOGRAM_BUCKETS, bucket_index, bucket_upper_bound, BUCKET_COUNT};
+
+    #[test]
+    fn test_bucket_bounds() {
+        assert_eq!(bucket_index(u64::MAX), BUCKET_COUNT - 1);
+        assert_eq!(bucket_upper_bound(BUCKET_COUNT - 1), u64::MAX);
+        for _ in 1..1024 {
+            let value = thread_rng().gen_range(0, 0xFFFFFFFFFFu64);
+            let index = bucket_index(value);
+            assert!(bucket_upper_bound(index) >= value);
+            assert!(index == 0 || bucket_upper_bound(index - 1) < value);
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.snapshot().p99, 0);
+        for value in 1..=10000u64 {
+            histogram.record(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 10000);
+        assert_eq!(snapshot.min, 1);
+        assert_eq!(snapshot.max, 10000);
+        assert!((snapshot.mean() - 5000.5).abs() < 1e-9);
+        for &(reported, expected) in [(snapshot.p50, 5000u64), (snapshot.p95, 9500),
+                                      (snapshot.p99, 9900), (snapshot.p999, 9990)].iter() {
+            assert!(reported >= expected);
+            assert!(reported <= expected + expected / 16);
+        }
+    }
+
+    #[test]
+    fn test_size_histogram() {
+        let mut histogram = SizeHistogram::default();
+        for size in [0, 1, 3, 4, 100, 100, 100, 1 << 30].iter() {
+            histogram.add(*size);
+        }
+        assert_eq!(&histogram.buckets[..8], &[1, 1, 1, 1, 0, 0, 0, 3]);
+        assert_eq!(histogram.buckets[SIZE_HISTOGRAM_BUCKETS - 1], 1);
+        assert_eq!(histogram.percentile(0.5), 7);
+        assert_eq!(histogram.percentile(0.75), 127);
+        assert_eq!(histogram.percentile(1.0), 1 << (SIZE_HISTOGRAM_BUCKETS - 2));
+
+        let mut raw = Vec::new();
+        histogram.serialize(&mut raw);
+        assert_eq!(raw.len(), SizeHistogram::ENCODED_SIZE);
+        let mut merged = SizeHistogram::decode(&raw);
+        merged.merge(&histogram);
+        assert_eq!(merged.count(), 16);
+    }
+}
@@ -0,0 +1,193 @@
+pub(crate) mod histogram;
+#[cfg(feature = "metrics")]
+pub(crate) mod prometheus;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::clock::Clock;
+
+pub use histogram::{Histogram, HistogramSnapshot, SizeHistogram, SIZE_HISTOGRAM_BUCKETS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistogramType {
+    Get,
+    Put,
+    Flush,
+    Compaction
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ticker {
+    RowCacheHit,
+    RowCacheMiss,
+    /// Reads of a table that failed on a corrupt block, after the retries
+    CorruptTableRead,
+    /// Bytes of the keys and values written
+    UserBytesWritten,
+    /// Bytes of the tables written by flushes and by compactions
+    FlushBytesWritten,
+    CompactionBytesWritten,
+    /// Writes rejected because of `Options::reserved_disk_bytes`
+    WriteNoSpace,
+    /// Reads and writes that waited for their partition's rate limit
+    ReadThrottled,
+    WriteThrottled,
+    /// Compactions aborted by `Options::paranoid_compaction_checks`
+    CompactionCorrupt,
+    /// Writes delayed and stopped by too many tables in their partition's level 0
+    WriteSlowdown,
+    WriteStop
+}
+
+pub struct Statistics {
+    get: Histogram,
+    put: Histogram,
+    flush: Histogram,
+    compaction: Histogram,
+    row_cache_hit: AtomicU64,
+    row_cache_miss: AtomicU64,
+    corrupt_table_read: AtomicU64,
+    user_bytes_written: AtomicU64,
+    flush_bytes_written: AtomicU64,
+    compaction_bytes_written: AtomicU64,
+    write_no_space: AtomicU64,
+    read_throttled: AtomicU64,
+    write_throttled: AtomicU64,
+    compaction_corrupt: AtomicU64,
+    write_slowdown: AtomicU64,
+    write_stop: AtomicU64
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self {
+            get: Histogram::new(),
+            put: Histogram::new(),
+            flush: Histogram::new(),
+            compaction: Histogram::new(),
+            row_cache_hit: AtomicU64::new(0),
+            row_cache_miss: AtomicU64::new(0),
+            corrupt_table_read: AtomicU64::new(0),
+            user_bytes_written: AtomicU64::new(0),
+            flush_bytes_written: AtomicU64::new(0),
+            compaction_bytes_written: AtomicU64::new(0),
+            write_no_space: AtomicU64::new(0),
+            read_throttled: AtomicU64::new(0),
+            write_throttled: AtomicU64::new(0),
+            compaction_corrupt: AtomicU64::new(0),
+            write_slowdown: AtomicU64::new(0),
+            write_stop: AtomicU64::new(0)
+        }
+    }
+
+    pub fn histogram(&self, histogram_type: HistogramType) -> &Histogram {
+        match histogram_type {
+            HistogramType::Get => &self.get,
+            HistogramType::Put => &self.put,
+            HistogramType::Flush => &self.flush,
+            HistogramType::Compaction => &self.compaction
+        }
+    }
+
+    fn ticker_counter(&self, ticker: Ticker) -> &AtomicU64 {
+        match ticker {
+            Ticker::RowCacheHit => &self.row_cache_hit,
+            Ticker::RowCacheMiss => &self.row_cache_miss,
+            Ticker::CorruptTableRead => &self.corrupt_table_read,
+            Ticker::UserBytesWritten => &self.user_bytes_written,
+            Ticker::FlushBytesWritten => &self.flush_bytes_written,
+            Ticker::CompactionBytesWritten => &self.compaction_bytes_written,
+            Ticker::WriteNoSpace => &self.write_no_space,
+            Ticker::ReadThrottled => &self.read_throttled,
+            Ticker::WriteThrottled => &self.write_throttled,
+            Ticker::CompactionCorrupt => &self.compaction_corrupt,
+            Ticker::WriteSlowdown => &self.write_slowdown,
+            Ticker::WriteStop => &self.write_stop
+        }
+    }
+
+    pub fn ticker(&self, ticker: Ticker) -> u64 {
+        self.ticker_counter(ticker).load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_tick(&self, ticker: Ticker) {
+        self.record_ticks(ticker, 1);
+    }
+
+    pub(crate) fn record_ticks(&self, ticker: Ticker, count: u64) {
+        self.ticker_counter(ticker).fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Records the microseconds from `start`, a reading of `clock`, until now.
+    pub(crate) fn record_since(&self, histogram_type: HistogramType, clock: &dyn Clock, start: u64) {
+        self.histogram(histogram_type).record(clock.now_micros().saturating_sub(start))
+    }
+
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        StatisticsSnapshot {
+            get: self.get.snapshot(),
+            put: self.put.snapshot(),
+            flush: self.flush.snapshot(),
+            compaction: self.compaction.snapshot(),
+            row_cache_hits: self.ticker(Ticker::RowCacheHit),
+            row_cache_misses: self.ticker(Ticker::RowCacheMiss),
+            corrupt_table_reads: self.ticker(Ticker::CorruptTableRead),
+            user_bytes_written: self.ticker(Ticker::UserBytesWritten),
+            flush_bytes_written: self.ticker(Ticker::FlushBytesWritten),
+            compaction_bytes_written: self.ticker(Ticker::CompactionBytesWritten),
+            writes_no_space: self.ticker(Ticker::WriteNoSpace),
+            reads_throttled: self.ticker(Ticker::ReadThrottled),
+            writes_throttled: self.ticker(Ticker::WriteThrottled),
+            compactions_corrupt: self.ticker(Ticker::CompactionCorrupt),
+            writes_slowed_down: self.ticker(Ticker::WriteSlowdown),
+            writes_stopped: self.ticker(Ticker::WriteStop)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatisticsSnapshot {
+    pub get: HistogramSnapshot,
+    pub put: HistogramSnapshot,
+    pub flush: HistogramSnapshot,
+    pub compaction: HistogramSnapshot,
+    pub row_cache_hits: u64,
+    pub row_cache_misses: u64,
+    pub corrupt_table_reads: u64,
+    pub user_bytes_written: u64,
+    pub flush_bytes_written: u64,
+    pub compaction_bytes_written: u64,
+    pub writes_no_space: u64,
+    pub reads_throttled: u64,
+    pub writes_throttled: u64,
+    pub compactions_corrupt: u64,
+    pub writes_slowed_down: u64,
+    pub writes_stopped: u64
+}
+
+impl StatisticsSnapshot {
+    /// Share of row cache lookups that hit, 0 before the first lookup.
+    pub fn row_cache_hit_rate(&self) -> f64 {
+        let lookups = self.row_cache_hits + self.row_cache_misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.row_cache_hits as f64 / lookups as f64
+        }
+    }
+
+    /// Bytes written to tables per byte of keys and values written, 0 before the first write.
+    pub fn write_amplification(&self) -> f64 {
+        if self.user_bytes_written == 0 {
+            0.0
+        } else {
+            (self.flush_bytes_written + self.compaction_bytes_written) as f64 / self.user_bytes_written as f64
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Prometheus text exposition (format 0.0.4) of the statistics subsystem.
+
+use std::fmt::Write;
+
+use crate::statistics::{HistogramSnapshot, StatisticsSnapshot};
+
+pub(crate) struct PartitionGauges {
+    pub(crate) partition_id: u32,
+    pub(crate) memtable_bytes: usize,
+    pub(crate) has_imm: bool,
+    pub(crate) level_tables: Vec<usize>
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, metric_type: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+fn write_summary(out: &mut String, db: &str, name: &str, help: &str, snapshot: &HistogramSnapshot) {
+    write_header(out, name, help, "summary");
+    for &(quantile, value) in [("0.5", snapshot.p50), ("0.95", snapshot.p95),
+                               ("0.99", snapshot.p99), ("0.999", snapshot.p999)].iter() {
+        writeln!(out, "{}{{db=\"{}\",quantile=\"{}\"}} {}", name, db, quantile, value).unwrap();
+    }
+    writeln!(out, "{}_sum{{db=\"{}\"}} {}", name, db, snapshot.sum).unwrap();
+    writeln!(out, "{}_count{{db=\"{}\"}} {}", name, db, snapshot.count).unwrap();
+}
+
+pub(crate) fn render(db_name: &str, snapshot: &StatisticsSnapshot, partitions: &[PartitionGauges]) -> String {
+    let db = escape_label(db_name);
+    let mut out = String::new();
+
+    write_summary(&mut out, &db, "pr65_get_latency_microseconds",
+                  "Latency of get operations.", &snapshot.get);
+    write_summary(&mut out, &db, "pr65_put_latency_microseconds",
+                  "Latency of put operations.", &snapshot.put);
+    write_summary(&mut out, &db, "pr65_flush_latency_microseconds",
+                  "Latency of memtable flushes.", &snapshot.flush);
+    write_summary(&mut out, &db, "pr65_compaction_latency_microseconds",
+                  "Latency of compactions.", &snapshot.compaction);
+
+    write_header(&mut out, "pr65_row_cache_hits_total", "Row cache lookups that found the key.", "counter");
+    writeln!(out, "pr65_row_cache_hits_total{{db=\"{}\"}} {}", db, snapshot.row_cache_hits).unwrap();
+    write_header(&mut out, "pr65_row_cache_misses_total", "Row cache lookups that did not.", "counter");
+    writeln!(out, "pr65_row_cache_misses_total{{db=\"{}\"}} {}", db, snapshot.row_cache_misses).unwrap();
+    write_header(&mut out, "pr65_corrupt_table_reads_total", "Table reads that hit a corrupt block.", "counter");
+    writeln!(out, "pr65_corrupt_table_reads_total{{db=\"{}\"}} {}", db, snapshot.corrupt_table_reads).unwrap();
+    write_header(&mut out, "pr65_bytes_written_total", "Bytes written, by what wrote them.", "counter");
+    for (source, bytes) in [("user", snapshot.user_bytes_written),
+                            ("flush", snapshot.flush_bytes_written),
+                            ("compaction", snapshot.compaction_bytes_written)].iter() {
+        writeln!(out, "pr65_bytes_written_total{{db=\"{}\",source=\"{}\"}} {}", db, source, bytes).unwrap();
+    }
+
+    write_header(&mut out, "pr65_memtable_bytes", "Estimated memory used by the active memtable.", "gauge");
+    for p in partitions {
+        writeln!(out, "pr65_memtable_bytes{{db=\"{}\",partition=\"{}\"}} {}",
+                 db, p.partition_id, p.memtable_bytes).unwrap();
+    }
+    write_header(&mut out, "pr65_immutable_memtables", "Number of memtables waiting for flush.", "gauge");
+    for p in partitions {
+        writeln!(out, "pr65_immutable_memtables{{db=\"{}\",partition=\"{}\"}} {}",
+                 db, p.partition_id, p.has_imm as u32).unwrap();
+    }
+    write_header(&mut out, "pr65_level_tables", "Number of table files in a level.", "gauge");
+    for p in partitions {
+        for (level, count) in p.level_tables.iter().enumerate() {
+            writeln!(out, "pr65_level_tables{{db=\"{}\",partition=\"{}\",level=\"{}\"}} {}",
+                     db, p.partition_id, level, count).unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use crate::statistics::Statistics;
+    use crate::statistics::prometheus::{render, PartitionGauges};
+
+    #[test]
+    fn test_render() {
+        let statistics = Statistics::new();
+        statistics.histogram(crate::HistogramType::Put).record(42);
+        statistics.record_tick(crate::Ticker::RowCacheHit);
+        let partitions = vec![
+            PartitionGauges { partition_id: 3, memtable_bytes: 1024, has_imm: true, level_tables: vec![4, 1] }
+        ];
+        let text = render("db\"1", &statistics.snapshot(), &partitions);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE pr65_put_latency_microseconds summary"));
+        assert!(lines.contains(&"pr65_put_latency_microseconds{db=\"db\\\"1\",quantile=\"0.99\"} 42"));
+        assert!(lines.contains(&"pr65_put_latency_microseconds_count{db=\"db\\\"1\"} 1"));
+        assert!(lines.contains(&"pr65_get_latency_microseconds_count{db=\"db\\\"1\"} 0"));
+        assert!(lines.contains(&"pr65_row_cache_hits_total{db=\"db\\\"1\"} 1"));
+        assert!(lines.contains(&"pr65_memtable_bytes{db=\"db\\\"1\",partition=\"3\"} 1024"));
+        assert!(lines.contains(&"pr65_immutable_memtables{db=\"db\\\"1\",partition=\"3\"} 1"));
+        assert!(lines.contains(&"pr65_level_tables{db=\"db\\\"1\",partition=\"3\",level=\"1\"} 1"));
+    }
+}
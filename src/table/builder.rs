@@ -1,64 +1,313 @@
 use crc::crc32;
 
-use crate::table::tablefmt::{TABLE_MAGIC, TABLE_MIN_SIZE, TABLE_CATALOG_ITEM_SIZE};
+use crate::table::tablefmt::{TABLE_MAGIC, TABLE_MIN_SIZE, TABLE_CATALOG_ITEM_SIZE, TABLE_FOOTER_SIZE,
+                             TABLE_INDEX_ITEM_HEAD_SIZE, TABLE_DEFAULT_BLOCK_SIZE,
+                             TABLE_DEFAULT_FILTER_BITS_PER_KEY, TABLE_HASH_INDEX_EMPTY,
+                             TABLE_HASH_INDEX_COLLISION, TABLE_DELETION_BITMASK};
 use crate::encode::{encode_fixed32_ret, encode_fixed32};
 use crate::table::cache::ScTableCatalogItem;
+use crate::table::filter::{filter_key, key_hash, FilterKeys, FilterPolicy, TableFilter};
+use crate::table::index::{ScTableIndexItem, ScTableFooter};
+use crate::statistics::SizeHistogram;
 
-pub(crate) struct ScTableBuilder {
+/// Bucket count of the hash index of a block holding `key_count` distinct user keys, `None`
+/// if there are too many keys to address.
+fn hash_index_buckets(key_count: usize, catalog_size: usize) -> Option<usize> {
+    if catalog_size >= TABLE_HASH_INDEX_COLLISION as usize {
+        None
+    } else {
+        // a load factor of 0.75
+        Some(key_count * 4 / 3 + 1)
+    }
+}
+
+pub(crate) fn hash_index_bucket(key: &[u8], buckets: usize) -> usize {
+    key_hash(key) as u32 as usize % buckets
+}
+
+fn encode_hash_index(catalog: &[ScTableCatalogItem], data: &[u8], dest: &mut Vec<u8>) {
+    let key = |item: &ScTableCatalogItem| &data[item.key_off as usize..(item.key_off + item.key_len) as usize];
+    let first_versions: Vec<usize> = (0..catalog.len())
+        .filter(|&i| i == 0 || key(&catalog[i - 1]) != key(&catalog[i]))
+        .collect();
+    let buckets = match hash_index_buckets(first_versions.len(), catalog.len()) {
+        Some(buckets) => buckets,
+        None => return
+    };
+    let mut index = vec![TABLE_HASH_INDEX_EMPTY; buckets];
+    for i in first_versions {
+        let bucket = &mut index[hash_index_bucket(key(&catalog[i]), buckets)];
+        *bucket = if *bucket == TABLE_HASH_INDEX_EMPTY { i as u16 } else { TABLE_HASH_INDEX_COLLISION };
+    }
+    let index_off = dest.len();
+    for bucket in index {
+        dest.extend_from_slice(&bucket.to_le_bytes());
+    }
+    let index_crc = crc32::checksum_ieee(&dest[index_off..]);
+    dest.extend_from_slice(&encode_fixed32_ret(buckets as u32));
+    dest.extend_from_slice(&encode_fixed32_ret(index_crc));
+}
+
+/// Lays out a block from its catalog and data, see `tablefmt`.
+pub(crate) fn encode_block(catalog: &[ScTableCatalogItem], data: &[u8], hash_index: bool) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(TABLE_MIN_SIZE + catalog.len() * TABLE_CATALOG_ITEM_SIZE + data.len());
+    ret.extend_from_slice(&encode_fixed32_ret((catalog.len() * TABLE_CATALOG_ITEM_SIZE) as u32));
+    ret.extend_from_slice(&encode_fixed32_ret(data.len() as u32));
+    for _ in 0..4 {
+        ret.push(0)
+    }
+    ret.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(data)));
+    for index in catalog.iter() {
+        index.serialize(&mut ret)
+    }
+    ret.extend_from_slice(data);
+    let index_checksum = crc32::checksum_ieee(&ret[16..16 + catalog.len() * TABLE_CATALOG_ITEM_SIZE]);
+    encode_fixed32(&mut ret[8..12], index_checksum);
+    if hash_index {
+        encode_hash_index(catalog, data, &mut ret);
+    }
+    ret.extend_from_slice(TABLE_MAGIC);
+    ret
+}
+
+pub(crate) struct ScBlockBuilder {
     indexes: Vec<ScTableCatalogItem>,
-    data: Vec<u8>
+    data: Vec<u8>,
+    hash_index: bool,
+    key_count: usize
 }
 
-impl Default for ScTableBuilder {
+impl Default for ScBlockBuilder {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl ScTableBuilder {
+impl ScBlockBuilder {
     pub(crate) fn new() -> Self {
-        Self { indexes: Vec::new(), data: Vec::new() }
+        Self::with_hash_index(false)
+    }
+
+    /// `hash_index` adds an index from the hash of every user key to its first catalog item.
+    pub(crate) fn with_hash_index(hash_index: bool) -> Self {
+        Self { indexes: Vec::new(), data: Vec::new(), hash_index, key_count: 0 }
     }
 
     pub(crate) fn add_kv(&mut self, key_seq: u64, key: &[u8], value: &[u8]) {
+        self.add_entry(key_seq, key, Some(value))
+    }
+
+    /// Records that `key` was deleted at `key_seq`, hiding its older versions from reads.
+    pub(crate) fn add_deletion(&mut self, key_seq: u64, key: &[u8]) {
+        self.add_entry(key_seq, key, None)
+    }
+
+    /// `value == None` adds a deletion.
+    pub(crate) fn add_entry(&mut self, key_seq: u64, key: &[u8], value: Option<&[u8]>) {
+        if self.is_empty() || self.last_key().1 != key {
+            self.key_count += 1;
+        }
         let key_off = self.data.len() as u32;
         let key_size = key.len() as u32;
         self.data.extend_from_slice(key);
 
-        let value_off = self.data.len() as u32;
-        let value_size = value.len() as u32;
-        self.data.extend_from_slice(value);
+        let (value_off, value_size) = match value {
+            Some(value) => {
+                let value_off = self.data.len() as u32;
+                self.data.extend_from_slice(value);
+                (value_off, value.len() as u32)
+            },
+            None => (TABLE_DELETION_BITMASK, 0)
+        };
 
         self.indexes.push(ScTableCatalogItem::new(key_seq, key_off, key_size, value_off, value_size));
     }
 
+    pub(crate) fn build(&self) -> Vec<u8> {
+        encode_block(&self.indexes, &self.data, self.hash_index)
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        let mut size = TABLE_MIN_SIZE + self.indexes.len() * TABLE_CATALOG_ITEM_SIZE + self.data.len();
+        if self.hash_index {
+            size += hash_index_buckets(self.key_count, self.indexes.len()).map_or(0, |buckets| buckets * 2 + 8);
+        }
+        size
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    fn last_key(&self) -> (u64, &[u8]) {
+        let last = self.indexes.last().unwrap();
+        (last.key_seq, &self.data[last.key_off as usize..(last.key_off + last.key_len) as usize])
+    }
+
+    fn index_item(&self, block_off: usize, block_size: usize) -> ScTableIndexItem {
+        let (last_seq, last_key) = self.last_key();
+        ScTableIndexItem::new(block_off as u32, block_size as u32, last_seq, last_key.to_vec())
+    }
+}
+
+pub(crate) struct ScTableBuilder {
+    block_size: usize,
+    filter_policy: FilterPolicy,
+    filter_keys: FilterKeys,
+    filter_bits_per_key: usize,
+    hash_index: bool,
+    block: ScBlockBuilder,
+    blocks: Vec<u8>,
+    index: Vec<ScTableIndexItem>,
+    key_hashes: Vec<u64>,
+    seq_range: Option<(u64, u64)>,
+    entry_count: usize,
+    key_count: usize,
+    data_size: u64,
+    key_sizes: SizeHistogram,
+    value_sizes: SizeHistogram
+}
+
+impl Default for ScTableBuilder {
+    fn default() -> Self {
+        Self::new(TABLE_DEFAULT_BLOCK_SIZE, FilterPolicy::Bloom, FilterKeys::WholeKey, TABLE_DEFAULT_FILTER_BITS_PER_KEY)
+    }
+}
+
+impl ScTableBuilder {
+    /// `filter_bits_per_key == 0` builds tables without a filter, like `FilterKeys::Disabled`.
+    pub(crate) fn new(block_size: usize,
+                      filter_policy: FilterPolicy,
+                      filter_keys: FilterKeys,
+                      filter_bits_per_key: usize) -> Self {
+        let filter_bits_per_key = if filter_keys == FilterKeys::Disabled { 0 } else { filter_bits_per_key };
+        Self {
+            block_size,
+            filter_policy,
+            filter_keys,
+            filter_bits_per_key,
+            hash_index: false,
+            block: ScBlockBuilder::new(),
+            blocks: Vec::new(),
+            index: Vec::new(),
+            key_hashes: Vec::new(),
+            seq_range: None,
+            entry_count: 0,
+            key_count: 0,
+            data_size: 0,
+            key_sizes: SizeHistogram::default(),
+            value_sizes: SizeHistogram::default()
+        }
+    }
+
+    /// Gives every block a hash index, see `ScBlockBuilder::with_hash_index`.
+    pub(crate) fn with_block_hash_index(mut self, hash_index: bool) -> Self {
+        self.hash_index = hash_index;
+        self.block = ScBlockBuilder::with_hash_index(hash_index);
+        self
+    }
+
+    pub(crate) fn add_kv(&mut self, key_seq: u64, key: &[u8], value: &[u8]) {
+        self.add_entry(key_seq, key, Some(value))
+    }
+
+    /// See `ScBlockBuilder::add_deletion`.
+    pub(crate) fn add_deletion(&mut self, key_seq: u64, key: &[u8]) {
+        self.add_entry(key_seq, key, None)
+    }
+
+    /// `value == None` adds a deletion.
+    pub(crate) fn add_entry(&mut self, key_seq: u64, key: &[u8], value: Option<&[u8]>) {
+        if self.filter_bits_per_key != 0 {
+            let hash = key_hash(filter_key(key, self.filter_keys.prefix_len()));
+            // keys come sorted, every prefix and every key with several versions is added
+            // only once
+            if self.key_hashes.last() != Some(&hash) {
+                self.key_hashes.push(hash);
+            }
+        }
+        self.seq_range = Some(match self.seq_range {
+            Some((min_seq, max_seq)) => (min_seq.min(key_seq), max_seq.max(key_seq)),
+            None => (key_seq, key_seq)
+        });
+        let last_key = if self.block.is_empty() {
+            self.index.last().map(|item| item.last_key.as_slice())
+        } else {
+            Some(self.block.last_key().1)
+        };
+        if last_key != Some(key) {
+            self.key_count += 1;
+        }
+        self.entry_count += 1;
+        self.data_size += (key.len() + value.map_or(0, |value| value.len())) as u64;
+        self.key_sizes.add(key.len());
+        if let Some(value) = value {
+            self.value_sizes.add(value.len());
+        }
+        self.block.add_entry(key_seq, key, value);
+        if self.block.size() >= self.block_size {
+            let block = self.block.build();
+            self.index.push(self.block.index_item(self.blocks.len(), block.len()));
+            self.blocks.extend_from_slice(&block);
+            self.block = ScBlockBuilder::with_hash_index(self.hash_index);
+        }
+    }
+
     pub(crate) fn build(&self) -> Vec<u8> {
         let mut ret = Vec::with_capacity(self.size());
-        ret.extend_from_slice(&encode_fixed32_ret((self.indexes.len() * TABLE_CATALOG_ITEM_SIZE) as u32));
-        ret.extend_from_slice(&encode_fixed32_ret(self.data.len() as u32));
-        for _ in 0..4 {
-            ret.push(0)
-        }
-        ret.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&self.data)));
-        for index in self.indexes.iter() {
-            index.serialize(&mut ret)
-        }
-        ret.extend_from_slice(&self.data);
-        let index_checksum = crc32::checksum_ieee(&ret[16..16 + self.indexes.len() * TABLE_CATALOG_ITEM_SIZE]);
-        encode_fixed32(&mut ret[8..12], index_checksum);
-        ret.extend_from_slice(TABLE_MAGIC);
+        ret.extend_from_slice(&self.blocks);
+        let mut last_index_item = None;
+        if !self.block.is_empty() {
+            let block = self.block.build();
+            last_index_item.replace(self.block.index_item(ret.len(), block.len()));
+            ret.extend_from_slice(&block);
+        }
+
+        let filter_off = ret.len();
+        let (mut filter_policy, mut filter_prefix_len) = (0, 0);
+        if self.filter_bits_per_key != 0 {
+            filter_prefix_len = self.filter_keys.prefix_len();
+            TableFilter::build(self.filter_policy, filter_prefix_len, &self.key_hashes, self.filter_bits_per_key)
+                .serialize(&mut ret);
+            filter_policy = self.filter_policy.code();
+        }
+        let filter_size = ret.len() - filter_off;
+        let filter_crc = if filter_size == 0 { 0 } else { crc32::checksum_ieee(&ret[filter_off..]) };
+
+        let index_off = ret.len();
+        for index_item in self.index.iter().chain(last_index_item.iter()) {
+            index_item.serialize(&mut ret)
+        }
+        let index_size = ret.len() - index_off;
+        let index_crc = crc32::checksum_ieee(&ret[index_off..]);
+        let (min_seq, max_seq) = self.seq_range.unwrap_or((0, 0));
+        ScTableFooter {
+            index_off, index_size, index_crc, filter_size, filter_crc, filter_policy, filter_prefix_len, min_seq, max_seq,
+            entry_count: self.entry_count, data_size: self.data_size, global_seq: 0, key_count: self.key_count,
+            key_sizes: self.key_sizes, value_sizes: self.value_sizes
+        }.serialize(&mut ret);
         ret
     }
 
     pub(crate) fn size(&self) -> usize {
-        TABLE_MIN_SIZE + self.indexes.len() * TABLE_CATALOG_ITEM_SIZE + self.data.len()
+        let index_size: usize = self.index.iter().map(|item| TABLE_INDEX_ITEM_HEAD_SIZE + item.last_key.len()).sum();
+        let mut size = self.blocks.len() + index_size + TABLE_FOOTER_SIZE;
+        if self.filter_bits_per_key != 0 {
+            size += TableFilter::serialized_size(self.filter_policy, self.key_hashes.len(), self.filter_bits_per_key);
+        }
+        if !self.block.is_empty() {
+            size += self.block.size() + TABLE_INDEX_ITEM_HEAD_SIZE + self.block.last_key().1.len();
+        }
+        size
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::table::builder::ScTableBuilder;
+    use crate::table::builder::{ScBlockBuilder, ScTableBuilder};
     use crate::table::cache::{ScTableCache, TableCacheManager};
+    use crate::table::filter::{FilterKeys, FilterPolicy, TableFilter};
+    use crate::table::index::{ScTableFooter, ScTableIndex};
 
     #[test]
     fn test_builder_1() {
@@ -73,7 +322,7 @@ mod test {
             (0x40490fd0fffffffeu64, "尾声".as_bytes(), "".as_bytes()),
         ];
 
-        let mut builder = ScTableBuilder::new();
+        let mut builder = ScBlockBuilder::new();
         for &(seq, key, value) in data.iter() {
             builder.add_kv(seq, key, value);
         }
@@ -87,7 +336,75 @@ mod test {
             let (seq1, key1, value1) = table.nth_item(i);
             assert_eq!(seq1, seq);
             assert_eq!(key1, key);
-            assert_eq!(value1, value);
+            assert_eq!(value1, Some(value));
+        }
+    }
+
+    fn check_table_blocks(filter_policy: FilterPolicy) {
+        // every tenth key is a deletion
+        let data: Vec<(u64, Vec<u8>, Option<Vec<u8>>)> = (0..100u64)
+            .map(|i| (i, format!("key{:03}", i).into_bytes(),
+                      Some(format!("value{}", i).into_bytes()).filter(|_| i % 10 != 0)))
+            .collect();
+
+        let mut builder = ScTableBuilder::new(256, filter_policy, FilterKeys::WholeKey, 10);
+        for (seq, key, value) in data.iter() {
+            builder.add_entry(*seq, key, value.as_deref());
+        }
+        let buffer = builder.build();
+        assert_eq!(buffer.len(), builder.size());
+
+        let index = ScTableIndex::from_table(&buffer).unwrap();
+        assert!(index.block_count() > 1);
+        let cache_manager = TableCacheManager::new(index.block_count());
+        let mut expected = data.iter();
+        for n in 0..index.block_count() {
+            let item = index.nth_block(n);
+            let raw = &buffer[item.block_off as usize..(item.block_off + item.block_size) as usize];
+            let block = ScTableCache::from_raw(raw, cache_manager.acquire_quota()).unwrap();
+            for i in 0..block.catalog_size() {
+                let (seq, key, value) = expected.next().unwrap();
+                assert_eq!(block.nth_item(i), (*seq, key.as_slice(), value.as_deref()));
+            }
+            let (last_seq, last_key, _) = block.nth_item(block.catalog_size() - 1);
+            assert_eq!((item.last_seq, item.last_key.as_slice()), (last_seq, last_key));
         }
+        assert!(expected.next().is_none());
+
+        let footer = ScTableFooter::decode(&buffer).unwrap();
+        assert_eq!((footer.min_seq, footer.max_seq), (0, 99));
+        let data_size: usize = data.iter().map(|(_, key, value)| key.len() + value.as_ref().map_or(0, Vec::len)).sum();
+        assert_eq!((footer.entry_count, footer.key_count, footer.data_size), (100, 100, data_size as u64));
+        // keys of 6 bytes and values of 6 or 7, all of 3 bits; deletions have no value
+        assert_eq!((footer.key_sizes.buckets[3], footer.value_sizes.buckets[3]), (100, 90));
+        assert_eq!((footer.key_sizes.count(), footer.value_sizes.count()), (100, 90));
+
+        let filter = TableFilter::from_table(&buffer).unwrap().unwrap();
+        assert!(data.iter().all(|(_, key, _)| filter.may_contain(key)));
+        let no_filter = ScTableBuilder::new(256, filter_policy, FilterKeys::WholeKey, 0).build();
+        assert!(TableFilter::from_table(&no_filter).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_builder_blocks() {
+        check_table_blocks(FilterPolicy::Bloom);
+        check_table_blocks(FilterPolicy::Ribbon);
+    }
+
+    #[test]
+    fn test_prefix_filter() {
+        let mut builder = ScTableBuilder::new(256, FilterPolicy::Bloom, FilterKeys::Prefix(4), 10);
+        for i in 0..100u64 {
+            builder.add_kv(i, format!("u{:03}:{}", i / 10, i).as_bytes(), b"value");
+        }
+        let buffer = builder.build();
+        assert_eq!(buffer.len(), builder.size());
+        let filter = TableFilter::from_table(&buffer).unwrap().unwrap();
+        // anything sharing a prefix with a key in the table may be in it
+        assert!(filter.may_contain(b"u003:absent"));
+        assert!((100..200).filter(|i| filter.may_contain(format!("u{:03}:0", i).as_bytes())).count() < 20);
+
+        let disabled = ScTableBuilder::new(256, FilterPolicy::Bloom, FilterKeys::Disabled, 10).build();
+        assert!(TableFilter::from_table(&disabled).unwrap().is_none());
     }
 }
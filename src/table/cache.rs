@@ -1,18 +1,24 @@
-use std::sync::{Arc, Mutex};
-use std::ptr::NonNull;
-use std_semaphore::Semaphore;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
 
 use lru::LruCache;
 use crc::crc32;
 
-use crate::table::sctable::ScTableFile;
+use crate::table::sctable::ScBlockId;
+use crate::table::builder::{encode_block, hash_index_bucket};
+use crate::table::secondary::SecondaryCache;
+use crate::table::TableGet;
 
 use crate::table::tablefmt::{TABLE_MIN_SIZE, TABLE_MAGIC_SIZE, TABLE_MAGIC, TABLE_CATALOG_ITEM_SIZE,
-                             TABLE_HEAD_SIZE, TABLE_MAX_SIZE, TABLE_DELETION_BITMASK};
+                             TABLE_HEAD_SIZE, TABLE_MAX_SIZE, TABLE_DELETION_BITMASK, TABLE_HASH_INDEX_EMPTY,
+                             TABLE_HASH_INDEX_COLLISION};
 use crate::encode::{encode_fixed32_ret, decode_fixed32, decode_fixed64, encode_fixed64_ret};
 use crate::error::Error;
 use crate::Comparator;
-use crate::partition::{InternalKey, UserKey};
+use crate::partition::LookupKey;
 
 pub(crate) struct ScTableCatalogItem {
     pub(crate) key_seq: u64,
@@ -50,6 +56,8 @@ impl ScTableCatalogItem {
 pub(crate) struct ScTableCache {
     catalog: Vec<ScTableCatalogItem>,
     data: Vec<u8>,
+    // empty if the block has no hash index
+    hash_index: Vec<u16>,
     quota: CacheQuota
 }
 
@@ -72,9 +80,12 @@ impl ScTableCache {
             return Err(Error::sc_table_corrupt("catalog size should be multiplication of 16".into()))
         }
 
-        if (kv_catalog_size + data_size + TABLE_MIN_SIZE) != raw.len() {
+        if (kv_catalog_size + data_size + TABLE_MIN_SIZE) > raw.len() {
             return Err(Error::sc_table_corrupt("incorrect table size".into()))
         }
+        let hash_index_off = TABLE_HEAD_SIZE + kv_catalog_size + data_size;
+        let hash_index = Self::decode_hash_index(&raw[hash_index_off..raw.len() - TABLE_MAGIC_SIZE],
+                                                 kv_catalog_size / TABLE_CATALOG_ITEM_SIZE)?;
 
         let kv_catalog_crc = decode_fixed32(&raw[8..12]);
         let data_crc = decode_fixed32(&raw[12..16]);
@@ -95,41 +106,92 @@ impl ScTableCache {
             let base = i * TABLE_CATALOG_ITEM_SIZE;
             let index =
                 ScTableCatalogItem::deserialize(&kv_catalog[base..base + TABLE_CATALOG_ITEM_SIZE]);
-            if index.value_off & TABLE_DELETION_BITMASK != 0 {
-            } else if (index.key_off + index.key_len) as usize > data.len()
-                      || (index.value_off + index.value_len) as usize > data.len() {
+            if (index.key_off as usize + index.key_len as usize) > data.len()
+               || (index.value_off & TABLE_DELETION_BITMASK == 0
+                   && (index.value_off as usize + index.value_len as usize) > data.len()) {
                 return Err(Error::sc_table_corrupt("incorrect key/value catalog data".into()))
             }
             catalog_item.push(index)
         }
 
-        Ok(Self { catalog: catalog_item, data: data.to_vec(), quota })
+        Ok(Self { catalog: catalog_item, data: data.to_vec(), hash_index, quota })
     }
 
-    pub(crate) fn get<Comp: Comparator>(&self, key: &InternalKey<Comp>) -> Option<Vec<u8>> {
-        if let Ok(idx) = self.catalog.binary_search_by(
-            |catalog_item| {
-                let seq = catalog_item.key_seq;
-                let user_key = self.key(catalog_item);
-                let lookup_key = InternalKey::new(seq, UserKey::new_borrow(user_key));
-                // TODO this is buggy.
-                key.cmp(&lookup_key)
-            }) {
-            if self.catalog[idx].value_off & TABLE_DELETION_BITMASK != 0 {
-                None
-            } else {
-                Some(self.value(&self.catalog[idx]).to_vec())
+    fn decode_hash_index(raw: &[u8], catalog_size: usize) -> Result<Vec<u16>, Error> {
+        if raw.is_empty() {
+            return Ok(Vec::new())
+        }
+        if raw.len() < 8 {
+            return Err(Error::sc_table_corrupt("incorrect table size".into()))
+        }
+        let buckets = decode_fixed32(&raw[raw.len() - 8..raw.len() - 4]) as usize;
+        let raw_buckets = &raw[..raw.len() - 8];
+        if buckets == 0 || buckets * 2 != raw_buckets.len() {
+            return Err(Error::sc_table_corrupt("incorrect hash index size".into()))
+        }
+        if crc32::checksum_ieee(raw_buckets) != decode_fixed32(&raw[raw.len() - 4..]) {
+            return Err(Error::sc_table_corrupt("incorrect hash index crc".into()))
+        }
+        let hash_index: Vec<u16> = raw_buckets.chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect();
+        if hash_index.iter().any(|&i| i < TABLE_HASH_INDEX_COLLISION && i as usize >= catalog_size) {
+            return Err(Error::sc_table_corrupt("incorrect hash index data".into()))
+        }
+        Ok(hash_index)
+    }
+
+    /// Where the binary search of `get` would end up, `None` if the hash index cannot tell.
+    fn hash_index_seek<Comp: Comparator>(&self, key: &LookupKey<Comp>) -> Option<usize> {
+        if self.hash_index.is_empty() {
+            return None
+        }
+        let user_key = key.user_key;
+        match self.hash_index[hash_index_bucket(user_key, self.hash_index.len())] {
+            TABLE_HASH_INDEX_EMPTY => Some(self.catalog.len()),
+            TABLE_HASH_INDEX_COLLISION => None,
+            first => {
+                let first = first as usize;
+                if self.key(&self.catalog[first]) != user_key {
+                    return Some(self.catalog.len())
+                }
+                // skip the versions newer than `key.seq`
+                let skip = self.catalog[first..].iter()
+                    .take_while(|item| self.key(item) == user_key && self.is_before(item, key))
+                    .count();
+                Some(first + skip)
             }
-        } else {
-            None
         }
     }
 
+    /// The newest version of `key.user_key` not newer than `key.seq`.
+    pub(crate) fn get<Comp: Comparator>(&self, key: &LookupKey<Comp>) -> TableGet {
+        let idx = self.hash_index_seek(key)
+            .unwrap_or_else(|| self.catalog.partition_point(|catalog_item| self.is_before(catalog_item, key)));
+        match self.catalog.get(idx) {
+            Some(catalog_item) if Comp::compare(self.key(catalog_item), key.user_key) == Ordering::Equal =>
+                match self.value(catalog_item) {
+                    Some(value) => TableGet::Found(value.to_vec()),
+                    None => TableGet::Deleted
+                },
+            _ => TableGet::Absent
+        }
+    }
+
+    fn is_before<Comp: Comparator>(&self, catalog_item: &ScTableCatalogItem, key: &LookupKey<Comp>) -> bool {
+        let lookup_key = LookupKey::new(catalog_item.key_seq, self.key(catalog_item));
+        lookup_key.cmp(key) == Ordering::Less
+    }
+
+    /// The block in its on-disk format again.
+    pub(crate) fn to_raw(&self) -> Vec<u8> {
+        encode_block(&self.catalog, &self.data, !self.hash_index.is_empty())
+    }
+
     pub(crate) fn catalog_size(&self) -> usize {
         self.catalog.len()
     }
 
-    pub(crate) fn nth_item(&self, n: usize) -> (u64, &[u8], &[u8]) {
+    /// The value is `None` for a deletion.
+    pub(crate) fn nth_item(&self, n: usize) -> (u64, &[u8], Option<&[u8]>) {
         assert!(n < self.catalog_size());
         let catalog_item = &self.catalog[n];
         (catalog_item.key_seq, self.key(catalog_item), self.value(catalog_item))
@@ -139,58 +201,508 @@ impl ScTableCache {
         &self.data[catalog_item.key_off as usize .. (catalog_item.key_off + catalog_item.key_len) as usize]
     }
 
-    fn value(&self, catalog_item: &ScTableCatalogItem) -> &[u8] {
-        &self.data[catalog_item.value_off as usize .. (catalog_item.value_off + catalog_item.value_len) as usize]
+    fn value(&self, catalog_item: &ScTableCatalogItem) -> Option<&[u8]> {
+        if catalog_item.value_off & TABLE_DELETION_BITMASK != 0 {
+            return None
+        }
+        Some(&self.data[catalog_item.value_off as usize .. (catalog_item.value_off + catalog_item.value_len) as usize])
     }
 }
 
 pub(crate) struct CacheQuota {
-    cache_manager: NonNull<TableCacheManager>
+    // the cache and the index of the shard the quota goes back to, `None` for blocks decoded
+    // outside of the cache budget, which are never cached. Weak, since the cache holds the
+    // blocks holding the quotas
+    shard: Option<(Weak<BlockCache>, usize)>
 }
 
 impl CacheQuota {
-    fn new(cache_manager: &TableCacheManager) -> Self {
-        Self { cache_manager: unsafe { NonNull::new_unchecked(cache_manager as *const TableCacheManager as _) } }
+    fn new(cache: &Arc<BlockCache>, shard: usize) -> Self {
+        Self { shard: Some((Arc::downgrade(cache), shard)) }
+    }
+
+    /// A quota for a block that is only needed for a moment and must not push anything out of
+    /// the cache, like compaction inputs or blocks refused by the admission policy.
+    pub(crate) fn transient() -> Self {
+        Self { shard: None }
+    }
+
+    fn is_transient(&self) -> bool {
+        self.shard.is_none()
     }
 }
 
 impl Drop for CacheQuota {
     fn drop(&mut self) {
-        unsafe { self.cache_manager.as_ref().on_cache_released() }
+        if let Some((cache, shard)) = &self.shard {
+            // a cache already gone has no budget left to give back to
+            if let Some(cache) = cache.upgrade() {
+                cache.shards[*shard].on_cache_released()
+            }
+        }
     }
 }
 
-pub(crate) struct TableCacheManager {
-    lru: Mutex<LruCache<ScTableFile, Arc<ScTableCache>>>,
-    sem: Semaphore
+/// Decides which blocks get into a full block cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheAdmission {
+    /// Every block read is cached, evicting the least recently used one.
+    Lru,
+    /// A block only replaces the least recently used one if it has been accessed more often
+    /// recently, so a single pass over many cold blocks cannot flush the hot ones.
+    TinyLfu
 }
 
-/// Warning: make sure all `CacheQuota`s are dropped before the `TableCacheManager` drops.
-/// Maybe we should mark the TableCacheManager to be `unsafe`.
-impl TableCacheManager {
-    pub(crate) fn new(cache_count: usize) -> Self {
-        TableCacheManager {
+/// Count-min sketch of recent block accesses with 4-bit counters, halved every `sample_size`
+/// increments so that old popularity fades.
+struct FrequencySketch {
+    table: Vec<u8>,
+    width: usize,
+    additions: usize,
+    sample_size: usize
+}
+
+impl FrequencySketch {
+    const DEPTH: usize = 4;
+    const MAX_COUNT: u8 = 15;
+
+    fn new(capacity: usize) -> Self {
+        let width = (capacity.max(1) * 4).next_power_of_two();
+        Self { table: vec![0; width * Self::DEPTH], width, additions: 0, sample_size: capacity.max(1) * 10 }
+    }
+
+    fn slots(&self, key: &CacheKey) -> [usize; FrequencySketch::DEPTH] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash as usize, (hash >> 32) as usize | 1);
+        let mut ret = [0; FrequencySketch::DEPTH];
+        for (row, slot) in ret.iter_mut().enumerate() {
+            *slot = row * self.width + (h1.wrapping_add(row.wrapping_mul(h2)) & (self.width - 1));
+        }
+        ret
+    }
+
+    fn increment(&mut self, key: &CacheKey) {
+        for slot in self.slots(key).iter() {
+            if self.table[*slot] < Self::MAX_COUNT {
+                self.table[*slot] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            for counter in self.table.iter_mut() {
+                *counter /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency(&self, key: &CacheKey) -> u8 {
+        self.slots(key).iter().map(|slot| self.table[*slot]).min().unwrap()
+    }
+}
+
+// blocks of different databases sharing a cache are told apart by the owner
+pub(crate) type CacheKey = (u64, ScBlockId);
+
+/// Occupancy and hit rate of one shard of a `BlockCache`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheShardStats {
+    pub capacity: usize,
+    pub occupancy: usize,
+    pub hits: u64,
+    pub misses: u64
+}
+
+impl CacheShardStats {
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            0.0
+        } else {
+            self.hits as f64 / (self.hits + self.misses) as f64
+        }
+    }
+}
+
+/// A slice of the budget of a `BlockCache` with its own lock, holding the blocks hashing to it.
+struct CacheShard {
+    lru: Mutex<LruCache<CacheKey, Arc<ScTableCache>>>,
+    sketch: Option<Mutex<FrequencySketch>>,
+    // quotas not handed out yet
+    available: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+impl CacheShard {
+    fn new(cache_count: usize, admission: CacheAdmission) -> Self {
+        Self {
             lru: Mutex::new(LruCache::new(cache_count)),
-            sem: Semaphore::new(cache_count as isize)
+            sketch: match admission {
+                CacheAdmission::Lru => None,
+                CacheAdmission::TinyLfu => Some(Mutex::new(FrequencySketch::new(cache_count)))
+            },
+            available: AtomicUsize::new(cache_count),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
         }
     }
 
-    pub(crate) fn acquire_quota(&self) -> CacheQuota {
-        self.sem.acquire();
-        CacheQuota::new(self)
+    fn try_acquire(&self) -> bool {
+        self.available.fetch_update(atomic::Ordering::SeqCst, atomic::Ordering::SeqCst,
+                                    |available| available.checked_sub(1)).is_ok()
+    }
+
+    fn on_cache_released(&self) {
+        self.available.fetch_add(1, atomic::Ordering::SeqCst);
+    }
+}
+
+/// A block cache with a fixed budget of `cache_count` blocks. It can be shared by several
+/// databases through `Options::block_cache`, which then split that budget between them.
+pub struct BlockCache {
+    shards: Vec<CacheShard>,
+    next_owner: AtomicU64,
+    secondary: Option<SecondaryCache>
+}
+
+impl BlockCache {
+    pub fn new(cache_count: usize, admission: CacheAdmission) -> Self {
+        Self::with_secondary(cache_count, admission, None)
+    }
+
+    /// Evicted blocks go to `secondary`, if any, and are looked up there on misses.
+    pub fn with_secondary(cache_count: usize, admission: CacheAdmission, secondary: Option<SecondaryCache>) -> Self {
+        Self::sharded(cache_count, 1, admission, secondary)
+    }
+
+    /// Splits the budget into `shard_count` shards with a lock each, so that concurrent
+    /// readers of different blocks rarely wait for one another. Every shard gets at least one
+    /// block.
+    pub fn sharded(cache_count: usize,
+                   shard_count: usize,
+                   admission: CacheAdmission,
+                   secondary: Option<SecondaryCache>) -> Self {
+        let shard_count = shard_count.clamp(1, cache_count.max(1));
+        let shards = (0..shard_count)
+            .map(|i| CacheShard::new(cache_count / shard_count + (i < cache_count % shard_count) as usize, admission))
+            .collect();
+        Self { shards, next_owner: AtomicU64::new(0), secondary }
+    }
+
+    pub fn shard_stats(&self) -> Vec<CacheShardStats> {
+        self.shards.iter().map(|shard| {
+            let lru = shard.lru.lock().unwrap();
+            CacheShardStats {
+                capacity: lru.cap(),
+                occupancy: lru.len(),
+                hits: shard.hits.load(atomic::Ordering::Relaxed),
+                misses: shard.misses.load(atomic::Ordering::Relaxed)
+            }
+        }).collect()
+    }
+
+    fn shard_index(&self, key: &CacheKey) -> usize {
+        if self.shards.len() == 1 {
+            return 0
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+
+    fn shard(&self, key: &CacheKey) -> &CacheShard {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn acquire_quota(self: &Arc<Self>) -> CacheQuota {
+        self.acquire_shard_quota(0)
+    }
+
+    /// Evicts blocks until a quota is free. An evicted block still read elsewhere keeps its
+    /// quota, once none are left to evict the block gets a transient one instead of waiting.
+    fn acquire_shard_quota(self: &Arc<Self>, shard_index: usize) -> CacheQuota {
+        let shard = &self.shards[shard_index];
+        loop {
+            if shard.try_acquire() {
+                return CacheQuota::new(self, shard_index)
+            }
+            let evicted = shard.lru.lock().unwrap().pop_lru();
+            match evicted {
+                // only handed over here, the file is written by the secondary cache's own thread
+                Some((key, block)) => if let Some(secondary) = &self.secondary {
+                    secondary.insert(key, block.to_raw());
+                },
+                None => return CacheQuota::transient()
+            }
+        }
     }
 
-    pub(crate) fn add_cache(&self, table_file: ScTableFile, table_cache: ScTableCache) -> Arc<ScTableCache> {
+    fn acquire_quota_for(self: &Arc<Self>, key: CacheKey) -> CacheQuota {
+        let shard_index = self.shard_index(&key);
+        let shard = &self.shards[shard_index];
+        if let Some(sketch) = &shard.sketch {
+            let lru = shard.lru.lock().unwrap();
+            if lru.len() >= lru.cap() {
+                let sketch = sketch.lock().unwrap();
+                if let Some((victim, _)) = lru.peek_lru() {
+                    if sketch.frequency(victim) >= sketch.frequency(&key) {
+                        return CacheQuota::transient()
+                    }
+                }
+            }
+        }
+        self.acquire_shard_quota(shard_index)
+    }
+
+    fn add_cache(&self, key: CacheKey, table_cache: ScTableCache) -> Arc<ScTableCache> {
         let ret = Arc::new(table_cache);
-        self.lru.lock().unwrap().put(table_file, ret.clone());
+        if !ret.quota.is_transient() {
+            self.shard(&key).lru.lock().unwrap().put(key, ret.clone());
+        }
         ret
     }
 
-    pub(crate) fn get_cache(&self, table_file: ScTableFile) -> Option<Arc<ScTableCache>> {
-        self.lru.lock().unwrap().get(&table_file).and_then(|arc| Some(arc.clone()))
+    fn get_cache(&self, key: CacheKey) -> Option<Arc<ScTableCache>> {
+        let shard = self.shard(&key);
+        if let Some(sketch) = &shard.sketch {
+            sketch.lock().unwrap().increment(&key);
+        }
+        let ret = shard.lru.lock().unwrap().get(&key).cloned();
+        let counter = if ret.is_some() { &shard.hits } else { &shard.misses };
+        counter.fetch_add(1, atomic::Ordering::Relaxed);
+        ret
     }
 
-    fn on_cache_released(&self) {
-        self.sem.release()
+    fn remove_owner(&self, owner: u64) {
+        for shard in self.shards.iter() {
+            let mut lru = shard.lru.lock().unwrap();
+            let keys: Vec<CacheKey> = lru.iter().map(|(key, _)| *key).filter(|(o, _)| *o == owner).collect();
+            for key in keys.iter() {
+                lru.pop(key);
+            }
+        }
+        if let Some(secondary) = &self.secondary {
+            secondary.remove_owner(owner);
+        }
+    }
+}
+
+/// One database's view of a `BlockCache`, possibly shared with other databases.
+pub(crate) struct TableCacheManager {
+    cache: Arc<BlockCache>,
+    owner: u64
+}
+
+impl TableCacheManager {
+    pub(crate) fn new(cache_count: usize) -> Self {
+        Self::with_admission(cache_count, CacheAdmission::Lru)
+    }
+
+    pub(crate) fn with_admission(cache_count: usize, admission: CacheAdmission) -> Self {
+        Self::with_shards(cache_count, 1, admission)
+    }
+
+    pub(crate) fn with_shards(cache_count: usize, shard_count: usize, admission: CacheAdmission) -> Self {
+        Self::shared(Arc::new(BlockCache::sharded(cache_count, shard_count, admission, None)))
+    }
+
+    pub(crate) fn shared(cache: Arc<BlockCache>) -> Self {
+        let owner = cache.next_owner.fetch_add(1, atomic::Ordering::SeqCst);
+        Self { cache, owner }
+    }
+
+    pub(crate) fn acquire_quota(&self) -> CacheQuota {
+        self.cache.acquire_quota()
+    }
+
+    /// Like `acquire_quota`, but for block `block_id` that just missed the cache. Returns a
+    /// transient quota if the admission policy prefers what would have to be evicted for it.
+    pub(crate) fn acquire_quota_for(&self, block_id: ScBlockId) -> CacheQuota {
+        self.cache.acquire_quota_for((self.owner, block_id))
+    }
+
+    pub(crate) fn add_cache(&self, block_id: ScBlockId, table_cache: ScTableCache) -> Arc<ScTableCache> {
+        self.cache.add_cache((self.owner, block_id), table_cache)
+    }
+
+    pub(crate) fn get_cache(&self, block_id: ScBlockId) -> Option<Arc<ScTableCache>> {
+        self.cache.get_cache((self.owner, block_id))
+    }
+
+    pub(crate) fn shard_stats(&self) -> Vec<CacheShardStats> {
+        self.cache.shard_stats()
+    }
+
+    /// The raw block from the secondary cache, to be tried after a miss in this cache.
+    pub(crate) fn get_secondary(&self, block_id: ScBlockId) -> Option<Vec<u8>> {
+        self.cache.secondary.as_ref()?.get((self.owner, block_id))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn wait_secondary_written(&self) {
+        if let Some(secondary) = &self.cache.secondary {
+            secondary.wait_written();
+        }
+    }
+}
+
+impl Drop for TableCacheManager {
+    fn drop(&mut self) {
+        // nobody is going to look these up again, give their budget back to the other owners
+        self.cache.remove_owner(self.owner);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::table::builder::ScBlockBuilder;
+    use std::sync::Arc;
+
+    use crate::table::cache::{BlockCache, CacheAdmission, ScTableCache, TableCacheManager};
+    use crate::table::sctable::{ScBlockId, ScTableFile};
+    use crate::table::TableGet;
+    use crate::partition::LookupKey;
+    use crate::DefaultComparator;
+
+    fn read_block(cache_manager: &TableCacheManager, block_id: ScBlockId, raw: &[u8]) {
+        if cache_manager.get_cache(block_id).is_none() {
+            let block = ScTableCache::from_raw(raw, cache_manager.acquire_quota_for(block_id)).unwrap();
+            cache_manager.add_cache(block_id, block);
+        }
+    }
+
+    fn hot_blocks_survive_scan(admission: CacheAdmission) -> bool {
+        let mut builder = ScBlockBuilder::new();
+        builder.add_kv(1, b"key", b"value");
+        let raw = builder.build();
+        let block_id = |n| ScBlockId::new(ScTableFile::new(0, 0, n), 0);
+
+        let cache_manager = TableCacheManager::with_admission(2, admission);
+        for _ in 0..5 {
+            read_block(&cache_manager, block_id(1), &raw);
+            read_block(&cache_manager, block_id(2), &raw);
+        }
+        for n in 3..13 {
+            read_block(&cache_manager, block_id(n), &raw);
+        }
+        cache_manager.get_cache(block_id(1)).is_some() && cache_manager.get_cache(block_id(2)).is_some()
+    }
+
+    #[test]
+    fn test_admission() {
+        assert!(!hot_blocks_survive_scan(CacheAdmission::Lru));
+        assert!(hot_blocks_survive_scan(CacheAdmission::TinyLfu));
+    }
+
+    #[test]
+    fn test_shared_cache() {
+        let mut builder = ScBlockBuilder::new();
+        builder.add_kv(1, b"key", b"value");
+        let raw = builder.build();
+        let block_id = ScBlockId::new(ScTableFile::new(0, 0, 1), 0);
+
+        let cache = Arc::new(BlockCache::new(2, CacheAdmission::Lru));
+        let db1 = TableCacheManager::shared(cache.clone());
+        let db2 = TableCacheManager::shared(cache.clone());
+        // the same file names in different databases are different blocks
+        read_block(&db1, block_id, &raw);
+        assert!(db2.get_cache(block_id).is_none());
+        read_block(&db2, block_id, &raw);
+        assert!(db1.get_cache(block_id).is_some() && db2.get_cache(block_id).is_some());
+
+        // the budget is shared, and handed back when a database goes away
+        drop(db1);
+        assert_eq!(cache.shard_stats()[0].occupancy, 1);
+        let db3 = TableCacheManager::shared(cache.clone());
+        read_block(&db3, block_id, &raw);
+        assert!(db2.get_cache(block_id).is_some() && db3.get_cache(block_id).is_some());
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let cache_manager = TableCacheManager::new(2);
+        let blocks: Vec<ScTableCache> = [false, true].iter().map(|&hash_index| {
+            let mut builder = ScBlockBuilder::with_hash_index(hash_index);
+            for i in 0..50u64 {
+                if i % 5 == 0 {
+                    builder.add_deletion(30, format!("key{:02}", i).as_bytes());
+                }
+                builder.add_kv(20, format!("key{:02}", i).as_bytes(), b"new");
+                builder.add_kv(10, format!("key{:02}", i).as_bytes(), b"old");
+            }
+            let raw = builder.build();
+            assert_eq!(raw.len(), builder.size());
+            let block = ScTableCache::from_raw(&raw, cache_manager.acquire_quota()).unwrap();
+            assert_eq!(block.to_raw(), raw);
+            block
+        }).collect();
+        assert!(blocks[0].hash_index.is_empty() && !blocks[1].hash_index.is_empty());
+
+        // the hash index finds exactly what the binary search does
+        for i in 0..60u64 {
+            let key = format!("key{:02}", i);
+            for &seq in [5, 10, 15, 25, 35].iter() {
+                let lookup = LookupKey::<DefaultComparator>::new(seq, key.as_bytes());
+                assert_eq!(blocks[1].get(&lookup), blocks[0].get(&lookup));
+            }
+        }
+        let lookup = LookupKey::<DefaultComparator>::new(15, b"key07");
+        assert_eq!(blocks[1].get(&lookup), TableGet::Found(b"old".to_vec()));
+        let lookup = LookupKey::<DefaultComparator>::new(35, b"key05");
+        assert_eq!(blocks[1].get(&lookup), TableGet::Deleted);
+        let lookup = LookupKey::<DefaultComparator>::new(25, b"key05");
+        assert_eq!(blocks[1].get(&lookup), TableGet::Found(b"new".to_vec()));
+        let lookup = LookupKey::<DefaultComparator>::new(35, b"key55");
+        assert_eq!(blocks[1].get(&lookup), TableGet::Absent);
+    }
+
+    #[test]
+    fn test_sharded_cache() {
+        let mut builder = ScBlockBuilder::new();
+        builder.add_kv(1, b"key", b"value");
+        let raw = builder.build();
+        let block_id = |n| ScBlockId::new(ScTableFile::new(0, 0, n), 0);
+
+        let cache_manager = TableCacheManager::with_shards(10, 4, CacheAdmission::Lru);
+        for n in 0..40 {
+            read_block(&cache_manager, block_id(n), &raw);
+            read_block(&cache_manager, block_id(n), &raw);
+        }
+        let stats = cache_manager.shard_stats();
+        assert_eq!(stats.iter().map(|shard| shard.capacity).collect::<Vec<usize>>(), vec![3, 3, 2, 2]);
+        assert!(stats.iter().all(|shard| shard.occupancy == shard.capacity && shard.hits > 0));
+        assert_eq!(stats.iter().map(|shard| shard.hits + shard.misses).sum::<u64>(), 80);
+        assert_eq!(stats.iter().map(|shard| shard.hits).sum::<u64>(), 40);
+    }
+
+    #[test]
+    fn test_quota_in_use() {
+        let mut builder = ScBlockBuilder::new();
+        builder.add_kv(1, b"key", b"value");
+        let raw = builder.build();
+        let block_id = |n| ScBlockId::new(ScTableFile::new(0, 0, n), 0);
+
+        let cache_manager = TableCacheManager::new(2);
+        read_block(&cache_manager, block_id(1), &raw);
+        read_block(&cache_manager, block_id(2), &raw);
+        // readers still hold both blocks after they were evicted, nothing is left to evict
+        let in_use: Vec<_> = (1..=2).map(|n| cache_manager.get_cache(block_id(n)).unwrap()).collect();
+        read_block(&cache_manager, block_id(3), &raw);
+        read_block(&cache_manager, block_id(4), &raw);
+        assert!(!in_use[0].quota.is_transient() && cache_manager.get_cache(block_id(3)).is_none());
+        assert_eq!(cache_manager.shard_stats()[0].occupancy, 0);
+
+        // a block given back frees its quota
+        drop(in_use);
+        read_block(&cache_manager, block_id(3), &raw);
+        assert!(cache_manager.get_cache(block_id(3)).is_some());
+
+        // a block may outlive its cache
+        let block = cache_manager.get_cache(block_id(3)).unwrap();
+        drop(cache_manager);
+        assert!(!block.quota.is_transient());
+        drop(block);
     }
 }
@@ -0,0 +1,455 @@
+use crc::crc32;
+
+use crate::table::index::ScTableFooter;
+use crate::encode::{encode_fixed32_ret, encode_fixed64_ret, decode_fixed32, decode_fixed64};
+use crate::error::Error;
+
+/// How table filters summarize their user keys, recorded in the table footer so that tables
+/// built with different policies can be read side by side.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FilterPolicy {
+    Bloom,
+    /// Roughly 30% smaller than a bloom filter with the same false positive rate, at the
+    /// cost of slower construction.
+    Ribbon
+}
+
+impl FilterPolicy {
+    pub(crate) fn code(self) -> u32 {
+        match self {
+            FilterPolicy::Bloom => 1,
+            FilterPolicy::Ribbon => 2
+        }
+    }
+
+    fn from_code(code: u32) -> Option<FilterPolicy> {
+        match code {
+            1 => Some(FilterPolicy::Bloom),
+            2 => Some(FilterPolicy::Ribbon),
+            _ => None
+        }
+    }
+}
+
+/// Which part of each user key the filter of a table is built over. Lookups of keys shorter
+/// than a prefix check the whole key.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FilterKeys {
+    Disabled,
+    WholeKey,
+    Prefix(usize)
+}
+
+impl FilterKeys {
+    /// The length recorded in the table footer, 0 for whole keys.
+    pub(crate) fn prefix_len(self) -> usize {
+        match self {
+            FilterKeys::Prefix(len) => len,
+            _ => 0
+        }
+    }
+}
+
+/// The part of `key` a filter with `prefix_len` summarizes.
+pub(crate) fn filter_key(key: &[u8], prefix_len: usize) -> &[u8] {
+    if prefix_len == 0 {
+        key
+    } else {
+        &key[..prefix_len.min(key.len())]
+    }
+}
+
+fn murmur(key: &[u8], seed: u32) -> u32 {
+    // the murmur-like hash leveldb uses for its bloom filters
+    const M: u32 = 0xc6a4a793;
+    let mut h = seed ^ (key.len() as u32).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(4);
+    for chunk in &mut chunks {
+        let w = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        h = h.wrapping_add(w).wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &byte) in rest.iter().enumerate() {
+            h = h.wrapping_add((byte as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
+
+/// The hash every filter is built from, collected by `ScTableBuilder` for each key.
+pub(crate) fn key_hash(key: &[u8]) -> u64 {
+    ((murmur(key, 0xbc9f1d34) as u64) << 32) | murmur(key, 0x9747b28c) as u64
+}
+
+fn mix64(mut z: u64) -> u64 {
+    // splitmix64 finalizer
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+enum FilterData {
+    Bloom(BloomFilter),
+    Ribbon(RibbonFilter)
+}
+
+pub(crate) struct TableFilter {
+    data: FilterData,
+    prefix_len: usize
+}
+
+impl TableFilter {
+    /// `key_hashes` are the `key_hash` of the `filter_key` of every key.
+    pub(crate) fn build(policy: FilterPolicy, prefix_len: usize, key_hashes: &[u64], bits_per_key: usize) -> TableFilter {
+        let data = match policy {
+            FilterPolicy::Bloom => FilterData::Bloom(BloomFilter::build(key_hashes, bits_per_key)),
+            FilterPolicy::Ribbon => FilterData::Ribbon(RibbonFilter::build(key_hashes, bits_per_key))
+        };
+        TableFilter { data, prefix_len }
+    }
+
+    pub(crate) fn serialized_size(policy: FilterPolicy, key_count: usize, bits_per_key: usize) -> usize {
+        match policy {
+            FilterPolicy::Bloom => BloomFilter::serialized_size(key_count, bits_per_key),
+            FilterPolicy::Ribbon => RibbonFilter::serialized_size(key_count, bits_per_key)
+        }
+    }
+
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        let key = filter_key(key, self.prefix_len);
+        match &self.data {
+            FilterData::Bloom(filter) => filter.may_contain(key),
+            FilterData::Ribbon(filter) => filter.may_contain(key)
+        }
+    }
+
+    pub(crate) fn serialize(&self, dest: &mut Vec<u8>) {
+        match &self.data {
+            FilterData::Bloom(filter) => filter.serialize(dest),
+            FilterData::Ribbon(filter) => filter.serialize(dest)
+        }
+    }
+
+    fn from_raw(policy: FilterPolicy, prefix_len: usize, raw: &[u8]) -> Result<TableFilter, Error> {
+        let data = match policy {
+            FilterPolicy::Bloom => FilterData::Bloom(BloomFilter::from_raw(raw)?),
+            FilterPolicy::Ribbon => FilterData::Ribbon(RibbonFilter::from_raw(raw)?)
+        };
+        Ok(TableFilter { data, prefix_len })
+    }
+
+    /// Loads the filter of a complete table file, `None` if it was built without one.
+    pub(crate) fn from_table(raw: &[u8]) -> Result<Option<TableFilter>, Error> {
+        let footer = ScTableFooter::decode(raw)?;
+        Self::from_footer(&raw[footer.filter_off()..footer.index_off], &footer)
+    }
+
+    /// Parses the filter `footer` points to, read separately from the rest of the table.
+    pub(crate) fn from_footer(raw_filter: &[u8], footer: &ScTableFooter) -> Result<Option<TableFilter>, Error> {
+        if footer.filter_size == 0 {
+            return Ok(None)
+        }
+        if crc32::checksum_ieee(raw_filter) != footer.filter_crc {
+            return Err(Error::sc_table_corrupt("incorrect filter crc".into()))
+        }
+        match FilterPolicy::from_code(footer.filter_policy) {
+            Some(policy) => Self::from_raw(policy, footer.filter_prefix_len, raw_filter).map(Some),
+            None => Err(Error::sc_table_corrupt("unknown filter policy".into()))
+        }
+    }
+}
+
+/// Bloom filter over the user keys of a table; serialized as the bit array followed by one
+/// byte holding the probe count.
+pub(crate) struct BloomFilter {
+    bits: Vec<u8>,
+    probes: u8
+}
+
+impl BloomFilter {
+    fn bit_count(key_count: usize, bits_per_key: usize) -> usize {
+        // tiny filters have a very high false positive rate, keep at least 64 bits
+        let bits = (key_count * bits_per_key).max(64);
+        bits + (8 - bits % 8) % 8
+    }
+
+    pub(crate) fn serialized_size(key_count: usize, bits_per_key: usize) -> usize {
+        Self::bit_count(key_count, bits_per_key) / 8 + 1
+    }
+
+    pub(crate) fn build(key_hashes: &[u64], bits_per_key: usize) -> Self {
+        // ln(2) * bits_per_key probes minimize the false positive rate
+        let probes = ((bits_per_key as f64 * 0.69) as usize).clamp(1, 30) as u8;
+        let bit_count = Self::bit_count(key_hashes.len(), bits_per_key);
+        let mut bits = vec![0u8; bit_count / 8];
+        for &hash in key_hashes {
+            let mut h = (hash >> 32) as u32;
+            let delta = h.rotate_right(17);
+            for _ in 0..probes {
+                let pos = h as usize % bit_count;
+                bits[pos / 8] |= 1 << (pos % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+        Self { bits, probes }
+    }
+
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        let bit_count = self.bits.len() * 8;
+        let mut h = (key_hash(key) >> 32) as u32;
+        let delta = h.rotate_right(17);
+        for _ in 0..self.probes {
+            let pos = h as usize % bit_count;
+            if self.bits[pos / 8] & (1 << (pos % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+
+    pub(crate) fn serialize(&self, dest: &mut Vec<u8>) {
+        dest.extend_from_slice(&self.bits);
+        dest.push(self.probes);
+    }
+
+    fn from_raw(raw: &[u8]) -> Result<BloomFilter, Error> {
+        if raw.len() < 2 {
+            return Err(Error::sc_table_corrupt("filter too small".into()))
+        }
+        Ok(Self { bits: raw[..raw.len() - 1].to_vec(), probes: raw[raw.len() - 1] })
+    }
+}
+
+/// Bloom filter that keys are added to one by one, in front of a memtable.
+pub(crate) struct MemTableFilter {
+    bits: Vec<u64>,
+    probes: u32
+}
+
+impl MemTableFilter {
+    pub(crate) fn new(key_count: usize, bits_per_key: usize) -> Self {
+        let words = (key_count * bits_per_key / 64).max(1);
+        let probes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        Self { bits: vec![0; words], probes }
+    }
+
+    // does not borrow the filter, so that `insert` can set the bits while iterating
+    fn positions(key: &[u8], probes: u32, bit_count: usize) -> impl Iterator<Item = usize> {
+        let hash = key_hash(key);
+        let (h, delta) = (hash as u32, (hash >> 32) as u32);
+        (0..probes).map(move |i| h.wrapping_add(delta.wrapping_mul(i)) as usize % bit_count)
+    }
+
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        for pos in Self::positions(key, self.probes, self.bits.len() * 64) {
+            self.bits[pos / 64] |= 1 << (pos % 64);
+        }
+    }
+
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        Self::positions(key, self.probes, self.bits.len() * 64).all(|pos| self.bits[pos / 64] & (1 << (pos % 64)) != 0)
+    }
+}
+
+/// Standard ribbon filter with 64 bit wide coefficient rows. Each key maps to a start slot,
+/// a coefficient row and a `result_bits` wide fingerprint; the filter is a solution of that
+/// linear system over GF(2), stored as one bit plane per fingerprint bit. Serialized as the
+/// planes followed by 4byte num_starts, 1byte seed and 1byte result_bits.
+pub(crate) struct RibbonFilter {
+    planes: Vec<Vec<u64>>,
+    num_starts: usize,
+    seed: u8,
+    result_bits: u8
+}
+
+impl RibbonFilter {
+    // marks a filter no seed could be solved for, it answers "maybe" to every key
+    const FAILED_SEED: u8 = 0xff;
+
+    fn result_bits(bits_per_key: usize) -> u8 {
+        // matches the false positive rate of a bloom filter with the same bits_per_key
+        ((bits_per_key as f64 * 0.69).round() as usize).clamp(1, 16) as u8
+    }
+
+    fn num_starts(key_count: usize) -> usize {
+        key_count + key_count / 10 + 1
+    }
+
+    fn num_words(num_starts: usize) -> usize {
+        // the rows starting at the last slot reach 63 slots further, plus one word of
+        // padding for `window`
+        num_starts / 64 + 3
+    }
+
+    pub(crate) fn serialized_size(key_count: usize, bits_per_key: usize) -> usize {
+        Self::result_bits(bits_per_key) as usize * Self::num_words(Self::num_starts(key_count)) * 8 + 6
+    }
+
+    fn row(hash: u64, seed: u8, num_starts: usize, result_bits: u8) -> (usize, u64, u16) {
+        let h = mix64(hash ^ (seed as u64).wrapping_mul(0x9e3779b97f4a7c15));
+        let start = (((h >> 32) * num_starts as u64) >> 32) as usize;
+        let coeff = mix64(h) | 1;
+        let result = (h as u32 & ((1u32 << result_bits) - 1)) as u16;
+        (start, coeff, result)
+    }
+
+    fn window(plane: &[u64], slot: usize) -> u64 {
+        let (word, bit) = (slot / 64, slot % 64);
+        if bit == 0 {
+            plane[word]
+        } else {
+            (plane[word] >> bit) | (plane[word + 1] << (64 - bit))
+        }
+    }
+
+    /// Gaussian elimination on the fly, `None` if the rows turned out linearly dependent.
+    fn band(key_hashes: &[u64], seed: u8, num_starts: usize, result_bits: u8) -> Option<(Vec<u64>, Vec<u16>)> {
+        let slots = num_starts + 63;
+        let mut coeffs = vec![0u64; slots];
+        let mut results = vec![0u16; slots];
+        for &hash in key_hashes {
+            let (mut start, mut coeff, mut result) = Self::row(hash, seed, num_starts, result_bits);
+            loop {
+                if coeffs[start] == 0 {
+                    coeffs[start] = coeff;
+                    results[start] = result;
+                    break;
+                }
+                coeff ^= coeffs[start];
+                result ^= results[start];
+                if coeff == 0 {
+                    // duplicate keys are fine
+                    if result == 0 {
+                        break;
+                    }
+                    return None
+                }
+                let shift = coeff.trailing_zeros() as usize;
+                start += shift;
+                coeff >>= shift;
+            }
+        }
+        Some((coeffs, results))
+    }
+
+    pub(crate) fn build(key_hashes: &[u64], bits_per_key: usize) -> Self {
+        let result_bits = Self::result_bits(bits_per_key);
+        let num_starts = Self::num_starts(key_hashes.len());
+        let mut planes = vec![vec![0u64; Self::num_words(num_starts)]; result_bits as usize];
+        for seed in 0..Self::FAILED_SEED {
+            if let Some((coeffs, results)) = Self::band(key_hashes, seed, num_starts, result_bits) {
+                // back substitution, every slot above the current one is solved already
+                for slot in (0..coeffs.len()).rev() {
+                    let coeff = coeffs[slot];
+                    if coeff == 0 {
+                        continue;
+                    }
+                    for (bit, plane) in planes.iter_mut().enumerate() {
+                        let parity = (Self::window(plane, slot) & coeff).count_ones() as u16 & 1;
+                        if (results[slot] >> bit) & 1 != parity {
+                            plane[slot / 64] |= 1 << (slot % 64);
+                        }
+                    }
+                }
+                return Self { planes, num_starts, seed, result_bits }
+            }
+        }
+        Self { planes, num_starts, seed: Self::FAILED_SEED, result_bits }
+    }
+
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        if self.seed == Self::FAILED_SEED {
+            return true;
+        }
+        let (start, coeff, result) = Self::row(key_hash(key), self.seed, self.num_starts, self.result_bits);
+        self.planes.iter().enumerate().all(|(bit, plane)| {
+            (Self::window(plane, start) & coeff).count_ones() as u16 & 1 == (result >> bit) & 1
+        })
+    }
+
+    pub(crate) fn serialize(&self, dest: &mut Vec<u8>) {
+        for plane in self.planes.iter() {
+            for &word in plane.iter() {
+                dest.extend_from_slice(&encode_fixed64_ret(word));
+            }
+        }
+        dest.extend_from_slice(&encode_fixed32_ret(self.num_starts as u32));
+        dest.push(self.seed);
+        dest.push(self.result_bits);
+    }
+
+    fn from_raw(raw: &[u8]) -> Result<RibbonFilter, Error> {
+        if raw.len() < 6 {
+            return Err(Error::sc_table_corrupt("filter too small".into()))
+        }
+        let tail = &raw[raw.len() - 6..];
+        let num_starts = decode_fixed32(&tail[0..4]) as usize;
+        let (seed, result_bits) = (tail[4], tail[5]);
+        let num_words = Self::num_words(num_starts);
+        if result_bits == 0 || result_bits > 16 || result_bits as usize * num_words * 8 + 6 != raw.len() {
+            return Err(Error::sc_table_corrupt("incorrect ribbon filter size".into()))
+        }
+        let planes = raw[..raw.len() - 6]
+            .chunks(num_words * 8)
+            .map(|plane| plane.chunks(8).map(decode_fixed64).collect())
+            .collect();
+        Ok(Self { planes, num_starts, seed, result_bits })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::table::filter::{key_hash, FilterPolicy, MemTableFilter, TableFilter};
+
+    /// Returns the serialized size of the filter.
+    fn check_filter(policy: FilterPolicy) -> usize {
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| format!("key{}", i).into_bytes()).collect();
+        let hashes: Vec<u64> = keys.iter().map(|key| key_hash(key)).collect();
+        let filter = TableFilter::build(policy, 0, &hashes, 10);
+
+        let mut raw = Vec::new();
+        filter.serialize(&mut raw);
+        assert_eq!(raw.len(), TableFilter::serialized_size(policy, keys.len(), 10));
+        let filter = TableFilter::from_raw(policy, 0, &raw).unwrap();
+
+        for key in keys.iter() {
+            assert!(filter.may_contain(key));
+        }
+        let false_positives = (0..10000u32)
+            .filter(|i| filter.may_contain(format!("absent{}", i).as_bytes()))
+            .count();
+        // ~1% expected with 10 bits per key
+        assert!(false_positives < 300, "{} false positives", false_positives);
+        raw.len()
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        check_filter(FilterPolicy::Bloom);
+    }
+
+    #[test]
+    fn test_ribbon_filter() {
+        let ribbon_size = check_filter(FilterPolicy::Ribbon);
+        let bloom_size = TableFilter::serialized_size(FilterPolicy::Bloom, 1000, 10);
+        assert!(ribbon_size < bloom_size, "{} vs {}", ribbon_size, bloom_size);
+    }
+
+    #[test]
+    fn test_memtable_filter() {
+        let mut filter = MemTableFilter::new(1000, 10);
+        for i in 0..1000u32 {
+            filter.insert(format!("key{}", i).as_bytes());
+        }
+        assert!((0..1000u32).all(|i| filter.may_contain(format!("key{}", i).as_bytes())));
+        let false_positives = (0..10000u32)
+            .filter(|i| filter.may_contain(format!("absent{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
@@ -0,0 +1,223 @@
+use std::cmp::Ordering;
+
+use crc::crc32;
+
+use crate::table::tablefmt::{TABLE_FOOTER_SIZE, TABLE_FOOTER_GLOBAL_SEQ_OFF, TABLE_INDEX_ITEM_HEAD_SIZE, TABLE_MAGIC,
+                             TABLE_MAGIC_SIZE};
+use crate::encode::{encode_fixed32_ret, encode_fixed64_ret, decode_fixed32, decode_fixed64};
+use crate::error::Error;
+use crate::Comparator;
+use crate::partition::LookupKey;
+use crate::statistics::SizeHistogram;
+
+pub(crate) struct ScTableIndexItem {
+    pub(crate) block_off: u32,
+    pub(crate) block_size: u32,
+    pub(crate) last_seq: u64,
+    pub(crate) last_key: Vec<u8>
+}
+
+impl ScTableIndexItem {
+    pub(crate) fn new(block_off: u32, block_size: u32, last_seq: u64, last_key: Vec<u8>) -> Self {
+        Self { block_off, block_size, last_seq, last_key }
+    }
+
+    pub(crate) fn serialize(&self, dest: &mut Vec<u8>) {
+        dest.extend_from_slice(&encode_fixed32_ret(self.block_off));
+        dest.extend_from_slice(&encode_fixed32_ret(self.block_size));
+        dest.extend_from_slice(&encode_fixed64_ret(self.last_seq));
+        dest.extend_from_slice(&encode_fixed32_ret(self.last_key.len() as u32));
+        dest.extend_from_slice(&self.last_key);
+    }
+
+    fn serialized_size(&self) -> usize {
+        TABLE_INDEX_ITEM_HEAD_SIZE + self.last_key.len()
+    }
+}
+
+pub(crate) struct ScTableFooter {
+    pub(crate) index_off: usize,
+    pub(crate) index_size: usize,
+    pub(crate) index_crc: u32,
+    pub(crate) filter_size: usize,
+    pub(crate) filter_crc: u32,
+    /// `FilterPolicy::code` of the filter, 0 if there is none
+    pub(crate) filter_policy: u32,
+    /// `FilterKeys::prefix_len` of the filter
+    pub(crate) filter_prefix_len: usize,
+    /// Sequence numbers of the oldest and the newest entry, both 0 for an empty table
+    pub(crate) min_seq: u64,
+    pub(crate) max_seq: u64,
+    pub(crate) entry_count: usize,
+    /// Size of the keys and values of the entries, without any format overhead
+    pub(crate) data_size: u64,
+    /// Sequence number the entries are read with instead of their own, 0 if there is none
+    pub(crate) global_seq: u64,
+    /// Number of distinct user keys, `entry_count` when every key has a single version
+    pub(crate) key_count: usize,
+    pub(crate) key_sizes: SizeHistogram,
+    pub(crate) value_sizes: SizeHistogram
+}
+
+impl ScTableFooter {
+    pub(crate) fn decode(raw: &[u8]) -> Result<ScTableFooter, Error> {
+        if raw.len() < TABLE_FOOTER_SIZE {
+            return Err(Error::sc_table_corrupt("too small to be a table file".into()))
+        }
+        Self::decode_tail(&raw[raw.len() - TABLE_FOOTER_SIZE..], raw.len())
+    }
+
+    /// Decodes the last `TABLE_FOOTER_SIZE` bytes of a table file `table_size` bytes long.
+    pub(crate) fn decode_tail(footer: &[u8], table_size: usize) -> Result<ScTableFooter, Error> {
+        debug_assert_eq!(footer.len(), TABLE_FOOTER_SIZE);
+        if &footer[TABLE_FOOTER_SIZE - TABLE_MAGIC_SIZE..] != TABLE_MAGIC {
+            return Err(Error::sc_table_corrupt("incorrect table magic".into()))
+        }
+        let ret = Self {
+            index_off: decode_fixed32(&footer[0..4]) as usize,
+            index_size: decode_fixed32(&footer[4..8]) as usize,
+            index_crc: decode_fixed32(&footer[8..12]),
+            filter_size: decode_fixed32(&footer[12..16]) as usize,
+            filter_crc: decode_fixed32(&footer[16..20]),
+            filter_policy: decode_fixed32(&footer[20..24]),
+            filter_prefix_len: decode_fixed32(&footer[24..28]) as usize,
+            min_seq: decode_fixed64(&footer[28..36]),
+            max_seq: decode_fixed64(&footer[36..44]),
+            entry_count: decode_fixed32(&footer[44..48]) as usize,
+            data_size: decode_fixed64(&footer[48..56]),
+            global_seq: decode_fixed64(&footer[TABLE_FOOTER_GLOBAL_SEQ_OFF..TABLE_FOOTER_GLOBAL_SEQ_OFF + 8]),
+            key_count: decode_fixed32(&footer[64..68]) as usize,
+            key_sizes: SizeHistogram::decode(&footer[68..68 + SizeHistogram::ENCODED_SIZE]),
+            value_sizes: SizeHistogram::decode(&footer[68 + SizeHistogram::ENCODED_SIZE..68 + 2 * SizeHistogram::ENCODED_SIZE])
+        };
+        if ret.index_off + ret.index_size + TABLE_FOOTER_SIZE != table_size {
+            return Err(Error::sc_table_corrupt("incorrect index size".into()))
+        }
+        if ret.filter_size > ret.index_off {
+            return Err(Error::sc_table_corrupt("incorrect filter size".into()))
+        }
+        Ok(ret)
+    }
+
+    pub(crate) fn serialize(&self, dest: &mut Vec<u8>) {
+        dest.extend_from_slice(&encode_fixed32_ret(self.index_off as u32));
+        dest.extend_from_slice(&encode_fixed32_ret(self.index_size as u32));
+        dest.extend_from_slice(&encode_fixed32_ret(self.index_crc));
+        dest.extend_from_slice(&encode_fixed32_ret(self.filter_size as u32));
+        dest.extend_from_slice(&encode_fixed32_ret(self.filter_crc));
+        dest.extend_from_slice(&encode_fixed32_ret(self.filter_policy));
+        dest.extend_from_slice(&encode_fixed32_ret(self.filter_prefix_len as u32));
+        dest.extend_from_slice(&encode_fixed64_ret(self.min_seq));
+        dest.extend_from_slice(&encode_fixed64_ret(self.max_seq));
+        dest.extend_from_slice(&encode_fixed32_ret(self.entry_count as u32));
+        dest.extend_from_slice(&encode_fixed64_ret(self.data_size));
+        dest.extend_from_slice(&encode_fixed64_ret(self.global_seq));
+        dest.extend_from_slice(&encode_fixed32_ret(self.key_count as u32));
+        self.key_sizes.serialize(dest);
+        self.value_sizes.serialize(dest);
+        dest.extend_from_slice(TABLE_MAGIC);
+    }
+
+    /// Fails for tables where a key has several versions, which would all be read with the
+    /// same global seq.
+    pub(crate) fn check_ingestible(&self) -> Result<(), Error> {
+        if self.key_count != self.entry_count {
+            return Err(Error::ingest_rejected(format!("{} entries for {} keys", self.entry_count, self.key_count).into()))
+        }
+        Ok(())
+    }
+
+    /// Gives the table a global sequence number, so that a table built elsewhere can be
+    /// ingested without rewriting its blocks. Returns where in the table file to write which
+    /// bytes; only the footer changes. Fails for 0, which means none, and for tables that
+    /// fail `check_ingestible`.
+    pub(crate) fn stamp_global_seq(&mut self, global_seq: u64) -> Result<(usize, [u8; 8]), Error> {
+        if global_seq == 0 {
+            return Err(Error::ingest_rejected("global seq 0".into()))
+        }
+        self.check_ingestible()?;
+        self.global_seq = global_seq;
+        Ok((self.index_off + self.index_size + TABLE_FOOTER_GLOBAL_SEQ_OFF, encode_fixed64_ret(global_seq)))
+    }
+
+    /// The sequence number entries with `seq` are read with.
+    pub(crate) fn entry_seq(&self, seq: u64) -> u64 {
+        if self.global_seq != 0 { self.global_seq } else { seq }
+    }
+
+    /// Blocks end where the filter begins.
+    pub(crate) fn filter_off(&self) -> usize {
+        self.index_off - self.filter_size
+    }
+}
+
+pub(crate) struct ScTableIndex {
+    items: Vec<ScTableIndexItem>
+}
+
+impl ScTableIndex {
+    /// Locates the index inside a complete table file.
+    pub(crate) fn from_table(raw: &[u8]) -> Result<ScTableIndex, Error> {
+        let footer = ScTableFooter::decode(raw)?;
+        Self::from_footer(&raw[footer.index_off..footer.index_off + footer.index_size], &footer)
+    }
+
+    /// Parses the index `footer` points to, read separately from the rest of the table.
+    pub(crate) fn from_footer(raw_index: &[u8], footer: &ScTableFooter) -> Result<ScTableIndex, Error> {
+        if crc32::checksum_ieee(raw_index) != footer.index_crc {
+            return Err(Error::sc_table_corrupt("incorrect index crc".into()))
+        }
+        Self::from_raw(raw_index, footer.filter_off())
+    }
+
+    fn from_raw(raw_index: &[u8], blocks_size: usize) -> Result<ScTableIndex, Error> {
+        let mut items = Vec::new();
+        let mut base = 0;
+        while base < raw_index.len() {
+            if base + TABLE_INDEX_ITEM_HEAD_SIZE > raw_index.len() {
+                return Err(Error::sc_table_corrupt("truncated index item".into()))
+            }
+            let head = &raw_index[base..base + TABLE_INDEX_ITEM_HEAD_SIZE];
+            let block_off = decode_fixed32(&head[0..4]);
+            let block_size = decode_fixed32(&head[4..8]);
+            let last_seq = decode_fixed64(&head[8..16]);
+            let last_key_size = decode_fixed32(&head[16..20]) as usize;
+            let key_base = base + TABLE_INDEX_ITEM_HEAD_SIZE;
+            if key_base + last_key_size > raw_index.len() {
+                return Err(Error::sc_table_corrupt("truncated index item".into()))
+            }
+            if block_off as usize + block_size as usize > blocks_size {
+                return Err(Error::sc_table_corrupt("incorrect block catalog data".into()))
+            }
+            let item = ScTableIndexItem::new(block_off, block_size, last_seq,
+                                             raw_index[key_base..key_base + last_key_size].to_vec());
+            base += item.serialized_size();
+            items.push(item);
+        }
+        Ok(Self { items })
+    }
+
+    pub(crate) fn block_count(&self) -> usize {
+        self.items.len()
+    }
+
+    pub(crate) fn nth_block(&self, n: usize) -> &ScTableIndexItem {
+        &self.items[n]
+    }
+
+    /// The blocks that may hold user keys from `lower` to `upper`, inclusive.
+    pub(crate) fn blocks_between<Comp: Comparator>(&self, lower: &[u8], upper: &[u8]) -> &[ScTableIndexItem] {
+        let first = self.items.partition_point(|item| Comp::compare(&item.last_key, lower) == Ordering::Less);
+        let last = self.items.partition_point(|item| Comp::compare(&item.last_key, upper) == Ordering::Less);
+        &self.items[first.min(self.items.len())..(last + 1).min(self.items.len())]
+    }
+
+    /// The only block that may contain `key`: the first one whose last key is not less than it.
+    pub(crate) fn find_block<Comp: Comparator>(&self, key: &LookupKey<Comp>) -> Option<&ScTableIndexItem> {
+        let idx = self.items.partition_point(|item| {
+            let last_key = LookupKey::new(item.last_seq, &item.last_key);
+            last_key.cmp(key) == Ordering::Less
+        });
+        self.items.get(idx)
+    }
+}
@@ -1,32 +1,96 @@
 pub mod tablefmt;
 pub(crate) mod builder;
 pub(crate) mod cache;
+pub(crate) mod filter;
+pub(crate) mod index;
 pub(crate) mod sctable;
+pub(crate) mod secondary;
 pub(crate) mod scsplit;
 
 use std::cmp::Ordering;
+use std::sync::Arc;
 
 use crate::{Comparator, error};
 use crate::io::IOManager;
+use crate::memory::MemoryTracker;
 use crate::table::cache::TableCacheManager;
-use crate::partition::{InternalKey, UserKey};
+use crate::table::sctable::ScTableFile;
+use crate::partition::{LookupKey, ScanCost, UserKey};
+use crate::statistics::SizeHistogram;
+
+/// What a table knows about a key: its value, that it was deleted, or nothing, in which case
+/// older tables have to be asked.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum TableGet {
+    Found(Vec<u8>),
+    Deleted,
+    Absent
+}
 
 pub(crate) trait Table<Comp: Comparator> {
+    /// A block read from the file or the secondary cache is charged to `memory_tracker`, if
+    /// any, while it is held outside of the block cache.
     fn get<'a>(&self,
-               key: &InternalKey<Comp>,
+               key: &LookupKey<Comp>,
                cache_manager: &'a TableCacheManager,
-               io_manager: &'a IOManager) -> Result<Option<Vec<u8>>, error::Error>;
+               io_manager: &'a IOManager,
+               memory_tracker: Option<&Arc<MemoryTracker>>) -> Result<TableGet, error::Error>;
 
-    fn cmp_key(&self, key: &UserKey<Comp>) -> Ordering {
-        if key.cmp(self.lower_bound()) == Ordering::Less {
+    fn cmp_key(&self, key: &[u8]) -> Ordering {
+        if Comp::compare(key, self.lower_bound().key()) == Ordering::Less {
             Ordering::Less
-        } else if key.cmp(self.upper_bound()) == Ordering::Greater {
+        } else if Comp::compare(key, self.upper_bound().key()) == Ordering::Greater {
             Ordering::Greater
         } else {
             Ordering::Equal
         }
     }
 
+    /// Whether `key` may be in the table, answered without any IO.
+    fn may_contain(&self, _key: &[u8]) -> bool {
+        true
+    }
+
+    /// Sequence number of the oldest entry; reads at an older sequence number skip the table.
+    fn min_seq(&self) -> u64 {
+        0
+    }
+
+    fn file_size(&self) -> usize {
+        0
+    }
+
+    fn entry_count(&self) -> usize {
+        0
+    }
+
+    /// Bytes of the keys and values in the table.
+    fn data_size(&self) -> u64 {
+        0
+    }
+
+    /// Sizes of the keys and of the values in the table.
+    fn entry_sizes(&self) -> (SizeHistogram, SizeHistogram) {
+        (SizeHistogram::default(), SizeHistogram::default())
+    }
+
+    /// The blocks that may hold keys from `lower` to `upper`, inclusive.
+    fn scan_cost(&self, _lower: &[u8], _upper: &[u8]) -> ScanCost {
+        ScanCost::default()
+    }
+
+    /// Bytes the table keeps in memory while open, its index and filter.
+    fn reader_memory(&self) -> usize {
+        0
+    }
+
+    /// CRC32 of the whole table file, `None` if it was never read whole.
+    fn file_checksum(&self) -> Option<u32> {
+        None
+    }
+
+    fn table_file(&self) -> ScTableFile;
+
     fn lower_bound(&self) -> &UserKey<Comp>;
 
     fn upper_bound(&self) -> &UserKey<Comp>;
@@ -1,10 +1,13 @@
+use std::sync::Arc;
+
 use crate::table::sctable::ScTableFile;
 use crate::Comparator;
-use crate::table::Table;
+use crate::table::{Table, TableGet};
 use crate::table::cache::TableCacheManager;
 use crate::io::IOManager;
+use crate::memory::MemoryTracker;
 use crate::error;
-use crate::partition::{InternalKey, UserKey};
+use crate::partition::{LookupKey, UserKey};
 
 pub(crate) struct ScSplit<Comp: Comparator> {
     file: ScTableFile,
@@ -18,12 +21,17 @@ pub(crate) struct ScSplit<Comp: Comparator> {
 
 impl<Comp: Comparator> Table<Comp> for ScSplit<Comp> {
     fn get<'a>(&self,
-               key: &InternalKey<Comp>,
+               key: &LookupKey<Comp>,
                cache_manager: &'a TableCacheManager,
-               io_manager: &'a IOManager) -> Result<Option<Vec<u8>>, error::Error> {
+               io_manager: &'a IOManager,
+               memory_tracker: Option<&Arc<MemoryTracker>>) -> Result<TableGet, error::Error> {
         unimplemented!()
     }
 
+    fn table_file(&self) -> ScTableFile {
+        self.file
+    }
+
     fn lower_bound(&self) -> &UserKey<Comp> {
         &self.lower_bound
     }
@@ -1,11 +1,21 @@
 use std::cmp::Ordering;
+use std::sync::Arc;
 
 use crate::error::Error;
-use crate::table::Table;
-use crate::table::cache::{TableCacheManager, ScTableCache};
-use crate::Comparator;
+use crate::table::{Table, TableGet};
+use crate::table::cache::{TableCacheManager, ScTableCache, CacheQuota};
+use crate::table::filter::TableFilter;
+use crate::table::index::{ScTableIndex, ScTableFooter};
+use crate::table::tablefmt::TABLE_FOOTER_SIZE;
+use crate::{Comparator, Options};
 use crate::io::IOManager;
-use crate::partition::{InternalKey, UserKey};
+use crate::io::trace::IoReason;
+use crate::memory::{MemoryKind, MemoryTracker};
+use crate::statistics::SizeHistogram;
+use crate::partition::{LookupKey, ScanCost, UserKey};
+
+/// Every table file name ends with it.
+pub(crate) const TABLE_FILE_SUFFIX: &str = ".sst";
 
 #[derive(Ord, Eq, PartialOrd, PartialEq, Hash, Copy, Clone)]
 pub(crate) struct ScTableFile {
@@ -22,47 +32,304 @@ impl ScTableFile {
     }
 
     pub(crate) fn file_name(&self) -> String {
-        format!("{}_{}_{}.sst", self.origin_partition, self.origin_level, self.origin_number)
+        format!("{}_{}_{}{}", self.origin_partition, self.origin_level, self.origin_number, TABLE_FILE_SUFFIX)
+    }
+
+    /// The file name in the directory `Options::db_paths` has for the level it was written to.
+    pub(crate) fn file_path(&self, options: &Options) -> String {
+        match options.db_path(self.origin_level as usize) {
+            Some(path) => format!("{}/{}", path, self.file_name()),
+            None => self.file_name()
+        }
+    }
+}
+
+#[derive(Ord, Eq, PartialOrd, PartialEq, Hash, Copy, Clone)]
+pub(crate) struct ScBlockId {
+    table_file: ScTableFile,
+    block_off: u32
+}
+
+impl ScBlockId {
+    pub(crate) fn new(table_file: ScTableFile, block_off: u32) -> Self {
+        Self { table_file, block_off }
     }
 }
 
 pub(crate) struct ScTable<Comp: Comparator> {
     table_file: ScTableFile,
+    file_path: String,
+    index: ScTableIndex,
+    filter: Option<TableFilter>,
+    min_seq: u64,
+    // `ScTableFooter::global_seq`
+    global_seq: u64,
+    file_size: usize,
+    reader_memory: usize,
+    entry_count: usize,
+    data_size: u64,
+    key_sizes: SizeHistogram,
+    value_sizes: SizeHistogram,
+    file_checksum: Option<u32>,
 
     key_lower_bound: UserKey<Comp>,
     key_upper_bound: UserKey<Comp>
 }
 
 impl<Comp: Comparator> ScTable<Comp> {
-    pub(crate) fn new(table_file: ScTableFile, key_lower_bound: UserKey<Comp>, key_upper_bound: UserKey<Comp>) -> Self {
-        Self { table_file, key_lower_bound, key_upper_bound }
+    pub(crate) fn new(table_file: ScTableFile,
+                      file_path: String,
+                      index: ScTableIndex,
+                      filter: Option<TableFilter>,
+                      footer: &ScTableFooter,
+                      key_lower_bound: UserKey<Comp>,
+                      key_upper_bound: UserKey<Comp>) -> Self {
+        Self {
+            table_file,
+            file_path,
+            index,
+            filter,
+            min_seq: footer.entry_seq(footer.min_seq),
+            global_seq: footer.global_seq,
+            file_size: footer.index_off + footer.index_size + TABLE_FOOTER_SIZE,
+            reader_memory: footer.index_size + footer.filter_size,
+            entry_count: footer.entry_count,
+            data_size: footer.data_size,
+            key_sizes: footer.key_sizes,
+            value_sizes: footer.value_sizes,
+            file_checksum: None,
+            key_lower_bound,
+            key_upper_bound
+        }
+    }
+
+    pub(crate) fn with_file_checksum(mut self, file_checksum: u32) -> Self {
+        self.file_checksum = Some(file_checksum);
+        self
+    }
+}
+
+/// `(seq, key, value)`, the value is `None` for a deletion.
+pub(crate) type TableEntry = (u64, Vec<u8>, Option<Vec<u8>>);
+
+/// Every `(seq, key, value)` of a table file in key order, for compaction. Blocks are read
+/// in sequential runs of about `readahead_size` bytes; 0 reads the whole file at once. Only
+/// the current run and the entries of the current block are held in memory.
+pub(crate) struct TableEntries<'a> {
+    file_name: String,
+    io_manager: &'a IOManager,
+    readahead_size: usize,
+    footer: ScTableFooter,
+    index: ScTableIndex,
+    // the last run read, holding the blocks up to `run_end` from file offset `run_off`
+    run: Vec<u8>,
+    run_off: usize,
+    run_end: usize,
+    next_block: usize,
+    block_entries: std::vec::IntoIter<TableEntry>
+}
+
+impl<'a> TableEntries<'a> {
+    pub(crate) fn open(file_name: String,
+                       readahead_size: usize,
+                       io_manager: &'a IOManager) -> Result<TableEntries<'a>, Error> {
+        let (footer, index, run) = if readahead_size == 0 {
+            let raw = io_manager.acquire_quota_for(IoReason::Compaction).read_file(file_name.clone())?;
+            (ScTableFooter::decode(&raw)?, ScTableIndex::from_table(&raw)?, raw)
+        } else {
+            let file_size = io_manager.acquire_quota().file_size(file_name.clone())? as usize;
+            if file_size < TABLE_FOOTER_SIZE {
+                return Err(Error::sc_table_corrupt("too small to be a table file".into()))
+            }
+            let raw_footer = io_manager.acquire_quota_for(IoReason::Compaction).read_file_range(
+                file_name.clone(), (file_size - TABLE_FOOTER_SIZE) as u64, TABLE_FOOTER_SIZE)?;
+            let footer = ScTableFooter::decode_tail(&raw_footer, file_size)?;
+            let raw_index = io_manager.acquire_quota_for(IoReason::Compaction).read_file_range(
+                file_name.clone(), footer.index_off as u64, footer.index_size)?;
+            let index = ScTableIndex::from_footer(&raw_index, &footer)?;
+            (footer, index, Vec::new())
+        };
+        // the whole file is one run
+        let run_end = if readahead_size == 0 { index.block_count() } else { 0 };
+        Ok(Self {
+            file_name,
+            io_manager,
+            readahead_size,
+            footer,
+            index,
+            run,
+            run_off: 0,
+            run_end,
+            next_block: 0,
+            block_entries: Vec::new().into_iter()
+        })
+    }
+
+    fn read_run(&mut self) -> Result<(), Error> {
+        // blocks are laid out back to back, take as many as fit into one read
+        let index = &self.index;
+        let run_off = index.nth_block(self.next_block).block_off as usize;
+        let mut run_end = self.next_block + 1;
+        while run_end < index.block_count() {
+            let item = index.nth_block(run_end);
+            if item.block_off as usize + item.block_size as usize - run_off > self.readahead_size {
+                break;
+            }
+            run_end += 1;
+        }
+        let last = index.nth_block(run_end - 1);
+        let run_size = last.block_off as usize + last.block_size as usize - run_off;
+        self.run = self.io_manager.acquire_quota_for(IoReason::Compaction)
+            .read_file_range(self.file_name.clone(), run_off as u64, run_size)?;
+        self.run_off = run_off;
+        self.run_end = run_end;
+        Ok(())
+    }
+
+    fn read_block(&mut self) -> Result<(), Error> {
+        if self.next_block == self.run_end {
+            self.read_run()?;
+        }
+        let item = self.index.nth_block(self.next_block);
+        let block_off = item.block_off as usize - self.run_off;
+        let raw_block = &self.run[block_off..block_off + item.block_size as usize];
+        // compaction inputs are read once, keep them from pushing hot blocks out of the cache
+        let block = ScTableCache::from_raw(raw_block, CacheQuota::transient())?;
+        let footer = &self.footer;
+        self.block_entries = (0..block.catalog_size())
+            .map(|i| {
+                let (seq, key, value) = block.nth_item(i);
+                (footer.entry_seq(seq), key.to_vec(), value.map(<[u8]>::to_vec))
+            })
+            .collect::<Vec<TableEntry>>()
+            .into_iter();
+        self.next_block += 1;
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for TableEntries<'a> {
+    type Item = Result<TableEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.block_entries.next() {
+                return Some(Ok(entry))
+            }
+            if self.next_block == self.index.block_count() {
+                return None
+            }
+            if let Err(e) = self.read_block() {
+                // nothing after a failed read
+                self.next_block = self.index.block_count();
+                return Some(Err(e))
+            }
+        }
     }
 }
 
 impl<Comp: Comparator> Table<Comp> for ScTable<Comp> {
     fn get<'a>(&self,
-               key: &InternalKey<Comp>,
+               key: &LookupKey<Comp>,
                cache_manager: &'a TableCacheManager,
-               io_manager: &'a IOManager) -> Result<Option<Vec<u8>>, Error> {
-        if key.user_key.cmp(self.lower_bound()) == Ordering::Less {
-            return Ok(None)
-        } else if key.user_key.cmp(self.upper_bound()) == Ordering::Greater {
-            return Ok(None)
+               io_manager: &'a IOManager,
+               memory_tracker: Option<&Arc<MemoryTracker>>) -> Result<TableGet, Error> {
+        if self.cmp_key(key.user_key) != Ordering::Equal {
+            return Ok(TableGet::Absent)
         }
+        // the entries of an ingested table are read with the global seq, which the level
+        // already found not newer than `key.seq`; their own sequence numbers do not count
+        let global_key;
+        let key = if self.global_seq != 0 {
+            global_key = LookupKey::new(u64::MAX, key.user_key);
+            &global_key
+        } else {
+            key
+        };
 
-        if let Some(cache) = cache_manager.get_cache(self.table_file) {
+        let block = match self.index.find_block(key) {
+            Some(block) => block,
+            None => return Ok(TableGet::Absent)
+        };
+        let block_id = ScBlockId::new(self.table_file, block.block_off);
+        if let Some(cache) = cache_manager.get_cache(block_id) {
             Ok(cache.get::<Comp>(key))
         } else {
-            let cache_quota = cache_manager.acquire_quota();
-            let cache =
-                ScTableCache::from_raw(
-                    &io_manager.acquire_quota()
-                                    .read_file(self.table_file.file_name())?, cache_quota)?;
-            let cache = cache_manager.add_cache(self.table_file, cache);
+            let _pin = memory_tracker.map(|tracker| tracker.pin(MemoryKind::PinnedBlocks, block.block_size as usize))
+                .transpose()?;
+            // a damaged secondary cache entry is not an error, the table file still has the block
+            let secondary = cache_manager.get_secondary(block_id).and_then(|raw| {
+                ScTableCache::from_raw(&raw, cache_manager.acquire_quota_for(block_id)).ok()
+            });
+            let cache = match secondary {
+                Some(cache) => cache,
+                None => {
+                    let cache_quota = cache_manager.acquire_quota_for(block_id);
+                    ScTableCache::from_raw(
+                        &io_manager.acquire_quota_for(IoReason::Get)
+                                        .read_file_range(self.file_path.clone(),
+                                                         block.block_off as u64,
+                                                         block.block_size as usize)?, cache_quota)?
+                }
+            };
+            let cache = cache_manager.add_cache(block_id, cache);
             Ok(cache.get::<Comp>(key))
         }
     }
 
+    fn may_contain(&self, key: &[u8]) -> bool {
+        match &self.filter {
+            Some(filter) => filter.may_contain(key),
+            None => true
+        }
+    }
+
+    fn min_seq(&self) -> u64 {
+        self.min_seq
+    }
+
+    fn file_size(&self) -> usize {
+        self.file_size
+    }
+
+    fn reader_memory(&self) -> usize {
+        self.reader_memory
+    }
+
+    fn entry_count(&self) -> usize {
+        self.entry_count
+    }
+
+    fn data_size(&self) -> u64 {
+        self.data_size
+    }
+
+    fn entry_sizes(&self) -> (SizeHistogram, SizeHistogram) {
+        (self.key_sizes, self.value_sizes)
+    }
+
+    fn scan_cost(&self, lower: &[u8], upper: &[u8]) -> ScanCost {
+        if Comp::compare(upper, self.lower_bound().key()) == Ordering::Less
+           || Comp::compare(lower, self.upper_bound().key()) == Ordering::Greater
+           || self.index.block_count() == 0 {
+            return ScanCost::default()
+        }
+        let blocks = self.index.blocks_between::<Comp>(lower, upper);
+        ScanCost {
+            blocks: blocks.len() as u64,
+            bytes: blocks.iter().map(|block| block.block_size as u64).sum(),
+            entries: (self.entry_count * blocks.len() / self.index.block_count()) as u64
+        }
+    }
+
+    fn file_checksum(&self) -> Option<u32> {
+        self.file_checksum
+    }
+
+    fn table_file(&self) -> ScTableFile {
+        self.table_file
+    }
+
     fn lower_bound(&self) -> &UserKey<Comp> {
         &self.key_lower_bound
     }
@@ -75,3 +342,78 @@ impl<Comp: Comparator> Table<Comp> for ScTable<Comp> {
         false
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::io::IOManager;
+    use crate::io::mem::MemFileSystem;
+    use crate::table::builder::ScTableBuilder;
+    use crate::table::filter::{FilterKeys, FilterPolicy};
+    use crate::table::index::ScTableFooter;
+    use crate::table::sctable::{ScTableFile, TableEntries, TableEntry};
+    use crate::table::tablefmt::TABLE_FOOTER_SIZE;
+
+    #[test]
+    fn test_readahead() {
+        let data: Vec<TableEntry> = (0..100u64)
+            .map(|i| (i, format!("key{:03}", i).into_bytes(),
+                      Some(format!("value{}", i).into_bytes()).filter(|_| i % 10 != 0)))
+            .collect();
+        let mut builder = ScTableBuilder::new(256, FilterPolicy::Bloom, FilterKeys::WholeKey, 10);
+        for (seq, key, value) in data.iter() {
+            builder.add_entry(*seq, key, value.as_deref());
+        }
+        let io_manager = IOManager::new(1, Arc::new(MemFileSystem::new()), 0);
+        let table_file = ScTableFile::new(0, 0, 1);
+        io_manager.acquire_quota().write_file(table_file.file_name(), &builder.build()).unwrap();
+
+        // smaller than one block, a few blocks, and the whole file
+        for &readahead_size in [0, 1, 1000, 1 << 20].iter() {
+            let entries: Vec<TableEntry> = TableEntries::open(table_file.file_name(), readahead_size, &io_manager)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(entries, data);
+        }
+    }
+
+    #[test]
+    fn test_global_seq() {
+        let mut builder = ScTableBuilder::new(256, FilterPolicy::Bloom, FilterKeys::WholeKey, 10);
+        for i in 0..100u64 {
+            builder.add_kv(i, format!("key{:03}", i).as_bytes(), b"value");
+        }
+        let mut raw = builder.build();
+        let blocks = raw[..raw.len() - TABLE_FOOTER_SIZE].to_vec();
+        let mut footer = ScTableFooter::decode(&raw).unwrap();
+        assert!(footer.stamp_global_seq(0).is_err());
+        let (off, stamp) = footer.stamp_global_seq(1000).unwrap();
+        raw[off..off + stamp.len()].copy_from_slice(&stamp);
+        // the blocks stay as they are
+        assert_eq!(&raw[..raw.len() - TABLE_FOOTER_SIZE], blocks.as_slice());
+        let footer = ScTableFooter::decode(&raw).unwrap();
+        assert_eq!((footer.global_seq, footer.entry_seq(footer.min_seq)), (1000, 1000));
+
+        let io_manager = IOManager::new(1, Arc::new(MemFileSystem::new()), 0);
+        let table_file = ScTableFile::new(0, 0, 1);
+        io_manager.acquire_quota().write_file(table_file.file_name(), &raw).unwrap();
+        for &readahead_size in [0, 1000].iter() {
+            let entries: Vec<TableEntry> = TableEntries::open(table_file.file_name(), readahead_size, &io_manager)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(entries.len(), 100);
+            assert!(entries.iter().all(|(seq, _, _)| *seq == 1000));
+        }
+
+        // every entry would be read with the same seq
+        let mut builder = ScTableBuilder::new(256, FilterPolicy::Bloom, FilterKeys::WholeKey, 10);
+        builder.add_kv(2, b"key", b"new");
+        builder.add_kv(1, b"key", b"old");
+        let mut footer = ScTableFooter::decode(&builder.build()).unwrap();
+        assert_eq!((footer.entry_count, footer.key_count), (2, 1));
+        assert!(footer.stamp_global_seq(1000).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+use crate::io::FileSystem;
+use crate::table::cache::CacheKey;
+
+/// Keeps blocks evicted from a `BlockCache` in local files, for when reading the table files
+/// again is much slower, e.g. on a network volume. Blocks are appended to the current of two
+/// generation files; once it holds half of `capacity` bytes the older one is dropped and
+/// truncated for reuse. The index lives in memory only, so the files start empty every time.
+/// Evicted blocks are written by a thread of their own, so that the read that evicted them
+/// does not wait for the file system.
+pub struct SecondaryCache {
+    files: Arc<SecondaryCacheFiles>,
+    writer: Option<JoinHandle<()>>
+}
+
+struct SecondaryCacheFiles {
+    fs: Arc<dyn FileSystem>,
+    file_name: String,
+    capacity: usize,
+    state: Mutex<SecondaryCacheState>,
+    pending: Mutex<PendingBlocks>,
+    // notified whenever `pending` changes
+    pending_changed: Condvar
+}
+
+struct SecondaryCacheState {
+    // key -> (generation, offset, size)
+    index: HashMap<CacheKey, (u64, u64, usize)>,
+    generation: u64,
+    generation_size: usize
+}
+
+/// Blocks evicted but not written yet, at most half of `capacity` bytes of them.
+struct PendingBlocks {
+    blocks: VecDeque<(CacheKey, Vec<u8>)>,
+    size: usize,
+    writing: bool,
+    closed: bool
+}
+
+impl SecondaryCache {
+    pub fn new(fs: Arc<dyn FileSystem>, file_name: impl ToString, capacity: usize) -> Self {
+        let files = Arc::new(SecondaryCacheFiles {
+            fs,
+            file_name: file_name.to_string(),
+            capacity,
+            state: Mutex::new(SecondaryCacheState { index: HashMap::new(), generation: 0, generation_size: 0 }),
+            pending: Mutex::new(PendingBlocks { blocks: VecDeque::new(), size: 0, writing: false, closed: false }),
+            pending_changed: Condvar::new()
+        });
+        // whatever a previous run left there is not indexed
+        let _ = files.fs.write_file(&files.generation_file(0), &[]);
+        let writer = {
+            let files = files.clone();
+            std::thread::spawn(move || files.write_pending())
+        };
+        Self { files, writer: Some(writer) }
+    }
+
+    /// Best effort: blocks that cannot be written, or that come in faster than they are
+    /// written, are simply not cached.
+    pub(crate) fn insert(&self, key: CacheKey, raw: Vec<u8>) {
+        let mut pending = self.files.pending.lock().unwrap();
+        if pending.closed || pending.size + raw.len() > self.files.capacity / 2 {
+            return;
+        }
+        pending.size += raw.len();
+        pending.blocks.push_back((key, raw));
+        self.files.pending_changed.notify_all();
+    }
+
+    pub(crate) fn get(&self, key: CacheKey) -> Option<Vec<u8>> {
+        {
+            let pending = self.files.pending.lock().unwrap();
+            if let Some((_, raw)) = pending.blocks.iter().rev().find(|(k, _)| *k == key) {
+                return Some(raw.clone())
+            }
+        }
+        // holding the lock keeps the generation file from being truncated under the read, and
+        // waits for a block the writer has just taken
+        let state = self.files.state.lock().unwrap();
+        let (generation, offset, size) = *state.index.get(&key)?;
+        self.files.fs.read_file_range(&self.files.generation_file(generation), offset, size).ok()
+    }
+
+    pub(crate) fn remove_owner(&self, owner: u64) {
+        {
+            let mut pending = self.files.pending.lock().unwrap();
+            pending.blocks.retain(|((o, _), _)| *o != owner);
+            pending.size = pending.blocks.iter().map(|(_, raw)| raw.len()).sum();
+        }
+        self.files.state.lock().unwrap().index.retain(|(o, _), _| *o != owner);
+    }
+
+    /// Waits until the blocks inserted so far are written.
+    #[cfg(test)]
+    pub(crate) fn wait_written(&self) {
+        let mut pending = self.files.pending.lock().unwrap();
+        while !pending.blocks.is_empty() || pending.writing {
+            pending = self.files.pending_changed.wait(pending).unwrap();
+        }
+    }
+}
+
+impl Drop for SecondaryCache {
+    fn drop(&mut self) {
+        // the blocks still pending are lost, like everything else in the files
+        self.files.pending.lock().unwrap().closed = true;
+        self.files.pending_changed.notify_all();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl SecondaryCacheFiles {
+    fn generation_file(&self, generation: u64) -> String {
+        format!("{}.{}", self.file_name, generation % 2)
+    }
+
+    /// Runs on the writer thread until the cache is dropped.
+    fn write_pending(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            if pending.closed {
+                return;
+            }
+            match pending.blocks.pop_front() {
+                Some((key, raw)) => {
+                    pending.size -= raw.len();
+                    pending.writing = true;
+                    // taken before `pending` is released, so that `get` never misses the block
+                    let mut state = self.state.lock().unwrap();
+                    drop(pending);
+                    self.write(&mut state, key, &raw);
+                    drop(state);
+                    pending = self.pending.lock().unwrap();
+                    pending.writing = false;
+                    self.pending_changed.notify_all();
+                },
+                None => pending = self.pending_changed.wait(pending).unwrap()
+            }
+        }
+    }
+
+    fn write(&self, state: &mut SecondaryCacheState, key: CacheKey, raw: &[u8]) {
+        if raw.len() > self.capacity / 2 || state.index.contains_key(&key) {
+            return;
+        }
+        if state.generation_size + raw.len() > self.capacity / 2 {
+            state.generation += 1;
+            let generation = state.generation;
+            state.index.retain(|_, (g, _, _)| *g + 1 == generation);
+            state.generation_size = 0;
+            if self.fs.write_file(&self.generation_file(generation), &[]).is_err() {
+                return;
+            }
+        }
+        let offset = state.generation_size as u64;
+        // a failed append may still have written part of the block, never reuse that range
+        state.generation_size += raw.len();
+        if self.fs.append_file(&self.generation_file(state.generation), raw).is_ok() {
+            let generation = state.generation;
+            state.index.insert(key, (generation, offset, raw.len()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::io::mem::MemFileSystem;
+    use crate::table::builder::ScBlockBuilder;
+    use crate::table::cache::{BlockCache, CacheAdmission, ScTableCache, TableCacheManager};
+    use crate::table::secondary::SecondaryCache;
+    use crate::table::sctable::{ScBlockId, ScTableFile};
+
+    #[test]
+    fn test_spill_and_rotate() {
+        let block_id = |n| ScBlockId::new(ScTableFile::new(0, 0, n), 0);
+        let raw_block = |n: u64| {
+            let mut builder = ScBlockBuilder::new();
+            builder.add_kv(n, format!("key{}", n).as_bytes(), b"value");
+            builder.build()
+        };
+        let block_size = raw_block(1).len();
+
+        // room for two blocks per generation
+        let secondary = SecondaryCache::new(Arc::new(MemFileSystem::new()), "cache", block_size * 4);
+        let cache_manager = TableCacheManager::shared(
+            Arc::new(BlockCache::with_secondary(1, CacheAdmission::Lru, Some(secondary))));
+        for n in 1..=6 {
+            let quota = cache_manager.acquire_quota_for(block_id(n));
+            cache_manager.add_cache(block_id(n), ScTableCache::from_raw(&raw_block(n), quota).unwrap());
+            cache_manager.wait_secondary_written();
+        }
+
+        // 6 is cached, 1..=5 were evicted in order: 1 and 2 fell out with the oldest generation
+        assert!(cache_manager.get_cache(block_id(6)).is_some());
+        for n in 1..=2 {
+            assert!(cache_manager.get_secondary(block_id(n)).is_none());
+        }
+        for n in 3..=5 {
+            assert_eq!(cache_manager.get_secondary(block_id(n)), Some(raw_block(n)));
+        }
+        assert!(cache_manager.get_secondary(block_id(6)).is_none());
+    }
+}
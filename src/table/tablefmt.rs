@@ -1,5 +1,36 @@
 //! Table format
 //! ```raw
+//! +-BLOCKS-------------------------------------+
+//! | block 0                                    |
+//! | block 1                                    |
+//! | ...                                        |
+//! +-FILTER-------------------------------------+
+//! | filter over the user keys, see `filter`    |
+//! +-INDEX--------------------------------------+
+//! | 4byte block_off | 4byte block_size         |
+//! | 8byte last_seq  | 4byte last_key_size      |
+//! | last_key_size binary last key              |
+//! | ...                                        |
+//! +-FOOTER-------------------------------------+
+//! | 4byte index offset                         |
+//! | 4byte index size                           |
+//! | 4byte index crc                            |
+//! | 4byte filter size, 0 if there is no filter |
+//! | 4byte filter crc                           |
+//! | 4byte filter policy                        |
+//! | 4byte filter prefix length, 0 = whole keys |
+//! | 8byte min seq      | 8byte max seq         |
+//! | 4byte entry count  | 8byte key+value bytes |
+//! | 8byte global seq, 0 = none                 |
+//! | 4byte count of distinct keys               |
+//! | 24 * 4byte counts of key sizes by bit      |
+//! | length, 24 * 4byte of value sizes          |
+//! | 8byte TABLE_MAGIC                          |
+//! +--------------------------------------------+
+//! ```
+//!
+//! Block format, a block is cut once it grows beyond `Options::block_size`
+//! ```raw
 //! +-HEADER-------------------------------------+
 //! | 4byte catalog size                         |
 //! | 4byte data size                            |
@@ -12,15 +43,33 @@
 //! +-DATA------+---------------+----------------+
 //! | data_size binary data                      |
 //! |                                            |
+//! +-HASH INDEX, optional-----------------------+
+//! | 2byte catalog index of the first version   |
+//! | of the keys hashing to each bucket, or     |
+//! | TABLE_HASH_INDEX_EMPTY/COLLISION           |
+//! | ...                                        |
+//! | 4byte bucket count | 4byte bucket crc      |
 //! +-TAIL---------------------------------------+
 //! | 8byte TABLE_MAGIC                          |
 //! +--------------------------------------------+
 //! ```
 
+use crate::statistics::SizeHistogram;
+
 pub const TABLE_HEAD_SIZE: usize = 16;
 pub const TABLE_MIN_SIZE: usize = TABLE_MAGIC_SIZE + TABLE_HEAD_SIZE;
 pub const TABLE_CATALOG_ITEM_SIZE: usize = 24;
 
+pub const TABLE_INDEX_ITEM_HEAD_SIZE: usize = 20;
+pub const TABLE_FOOTER_SIZE: usize = 68 + 2 * SizeHistogram::ENCODED_SIZE + TABLE_MAGIC_SIZE;
+/// Where the global seq is in the footer, rewritten in place by ingestion.
+pub const TABLE_FOOTER_GLOBAL_SEQ_OFF: usize = 56;
+pub const TABLE_DEFAULT_BLOCK_SIZE: usize = 4096;
+pub const TABLE_DEFAULT_FILTER_BITS_PER_KEY: usize = 10;
+
+pub const TABLE_HASH_INDEX_EMPTY: u16 = 0xFFFF;
+pub const TABLE_HASH_INDEX_COLLISION: u16 = 0xFFFE;
+
 pub const TABLE_MAX_SIZE: usize = 0x7FFFFFFF;
 pub const TABLE_DELETION_BITMASK: u32 = 0x80000000;
 